@@ -1,5 +1,3 @@
-use std::sync::Arc;
-
 use natpmp::*;
 use std::time::Duration;
 
@@ -8,7 +6,7 @@ fn main() -> Result<()> {
     use async_std::task;
 
     task::block_on(async {
-        let n = Arc::new(new_async_std_natpmp().await.unwrap());
+        let n = new_async_std_natpmp().await.unwrap();
 
         let n_cloned = n.clone();
         task::spawn(async {
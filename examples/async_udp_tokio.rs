@@ -1,11 +1,9 @@
-use std::sync::Arc;
-
 use natpmp::*;
 use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let n = Arc::new(new_tokio_natpmp().await?);
+    let n = new_tokio_natpmp().await?;
 
     let n_cloned = n.clone();
     tokio::spawn(async {
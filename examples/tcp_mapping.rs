@@ -1,17 +1,15 @@
 extern crate natpmp;
 
 use natpmp::*;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 fn main() -> Result<()> {
-    let mut n = Natpmp::new()?;
+    let n = Natpmp::new()?;
     n.send_port_mapping_request(Protocol::TCP, 4020, 4020, 30)?;
-    // sleep for a while
-    thread::sleep(Duration::from_millis(100));
-    match n.read_response_or_retry() {
+    // wait for the response, retransmitting the request
+    match n.read_response_until(Instant::now() + Duration::from_secs(2)) {
         Err(e) => match e {
-            Error::NATPMP_TRYAGAIN => println!("Try again later"),
+            Error::NATPMP_TRYAGAIN => println!("No response yet"),
             _ => return Err(e),
         },
         Ok(Response::TCP(tr)) => {
@@ -1,24 +1,65 @@
 use std::io;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
 
-use async_trait::async_trait;
 use tokio::net::UdpSocket;
 
-use crate::asynchronous::{new_natpmp_async_with, AsyncUdpSocket, NatpmpAsync};
-use crate::{get_default_gateway, Error, Result, NATPMP_PORT};
+use crate::announcements::{new_announcement_listener_with, AnnouncementListener};
+use crate::asynchronous::{
+    get_public_address_with, map_port_with, new_natpmp_async_with_port, new_pcp_async_with,
+    probe_gateway_with, timeout_with, AsyncTimer, AsyncUdpSocket, NatpmpAsync, PcpAsync,
+};
+use crate::pcp::pcp_server;
+use crate::{
+    get_default_gateway, get_default_gateway_v6, route_source, Error, MappingLifetime,
+    MappingResponse, Protocol, PublicPort, Result, NATPMP_PORT, PCP_ANNOUNCE_PORT,
+};
 
-#[async_trait]
 impl AsyncUdpSocket for UdpSocket {
-    async fn connect(&self, addr: &str) -> io::Result<()> {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
         self.connect(addr).await
     }
 
-    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
-        self.send(buf).await
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.send_to(buf, target).await
     }
 
-    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
-        self.recv(buf).await
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf).await
+    }
+
+    async fn recv_from_timeout(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<(usize, SocketAddr)> {
+        timeout_with(&TokioTimer, timeout, self.recv_from(buf)).await
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        TokioTimer.sleep(duration).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+
+    fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        self.join_multicast_v4(group, interface)
+    }
+
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.try_recv_from(buf)
+    }
+}
+
+/// Timer of the tokio runtime.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TokioTimer;
+
+impl AsyncTimer for TokioTimer {
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
     }
 }
 
@@ -26,7 +67,6 @@ impl AsyncUdpSocket for UdpSocket {
 ///
 /// # Errors
 /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
-/// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
 ///
 /// # Examples
 /// ```
@@ -43,7 +83,6 @@ pub async fn new_tokio_natpmp() -> Result<NatpmpAsync<UdpSocket>> {
 ///
 /// # Errors
 /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
-/// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
 ///
 /// # Examples
 /// ```
@@ -53,13 +92,212 @@ pub async fn new_tokio_natpmp() -> Result<NatpmpAsync<UdpSocket>> {
 /// let n = new_tokio_natpmp_with(gateway).await?;
 /// ```
 pub async fn new_tokio_natpmp_with(gateway: Ipv4Addr) -> Result<NatpmpAsync<UdpSocket>> {
-    let s = UdpSocket::bind("0.0.0.0:0")
+    new_tokio_natpmp_with_port(gateway, NATPMP_PORT).await
+}
+
+/// Create a tokio NAT-PMP object with specified gateway listening on a non-default `port`.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+///
+/// # Examples
+/// ```
+/// use std::net::Ipv4Addr;
+/// use natpmp::*;
+///
+/// let n = new_tokio_natpmp_with_port(Ipv4Addr::LOCALHOST, 15351).await?;
+/// ```
+pub async fn new_tokio_natpmp_with_port(
+    gateway: Ipv4Addr,
+    port: u16,
+) -> Result<NatpmpAsync<UdpSocket>> {
+    let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    new_tokio_natpmp_bound(local, SocketAddrV4::new(gateway, port)).await
+}
+
+/// Create a tokio NAT-PMP object with its socket bound to `local`, talking to the gateway at
+/// `gateway`.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+///
+/// # Examples
+/// ```
+/// use std::net::{Ipv4Addr, SocketAddrV4};
+/// use natpmp::*;
+///
+/// let local = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 0);
+/// let gateway = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), NATPMP_PORT);
+/// let n = new_tokio_natpmp_bound(local, gateway).await?;
+/// ```
+pub async fn new_tokio_natpmp_bound(
+    local: SocketAddrV4,
+    gateway: SocketAddrV4,
+) -> Result<NatpmpAsync<UdpSocket>> {
+    let s = UdpSocket::bind(local)
         .await
         .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
-    let gateway_sockaddr = SocketAddrV4::new(gateway, NATPMP_PORT);
-    if s.connect(gateway_sockaddr).await.is_err() {
-        return Err(Error::NATPMP_ERR_CONNECTERR);
-    }
-    let n = new_natpmp_async_with(s, gateway);
+    let n = new_natpmp_async_with_port(s, *gateway.ip(), gateway.port());
     Ok(n)
 }
+
+/// Get the public address from `gateway`, waiting for the response.
+///
+/// It is a one-shot helper, the request is retransmitted with the default
+/// [`RetryPolicy`](struct.RetryPolicy.html). Use a [`NatpmpAsync`](struct.NatpmpAsync.html)
+/// client to control the retries.
+///
+/// # Errors
+/// * See [`new_tokio_natpmp_with`](fn.new_tokio_natpmp_with.html) and
+///   [`NatpmpAsync::read_response_or_retry`](struct.NatpmpAsync.html#method.read_response_or_retry)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let gateway = get_default_gateway()?;
+/// let public_address = get_public_address_tokio(gateway).await?;
+/// println!("public address: {}", public_address);
+/// ```
+pub async fn get_public_address_tokio(gateway: Ipv4Addr) -> Result<Ipv4Addr> {
+    get_public_address_with(new_tokio_natpmp_with(gateway).await?).await
+}
+
+/// Check whether `gateway` speaks NAT-PMP, with a single public address request waiting
+/// `timeout` for the response.
+///
+/// See [`probe_gateway`](fn.probe_gateway.html), of which it is the async version.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), zero `timeout`
+/// * See [`get_public_address_tokio`](fn.get_public_address_tokio.html)
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// let gateway = get_default_gateway()?;
+/// if !probe_gateway_tokio(gateway, Duration::from_secs(1)).await? {
+///     println!("no NAT-PMP, trying UPnP");
+/// }
+/// ```
+pub async fn probe_gateway_tokio(gateway: Ipv4Addr, timeout: Duration) -> Result<bool> {
+    probe_gateway_with(new_tokio_natpmp_with(gateway).await?, timeout).await
+}
+
+/// Map `private_port` on `gateway`, waiting for the response.
+///
+/// `public_port` and `lifetime` are the same as for
+/// [`NatpmpAsync::send_port_mapping_request`](struct.NatpmpAsync.html#method.send_port_mapping_request).
+/// The mapping expires after its lifetime unless it is requested again.
+///
+/// # Errors
+/// * See [`get_public_address_tokio`](fn.get_public_address_tokio.html)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let gateway = get_default_gateway()?;
+/// let mapping = map_port_tokio(gateway, Protocol::TCP, 4020, 4020, 3600).await?;
+/// println!("mapped to public port {}", mapping.public_port());
+/// ```
+pub async fn map_port_tokio(
+    gateway: Ipv4Addr,
+    protocol: Protocol,
+    private_port: u16,
+    public_port: impl Into<PublicPort>,
+    lifetime: impl Into<MappingLifetime>,
+) -> Result<MappingResponse> {
+    let n = new_tokio_natpmp_with(gateway).await?;
+    map_port_with(
+        n,
+        protocol,
+        private_port,
+        public_port.into(),
+        lifetime.into(),
+    )
+    .await
+}
+
+/// Create a tokio PCP object with default gateway
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+/// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let pcp = new_tokio_pcp().await?;
+/// ```
+pub async fn new_tokio_pcp() -> Result<PcpAsync<UdpSocket>> {
+    let gateway = get_default_gateway()?;
+    new_tokio_pcp_with(gateway).await
+}
+
+/// Create a tokio PCP object with specified IPv4 or IPv6 gateway.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+/// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+pub async fn new_tokio_pcp_with(gateway: impl Into<IpAddr>) -> Result<PcpAsync<UdpSocket>> {
+    new_tokio_pcp_scoped(gateway.into(), 0).await
+}
+
+/// Create a tokio PCP object with the IPv6 default router, see
+/// [`get_default_gateway_v6`](fn.get_default_gateway_v6.html).
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_CANNOTGETGATEWAY`](enum.Error.html#variant.NATPMP_ERR_CANNOTGETGATEWAY)
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+/// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let pcp = new_tokio_pcp_v6().await?;
+/// ```
+pub async fn new_tokio_pcp_v6() -> Result<PcpAsync<UdpSocket>> {
+    let (gateway, scope_id) = get_default_gateway_v6()?;
+    new_tokio_pcp_scoped(gateway.into(), scope_id).await
+}
+
+async fn new_tokio_pcp_scoped(gateway: IpAddr, scope_id: u32) -> Result<PcpAsync<UdpSocket>> {
+    let bind_addr = match gateway {
+        IpAddr::V4(_) => "0.0.0.0:0",
+        IpAddr::V6(_) => "[::]:0",
+    };
+    let s = UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+    let client_address = route_source(pcp_server(gateway, scope_id))?;
+    let mut pcp = new_pcp_async_with(s, gateway, client_address);
+    pcp.set_scope_id(scope_id);
+    Ok(pcp)
+}
+
+/// Create a tokio listener of the public address announcements of `gateway`, see
+/// [`AnnouncementListener`](struct.AnnouncementListener.html).
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let mut listener = new_tokio_announcement_listener(get_default_gateway()?).await?;
+/// let address = listener.next_address().await?;
+/// ```
+pub async fn new_tokio_announcement_listener(
+    gateway: Ipv4Addr,
+) -> Result<AnnouncementListener<UdpSocket>> {
+    let s = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PCP_ANNOUNCE_PORT))
+        .await
+        .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+    new_announcement_listener_with(s, gateway)
+}
@@ -0,0 +1,203 @@
+//! Listener of the multicast public address announcements of a NAT-PMP gateway.
+
+use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(all(feature = "tokio", feature = "stream"))]
+use std::pin::Pin;
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::protocol::NATPMP_MAX_RESPONSE_LEN;
+use crate::{AnnouncementFilter, AsyncUdpSocket, Error, Response, Result, PCP_ANNOUNCE_GROUP_V4};
+
+/// Listener of the public address changes a NAT-PMP gateway multicasts to 224.0.0.1 port 5350
+/// (rfc6886 section 3.2.1), on an async socket bound to port 5350.
+///
+/// The retransmissions of an announcement are filtered with an
+/// [`AnnouncementFilter`](struct.AnnouncementFilter.html), so each change is reported once.
+/// With the `tokio` and `stream` features the tokio listener is also a `futures_core::Stream`
+/// of the new addresses, ending on a socket error.
+///
+/// See [`new_tokio_announcement_listener`](fn.new_tokio_announcement_listener.html) and
+/// [`new_async_std_announcement_listener`](fn.new_async_std_announcement_listener.html), or
+/// [`new_announcement_listener_with`](fn.new_announcement_listener_with.html) for other
+/// runtimes.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let mut listener = new_tokio_announcement_listener(get_default_gateway()?).await?;
+/// loop {
+///     let address = listener.next_address().await?;
+///     println!("public address changed to {}", address);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct AnnouncementListener<S>
+where
+    S: AsyncUdpSocket,
+{
+    s: S,
+    gateway: Ipv4Addr,
+    filter: AnnouncementFilter,
+}
+
+/// Create a listener of the announcements of `gateway` with an async udpsocket bound to port
+/// [`PCP_ANNOUNCE_PORT`](constant.PCP_ANNOUNCE_PORT.html), NAT-PMP and PCP share the port.
+///
+/// The socket joins the multicast group on the default interface.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR), also
+///   when the socket does not implement [`AsyncUdpSocket::join_multicast_v4`](trait.AsyncUdpSocket.html#method.join_multicast_v4)
+pub fn new_announcement_listener_with<S>(s: S, gateway: Ipv4Addr) -> Result<AnnouncementListener<S>>
+where
+    S: AsyncUdpSocket,
+{
+    s.join_multicast_v4(PCP_ANNOUNCE_GROUP_V4, Ipv4Addr::UNSPECIFIED)
+        .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+    Ok(AnnouncementListener {
+        s,
+        gateway,
+        filter: AnnouncementFilter::new(),
+    })
+}
+
+impl<S> AnnouncementListener<S>
+where
+    S: AsyncUdpSocket,
+{
+    /// NAT-PMP server address.
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    /// Wait for the next public address change.
+    ///
+    /// Datagrams from other sources, invalid datagrams and repeated announcements are discarded.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_RECV`](enum.Error.html#variant.NATPMP_ERR_RECV)
+    pub async fn next_address(&mut self) -> Result<Ipv4Addr> {
+        let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+        loop {
+            let (n, source) = self
+                .s
+                .recv_from(&mut buf)
+                .await
+                .map_err(|e| Error::NATPMP_ERR_RECV(e.kind()))?;
+            if let Some(address) = self.handle_datagram(&buf[..n], source, Instant::now()) {
+                return Ok(address);
+            }
+        }
+    }
+
+    fn handle_datagram(
+        &mut self,
+        buf: &[u8],
+        source: SocketAddr,
+        now: Instant,
+    ) -> Option<Ipv4Addr> {
+        if source.ip() != self.gateway {
+            return None;
+        }
+        match Response::parse(buf) {
+            Ok(Response::Gateway(gr)) if self.filter.is_new(&gr, now) => Some(*gr.public_address()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AnnouncementListener<tokio::net::UdpSocket> {
+    /// Poll for the next public address change, see [`next_address`](#method.next_address).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_RECV`](enum.Error.html#variant.NATPMP_ERR_RECV)
+    pub fn poll_next_address(&mut self, cx: &mut Context<'_>) -> Poll<Result<Ipv4Addr>> {
+        let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+        loop {
+            let mut read = tokio::io::ReadBuf::new(&mut buf);
+            let source = match self.s.poll_recv_from(cx, &mut read) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(source)) => source,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(Error::NATPMP_ERR_RECV(e.kind()))),
+            };
+            if let Some(address) = self.handle_datagram(read.filled(), source, Instant::now()) {
+                return Poll::Ready(Ok(address));
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "tokio", feature = "stream"))]
+impl futures_core::Stream for AnnouncementListener<tokio::net::UdpSocket> {
+    type Item = Ipv4Addr;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Ipv4Addr>> {
+        self.poll_next_address(cx).map(Result::ok)
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handle_datagram() {
+        let now = Instant::now();
+        let gateway = SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 5351));
+        let mut listener = AnnouncementListener {
+            s: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            gateway: Ipv4Addr::new(192, 168, 0, 1),
+            filter: AnnouncementFilter::new(),
+        };
+        let first = [0, 128, 0, 0, 0, 0, 0x03, 0xe8, 10, 0, 0, 1];
+        assert_eq!(
+            listener.handle_datagram(&first, gateway, now),
+            Some(Ipv4Addr::new(10, 0, 0, 1))
+        );
+
+        // retransmission
+        let later = now + Duration::from_secs(1);
+        let repeat = [0, 128, 0, 0, 0, 0, 0x03, 0xe9, 10, 0, 0, 1];
+        assert_eq!(listener.handle_datagram(&repeat, gateway, later), None);
+
+        // other source
+        let change = [0, 128, 0, 0, 0, 0, 0x03, 0xea, 10, 0, 0, 2];
+        let other = SocketAddr::from((Ipv4Addr::new(192, 168, 0, 9), 5351));
+        assert_eq!(listener.handle_datagram(&change, other, later), None);
+
+        assert_eq!(
+            listener.handle_datagram(&change, gateway, later),
+            Some(Ipv4Addr::new(10, 0, 0, 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_next_address() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = s.local_addr().unwrap();
+        let mut listener = new_announcement_listener_with(s, Ipv4Addr::LOCALHOST).unwrap();
+        let first = [0, 128, 0, 0, 0, 0, 0x03, 0xe8, 10, 0, 0, 1];
+        let repeat = [0, 128, 0, 0, 0, 0, 0x03, 0xe9, 10, 0, 0, 1];
+        let change = [0, 128, 0, 0, 0, 0, 0x03, 0xea, 10, 0, 0, 2];
+        for announcement in [&first, &repeat, &change] {
+            gateway.send_to(announcement, client).await.unwrap();
+        }
+        assert_eq!(
+            listener.next_address().await,
+            Ok(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(
+            listener.next_address().await,
+            Ok(Ipv4Addr::new(10, 0, 0, 2))
+        );
+    }
+}
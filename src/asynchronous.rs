@@ -1,30 +1,333 @@
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
 use std::io;
-use std::net::Ipv4Addr;
-use std::time::Duration;
-
-use async_trait::async_trait;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
+use crate::pcp::{pcp_server, unspecified, Nonces, PCP_MAX_PACKET_LEN};
+use crate::protocol::{NATPMP_MAX_REQUEST_LEN, NATPMP_MAX_RESPONSE_LEN};
 use crate::{
-    Error, GatewayResponse, MappingResponse, Protocol, Response, Result, NATPMP_MAX_ATTEMPS,
+    probe_result, send_error, AnnouncementFilter, Error, GatewayResponse, MappingLifetime,
+    MappingResponse, NatpmpBuilder, NatpmpProto, PcpAnnounceResponse, PcpAuthenticator,
+    PcpMapRequest, PcpOption, PcpPeerRequest, PcpProto, PcpRequest, PcpResponse, Protocol,
+    PublicPort, Request, RequestToken, Response, Result, RetryPolicy, NATPMP_PORT, PCP_PORT,
 };
 
 /// A wrapper trait for async udpsocket.
-#[async_trait]
+///
+/// The methods return `Send` futures, implement them with `async fn`. The clients send to and
+/// receive from the gateway address, so that the source of the responses is always checked.
+/// Leave their sockets unconnected: macOS and the BSDs reject a send with a destination on a
+/// connected socket.
+///
+/// # Migration
+///
+/// `connect(&str)`, `send` and `recv` are replaced by [`connect`](#tymethod.connect) with a
+/// `SocketAddr`, [`send_to`](#tymethod.send_to) and [`recv_from`](#tymethod.recv_from), which
+/// forward to the methods of the same name of the usual UDP sockets.
 pub trait AsyncUdpSocket {
-    async fn connect(&self, addr: &str) -> io::Result<()>;
+    /// Set the default destination of the socket and filter the received datagrams.
+    fn connect(&self, addr: SocketAddr) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Send a datagram to `target`.
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Receive a datagram with its source address.
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    /// Receive a datagram with its source address, fail with `io::ErrorKind::TimedOut` after
+    /// `timeout`.
+    ///
+    /// The requests are retransmitted on these timeouts, implement it with the timer of the
+    /// runtime, e.g. with [`timeout_with`](fn.timeout_with.html).
+    fn recv_from_timeout(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    /// Wait for `duration` with the timer of the runtime, e.g. before receiving again after a
+    /// failure.
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+
+    /// Local address the socket is bound to.
+    ///
+    /// The default implementation fails with `io::ErrorKind::Unsupported`.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Join the IPv4 multicast group `group` on `interface`, e.g. to receive the public
+    /// address announcements, see [`AnnouncementListener`](struct.AnnouncementListener.html).
+    ///
+    /// The default implementation fails with `io::ErrorKind::Unsupported`.
+    fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        let _ = (group, interface);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Receive a datagram if one is already queued, fail with `io::ErrorKind::WouldBlock`
+    /// otherwise.
+    ///
+    /// The default implementation polls [`recv_from`](#tymethod.recv_from) once.
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut recv = pin!(self.recv_from(buf));
+        match recv.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// A wrapper trait for the timer of an async runtime.
+///
+/// The retransmissions of the requests are timed with it, so that custom runtimes supply
+/// their own sleep, see [`timeout_with`](fn.timeout_with.html) and
+/// [`TimedUdpSocket`](struct.TimedUdpSocket.html).
+pub trait AsyncTimer {
+    /// Complete after `duration`.
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+}
+
+/// Async udpsocket receiving with the timeouts of an [`AsyncTimer`](trait.AsyncTimer.html)
+/// instead of the ones of the inner socket, e.g. to time a socket of one runtime with the timer
+/// of another.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// struct SmolTimer;
+///
+/// impl AsyncTimer for SmolTimer {
+///     async fn sleep(&self, duration: Duration) {
+///         smol::Timer::after(duration).await;
+///     }
+/// }
+///
+/// let s = TimedUdpSocket::new(tokio::net::UdpSocket::bind("0.0.0.0:0").await?, SmolTimer);
+/// let n = new_natpmp_async_with(s, get_default_gateway()?);
+/// ```
+#[derive(Debug)]
+pub struct TimedUdpSocket<S, T> {
+    s: S,
+    timer: T,
+}
+
+impl<S, T> TimedUdpSocket<S, T>
+where
+    S: AsyncUdpSocket + Sync,
+    T: AsyncTimer + Sync,
+{
+    /// Time out the receptions of `s` with `timer`.
+    pub fn new(s: S, timer: T) -> TimedUdpSocket<S, T> {
+        TimedUdpSocket { s, timer }
+    }
+
+    /// Inner socket.
+    pub fn get_ref(&self) -> &S {
+        &self.s
+    }
+
+    /// Unwrap the inner socket and the timer.
+    pub fn into_inner(self) -> (S, T) {
+        (self.s, self.timer)
+    }
+}
+
+impl<S, T> AsyncUdpSocket for TimedUdpSocket<S, T>
+where
+    S: AsyncUdpSocket + Sync,
+    T: AsyncTimer + Sync,
+{
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.s.connect(addr).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.s.send_to(buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.s.recv_from(buf).await
+    }
+
+    async fn recv_from_timeout(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<(usize, SocketAddr)> {
+        timeout_with(&self.timer, timeout, self.s.recv_from(buf)).await
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.timer.sleep(duration).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.s.local_addr()
+    }
+
+    fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        self.s.join_multicast_v4(group, interface)
+    }
 
-    async fn send(&self, buf: &[u8]) -> io::Result<usize>;
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.s.try_recv_from(buf)
+    }
+}
 
-    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+/// Run `io` for at most `timeout` timed by `timer`, fail with `io::ErrorKind::TimedOut` after.
+///
+/// # Examples
+/// ```
+/// use std::io;
+/// use std::net::SocketAddr;
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// struct SmolTimer;
+///
+/// impl AsyncTimer for SmolTimer {
+///     async fn sleep(&self, duration: Duration) {
+///         smol::Timer::after(duration).await;
+///     }
+/// }
+///
+/// struct SmolUdpSocket(smol::net::UdpSocket);
+///
+/// impl AsyncUdpSocket for SmolUdpSocket {
+///     // connect, send_to and recv_from forward to the socket
+///
+///     async fn recv_from_timeout(
+///         &self,
+///         buf: &mut [u8],
+///         timeout: Duration,
+///     ) -> io::Result<(usize, SocketAddr)> {
+///         timeout_with(&SmolTimer, timeout, self.0.recv_from(buf)).await
+///     }
+///
+///     async fn sleep(&self, duration: Duration) {
+///         SmolTimer.sleep(duration).await
+///     }
+/// }
+/// ```
+pub async fn timeout_with<T, R>(
+    timer: &T,
+    timeout: Duration,
+    io: impl Future<Output = io::Result<R>>,
+) -> io::Result<R>
+where
+    T: AsyncTimer,
+{
+    let mut io = pin!(io);
+    let mut sleep = pin!(timer.sleep(timeout));
+    poll_fn(|cx| {
+        if let Poll::Ready(result) = io.as_mut().poll(cx) {
+            return Poll::Ready(result);
+        }
+        sleep
+            .as_mut()
+            .poll(cx)
+            .map(|()| Err(io::ErrorKind::TimedOut.into()))
+    })
+    .await
 }
 
 /// NAT-PMP async client
+///
+/// Clones share the socket and the pending requests, so that a task can send the requests
+/// while another one reads the responses. Tasks waiting concurrently for their own requests
+/// use [`send_tracked_request`](#method.send_tracked_request) and
+/// [`read_response_for`](#method.read_response_for).
 pub struct NatpmpAsync<S>
 where
     S: AsyncUdpSocket,
 {
-    s: S,
+    s: Arc<S>,
     gateway: Ipv4Addr,
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+impl<S> Clone for NatpmpAsync<S>
+where
+    S: AsyncUdpSocket,
+{
+    fn clone(&self) -> Self {
+        NatpmpAsync {
+            s: self.s.clone(),
+            gateway: self.gateway,
+            port: self.port,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    proto: NatpmpProto,
+    announcements: AnnouncementFilter,
+    announcement_queue: VecDeque<GatewayResponse>,
+    responses: VecDeque<(RequestToken, Result<Response>)>,
+    waiters: Vec<Waker>,
+}
+
+impl State {
+    /// Keep the response of the request of `token` and wake the tasks waiting for theirs.
+    fn push_response(&mut self, token: RequestToken, result: Result<Response>) {
+        self.responses.push_back((token, result));
+        for waiter in self.waiters.drain(..) {
+            waiter.wake();
+        }
+    }
+
+    fn take_response(&mut self, token: RequestToken) -> Option<Result<Response>> {
+        let i = self.responses.iter().position(|(t, _)| *t == token)?;
+        self.responses.remove(i).map(|(_, result)| result)
+    }
+
+    fn queue_announcement(&mut self, buf: &[u8], now: Instant) {
+        if self.proto.is_late_response(buf) {
+            return;
+        }
+        if let Ok(Response::Gateway(gr)) = Response::parse(buf) {
+            if self.announcements.is_new(&gr, now) {
+                self.announcement_queue.push_back(gr);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl NatpmpAsync<tokio::net::UdpSocket> {
+    /// Create a builder to configure the gateway, the socket and the retry schedule of a tokio
+    /// client, see [`NatpmpBuilder::build_tokio`](struct.NatpmpBuilder.html#method.build_tokio).
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// let n = NatpmpAsync::builder()
+    ///     .gateway(Ipv4Addr::new(192, 168, 0, 1))
+    ///     .retry_policy(RetryPolicy::default())
+    ///     .build_tokio()?;
+    /// ```
+    pub fn builder() -> NatpmpBuilder {
+        NatpmpBuilder::new()
+    }
 }
 
 /// Create a NAT-PMP object with async udpsocket and gateway
@@ -32,7 +335,21 @@ pub fn new_natpmp_async_with<S>(s: S, gateway: Ipv4Addr) -> NatpmpAsync<S>
 where
     S: AsyncUdpSocket,
 {
-    NatpmpAsync { s, gateway }
+    new_natpmp_async_with_port(s, gateway, NATPMP_PORT)
+}
+
+/// Create a NAT-PMP object with async udpsocket and gateway listening on a non-default `port`,
+/// the responses must come from that port.
+pub fn new_natpmp_async_with_port<S>(s: S, gateway: Ipv4Addr, port: u16) -> NatpmpAsync<S>
+where
+    S: AsyncUdpSocket,
+{
+    NatpmpAsync {
+        s: Arc::new(s),
+        gateway,
+        port,
+        state: Arc::new(Mutex::new(State::default())),
+    }
 }
 
 impl<S> NatpmpAsync<S>
@@ -44,33 +361,349 @@ where
         &self.gateway
     }
 
-    /// Send public address request.
+    /// Local address of the socket, e.g. to log the source port of the requests.
     ///
     /// # Errors
-    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR), also
+    ///   when the socket does not implement [`AsyncUdpSocket::local_addr`](trait.AsyncUdpSocket.html#method.local_addr)
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.s
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)
+    }
+
+    /// Set the retry policy of the following requests.
+    ///
+    /// The requests are retransmitted by [`read_response_or_retry`](#method.read_response_or_retry)
+    /// if the socket implements [`AsyncUdpSocket::recv_from_timeout`](trait.AsyncUdpSocket.html#method.recv_from_timeout).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no delay
+    pub fn set_retry_policy(&self, policy: RetryPolicy) -> Result<()> {
+        if !policy.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.state().proto.set_retry_policy(policy);
+        Ok(())
+    }
+
+    /// Retry policy of the following requests.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        *self.state().proto.retry_policy()
+    }
+
+    /// Whether a request is waiting for its response.
+    pub fn has_pending_request(&self) -> bool {
+        self.state().proto.has_pending_request()
+    }
+
+    /// Abandon the pending requests, e.g. to give up early.
+    pub fn cancel_pending_request(&self) {
+        let mut state = self.state();
+        state.proto.cancel_pending_request();
+        state.responses.clear();
+    }
+
+    /// Abandon the request of `token`, returns whether it was waiting for its response.
+    pub fn cancel_request(&self, token: RequestToken) -> bool {
+        let mut state = self.state();
+        state.responses.retain(|(t, _)| *t != token);
+        state.proto.cancel_request(token)
+    }
+
+    /// Most recent request waiting for its response, if any.
+    pub fn pending_request_type(&self) -> Option<Request> {
+        self.state().proto.pending_request_type()
+    }
+
+    /// Number of times the most recent request waiting for its response was sent so far,
+    /// counting the first transmission.
+    pub fn attempts_made(&self) -> Option<u32> {
+        self.state().proto.attempts_made()
+    }
+
+    /// Time of the next retransmission of the pending requests, or of giving up on them once
+    /// their attempts are exhausted.
+    pub fn next_retry_at(&self) -> Option<Instant> {
+        self.state().proto.next_retry_at()
+    }
+
+    /// Get timeout duration of the currently pending NAT-PMP request.
+    ///
+    /// It lets an application wait on other events meanwhile, then call
+    /// [`read_response_or_retry_timeout`](#method.read_response_or_retry_timeout) with it.
+    ///
+    /// # Errors:
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
     ///
     /// # Examples
     /// ```
     /// use natpmp::*;
     ///
-    /// let mut n = new_tokio_natpmp().await?;
+    /// let n = new_tokio_natpmp().await?;
     /// n.send_public_address_request().await?;
+    /// tokio::select! {
+    ///     _ = tokio::time::sleep(n.get_natpmp_request_timeout()?) => {
+    ///         let response = n.read_response_or_retry().await?;
+    ///     }
+    ///     _ = tokio::signal::ctrl_c() => n.cancel_pending_request(),
+    /// }
     /// ```
-    pub async fn send_public_address_request(&mut self) -> Result<()> {
-        let request = [0_u8; 2];
+    pub fn get_natpmp_request_timeout(&self) -> Result<Duration> {
+        self.state().proto.timeout(Instant::now())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn send_request(&self, request: Request) -> Result<()> {
+        let mut buf = [0_u8; NATPMP_MAX_REQUEST_LEN];
+        let len = {
+            let mut state = self.state();
+            let request = state.proto.send_request(request, Instant::now());
+            buf[..request.len()].copy_from_slice(request);
+            request.len()
+        };
+        self.send_bytes(&buf[..len]).await
+    }
+
+    async fn send_bytes(&self, request: &[u8]) -> Result<()> {
         let n = self
             .s
-            .send(&request[..])
+            .send_to(request, self.server())
             .await
-            .map_err(|_| Error::NATPMP_ERR_SENDERR)?;
+            .map_err(|e| send_error(&e))?;
         if n != request.len() {
             return Err(Error::NATPMP_ERR_SENDERR);
         }
         Ok(())
     }
 
+    /// Address of the NAT-PMP server of the gateway.
+    fn server(&self) -> SocketAddr {
+        SocketAddr::from((self.gateway, self.port))
+    }
+
+    /// Check that a datagram comes from the NAT-PMP server of the gateway.
+    fn check_source(&self, source: SocketAddr) -> Result<()> {
+        if source != self.server() {
+            return Err(Error::NATPMP_ERR_WRONGPACKETSOURCE);
+        }
+        Ok(())
+    }
+
+    /// Advance the retry schedules, returns the requests to retransmit and the duration until
+    /// the next retransmission.
+    #[allow(clippy::type_complexity)]
+    fn handle_timeout(&self) -> Result<(Vec<([u8; NATPMP_MAX_REQUEST_LEN], usize)>, Duration)> {
+        let now = Instant::now();
+        let mut state = self.state();
+        let mut requests = Vec::new();
+        while let Some(request) = state.proto.handle_timeout(now).map_err(timeout_error)? {
+            let mut buf = [0_u8; NATPMP_MAX_REQUEST_LEN];
+            buf[..request.len()].copy_from_slice(request);
+            requests.push((buf, request.len()));
+        }
+        Ok((requests, state.proto.timeout(now)?))
+    }
+
+    /// Send a request and track it, returns the token to read its response with
+    /// [`read_response_for`](#method.read_response_for).
+    ///
+    /// A request is identified by its opcode and, for mappings, its protocol and private port:
+    /// a request with the same opcode and port replaces the pending one.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// let tcp = n.send_tracked_request(Request::Mapping {
+    ///     protocol: Protocol::TCP,
+    ///     private_port: 4020,
+    ///     public_port: 4020,
+    ///     lifetime: 30,
+    /// });
+    /// let udp = n.send_tracked_request(Request::Mapping {
+    ///     protocol: Protocol::UDP,
+    ///     private_port: 4020,
+    ///     public_port: 4020,
+    ///     lifetime: 30,
+    /// });
+    /// let (tcp, udp) = tokio::try_join!(tcp, udp)?;
+    /// // each task gets its own response
+    /// let (tcp_response, udp_response) =
+    ///     tokio::join!(n.read_response_for(tcp), n.read_response_for(udp));
+    /// ```
+    pub async fn send_tracked_request(&self, request: Request) -> Result<RequestToken> {
+        let policy = self.retry_policy();
+        self.send_tracked_request_with_policy(request, policy).await
+    }
+
+    /// Send a request and track it like [`send_tracked_request`](#method.send_tracked_request),
+    /// retransmitting it following `policy` instead of the retry policy of the client.
+    ///
+    /// It lets an operation fail fast while the others keep the full schedule of rfc6886.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no delay
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// // probe the gateway with a single attempt
+    /// let probe = RetryPolicy {
+    ///     attempts: 1,
+    ///     initial_delay: Duration::from_millis(500),
+    ///     ..RetryPolicy::default()
+    /// };
+    /// let token = n
+    ///     .send_tracked_request_with_policy(Request::PublicAddress, probe)
+    ///     .await?;
+    /// if n.read_response_for(token).await.is_ok() {
+    ///     let token = n
+    ///         .send_tracked_request(Request::Mapping {
+    ///             protocol: Protocol::TCP,
+    ///             private_port: 8080,
+    ///             public_port: 8080,
+    ///             lifetime: 3600,
+    ///         })
+    ///         .await?;
+    ///     let response = n.read_response_for(token).await?;
+    /// }
+    /// ```
+    pub async fn send_tracked_request_with_policy(
+        &self,
+        request: Request,
+        policy: RetryPolicy,
+    ) -> Result<RequestToken> {
+        if !policy.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        let mut buf = [0_u8; NATPMP_MAX_REQUEST_LEN];
+        let (token, len) = {
+            let mut state = self.state();
+            let (token, request) =
+                state
+                    .proto
+                    .send_tracked_request_with_policy(request, policy, Instant::now());
+            buf[..request.len()].copy_from_slice(request);
+            (token, request.len())
+        };
+        self.send_bytes(&buf[..len]).await?;
+        Ok(token)
+    }
+
+    /// Read the response of the request of `token`, retransmitting the pending requests when it
+    /// is time to.
+    ///
+    /// Several tasks may wait at once on clones of the client: a response is routed to the
+    /// task waiting for it, whichever task received it. Unsolicited public address
+    /// announcements received meanwhile are queued, see
+    /// [`pop_announcement`](#method.pop_announcement).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ), the
+    ///   response was already read or the request was replaced or cancelled
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry)
+    pub async fn read_response_for(&self, token: RequestToken) -> Result<Response> {
+        let mut buf = [0_u8; NATPMP_MAX_RESPONSE_LEN];
+        loop {
+            let (requests, timeout) = {
+                let now = Instant::now();
+                let mut state = self.state();
+                let state = &mut *state;
+                let mut requests = Vec::new();
+                while let Some((t, result)) = state.proto.handle_request_timeout(now) {
+                    match result {
+                        Ok(request) => {
+                            let mut buf = [0_u8; NATPMP_MAX_REQUEST_LEN];
+                            buf[..request.len()].copy_from_slice(request);
+                            requests.push((buf, request.len()));
+                        }
+                        Err(e) => state.push_response(t, Err(timeout_error(e))),
+                    }
+                }
+                if let Some(result) = state.take_response(token) {
+                    return result;
+                }
+                if !state.proto.is_pending(token) {
+                    return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+                }
+                (requests, state.proto.timeout(now)?)
+            };
+            for (request, len) in &requests {
+                self.send_bytes(&request[..*len]).await?;
+            }
+            // receive a datagram, unless another task receives the response first
+            let received = {
+                let mut recv = pin!(self.s.recv_from_timeout(&mut buf, timeout));
+                poll_fn(|cx| {
+                    if let Poll::Ready(result) = recv.as_mut().poll(cx) {
+                        return Poll::Ready(Some(result));
+                    }
+                    let mut state = self.state();
+                    if state.responses.iter().any(|(t, _)| *t == token) {
+                        return Poll::Ready(None);
+                    }
+                    if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                        state.waiters.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                })
+                .await
+            };
+            let n = match received {
+                None => continue,
+                Some(Ok((n, source))) => {
+                    self.check_source(source)?;
+                    n
+                }
+                Some(Err(e)) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Some(Err(e)) if is_refused(&e) => return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT),
+                Some(Err(e)) => return Err(Error::NATPMP_ERR_RECV(e.kind())),
+            };
+            let now = Instant::now();
+            let mut state = self.state();
+            match state.proto.demux_datagram(&buf[..n], now) {
+                Some((t, result)) => state.push_response(t, result),
+                None => state.queue_announcement(&buf[..n], now),
+            }
+        }
+    }
+
+    /// Send public address request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// n.send_public_address_request().await?;
+    /// ```
+    pub async fn send_public_address_request(&self) -> Result<()> {
+        self.send_request(Request::PublicAddress).await
+    }
+
     /// Send port mapping request.
     ///
+    /// `public_port` is a suggestion, pass [`PublicPort::Any`](enum.PublicPort.html#variant.Any) to let
+    /// the gateway choose it. `lifetime` is either seconds or a `Duration`, see
+    /// [`MappingLifetime`](struct.MappingLifetime.html).
+    ///
     /// # Errors
     /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
     ///
@@ -78,54 +711,112 @@ where
     /// ```
     /// use natpmp::*;
     ///
-    /// let mut n = new_tokio_natpmp().await?;
+    /// let n = new_tokio_natpmp().await?;
     /// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30).await?;
     /// ```
     pub async fn send_port_mapping_request(
         &self,
         protocol: Protocol,
         private_port: u16,
-        public_port: u16,
-        lifetime: u32,
+        public_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
     ) -> Result<()> {
-        let mut request = [0_u8; 12];
-        request[1] = match protocol {
-            Protocol::UDP => 1,
-            _ => 2,
+        let request = Request::Mapping {
+            protocol,
+            private_port,
+            public_port: public_port.into().into(),
+            lifetime: lifetime.into().as_secs(),
         };
-        request[2] = 0; // reserved
-        request[3] = 0;
-        // private port
-        request[4] = (private_port >> 8 & 0xff) as u8;
-        request[5] = (private_port & 0xff) as u8;
-        // public port
-        request[6] = (public_port >> 8 & 0xff) as u8;
-        request[7] = (public_port & 0xff) as u8;
-        // lifetime
-        request[8] = ((lifetime >> 24) & 0xff) as u8;
-        request[9] = ((lifetime >> 16) & 0xff) as u8;
-        request[10] = ((lifetime >> 8) & 0xff) as u8;
-        request[11] = (lifetime & 0xff) as u8;
+        self.send_request(request).await
+    }
 
-        let n = self
-            .s
-            .send(&request[..])
+    /// Send port mapping deletion request.
+    ///
+    /// The response is a mapping response with [`is_deleted`](struct.MappingResponse.html#method.is_deleted).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// n.send_port_mapping_delete(Protocol::UDP, 4020).await?;
+    /// ```
+    pub async fn send_port_mapping_delete(
+        &self,
+        protocol: Protocol,
+        private_port: u16,
+    ) -> Result<()> {
+        self.send_request(Request::delete_mapping(protocol, private_port))
             .await
-            .map_err(|_| Error::NATPMP_ERR_SENDERR)?;
-        if n != request.len() {
-            return Err(Error::NATPMP_ERR_SENDERR);
-        }
-        Ok(())
     }
 
-    /// Read NAT-PMP response if possible
+    /// Send request to delete all mappings of the client for `protocol`.
+    ///
+    /// The response is a mapping response with [`is_all_deleted`](struct.MappingResponse.html#method.is_all_deleted).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// n.send_delete_all_mappings(Protocol::TCP).await?;
+    /// ```
+    pub async fn send_delete_all_mappings(&self, protocol: Protocol) -> Result<()> {
+        self.send_request(Request::delete_all_mappings(protocol))
+            .await
+    }
+
+    /// Take the oldest unsolicited public address announcement received while reading a
+    /// response.
+    ///
+    /// [`read_response_or_retry`](#method.read_response_or_retry) queues the announcements
+    /// arriving before the reply to a port mapping request and keeps waiting for the reply.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30).await?;
+    /// let response = n.read_response_or_retry().await;
+    /// while let Some(announcement) = n.pop_announcement() {
+    ///     println!("public address changed: {}", announcement);
+    /// }
+    /// ```
+    pub fn pop_announcement(&self) -> Option<GatewayResponse> {
+        self.state().announcement_queue.pop_front()
+    }
+
+    /// Whether the epoch of `response` indicates that the gateway rebooted and lost its
+    /// mappings, see [`EpochTracker::indicates_reboot`](struct.EpochTracker.html#method.indicates_reboot).
+    pub fn epoch_indicates_reboot(&self, response: &Response) -> bool {
+        self.state().proto.epoch_indicates_reboot(response)
+    }
+
+    /// Read the response of a pending request, retransmitting the requests following the retry
+    /// policy (rfc6886 section 3.1).
+    ///
+    /// It returns the response of any pending request, tasks waiting concurrently for their
+    /// own requests use [`read_response_for`](#method.read_response_for) instead.
+    ///
+    /// Unsolicited public address announcements received meanwhile are queued, see
+    /// [`pop_announcement`](#method.pop_announcement).
     ///
     /// # Errors
-    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
     /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
     /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
-    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    /// * [`Error::NATPMP_ERR_TIMEOUT`](enum.Error.html#variant.NATPMP_ERR_TIMEOUT), no response
+    ///   to any attempt
+    /// * [`Error::NATPMP_ERR_RECV`](enum.Error.html#variant.NATPMP_ERR_RECV)
     /// * [`Error::NATPMP_ERR_WRONGPACKETSOURCE`](enum.Error.html#variant.NATPMP_ERR_WRONGPACKETSOURCE)
+    /// * [`Error::NATPMP_ERR_TRUNCATEDPACKET`](enum.Error.html#variant.NATPMP_ERR_TRUNCATEDPACKET)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDOPCODE)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
@@ -133,77 +824,1009 @@ where
     /// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
     /// * [`Error::NATPMP_ERR_OUTOFRESOURCES`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
-    /// * [`Error::NATPMP_ERR_UNDEFINEDERROR`](enum.Error.html#variant.NATPMP_ERR_UNDEFINEDERROR)
+    /// * [`Error::NATPMP_ERR_UNKNOWNRESULTCODE`](enum.Error.html#variant.NATPMP_ERR_UNKNOWNRESULTCODE)
     ///
     /// # Examples
     /// ```
     /// use natpmp::*;
     ///
-    /// let mut n = new_tokio_natpmp().await?;
+    /// let n = new_tokio_natpmp().await?;
     /// n.send_public_address_request().await?;
     /// let response = n.read_response_or_retry().await?;
     ///
     /// ```
     pub async fn read_response_or_retry(&self) -> Result<Response> {
-        let mut buf = [0_u8; 16];
-        let mut retries = 0;
-        while retries < NATPMP_MAX_ATTEMPS {
-            match self.s.recv(&mut buf).await {
-                Err(_) => retries += 1,
-                Ok(_) => {
-                    // version
-                    if buf[0] != 0 {
-                        return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
-                    }
-                    // opcode
-                    if buf[1] < 128 || buf[1] > 130 {
-                        return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE);
-                    }
-                    // result code
-                    let resultcode = u16::from_be_bytes([buf[2], buf[3]]);
-                    // result
-                    if resultcode != 0 {
-                        return Err(match resultcode {
-                            1 => Error::NATPMP_ERR_UNSUPPORTEDVERSION,
-                            2 => Error::NATPMP_ERR_NOTAUTHORIZED,
-                            3 => Error::NATPMP_ERR_NETWORKFAILURE,
-                            4 => Error::NATPMP_ERR_OUTOFRESOURCES,
-                            5 => Error::NATPMP_ERR_UNSUPPORTEDOPCODE,
-                            _ => Error::NATPMP_ERR_UNDEFINEDERROR,
-                        });
-                    }
-                    // epoch
-                    let epoch = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
-                    let rsp_type = buf[1] & 0x7f;
-                    return Ok(match rsp_type {
-                        0 => Response::Gateway(GatewayResponse {
-                            epoch,
-                            public_address: Ipv4Addr::from(u32::from_be_bytes([
-                                buf[8], buf[9], buf[10], buf[11],
-                            ])),
-                        }),
-                        _ => {
-                            let private_port = u16::from_be_bytes([buf[8], buf[9]]);
-                            let public_port = u16::from_be_bytes([buf[10], buf[11]]);
-                            let lifetime = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
-                            let lifetime = Duration::from_secs(lifetime.into());
-                            let m = MappingResponse {
-                                epoch,
-                                private_port,
-                                public_port,
-                                lifetime,
-                            };
-                            if rsp_type == 1 {
-                                Response::UDP(m)
-                            } else {
-                                Response::TCP(m)
-                            }
-                        }
-                    });
-                }
-            }
-        }
+        self.read_until(None).await
+    }
 
-        Err(Error::NATPMP_ERR_RECVFROM)
+    /// Read the response of a pending request until `deadline`, retransmitting the requests
+    /// when it is time to.
+    ///
+    /// The requests stay pending at the deadline, the response can be read later.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN), no response
+    ///   before `deadline`
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// n.send_public_address_request().await?;
+    /// let response = n.read_response_until(Instant::now() + Duration::from_secs(2)).await?;
+    /// ```
+    pub async fn read_response_until(&self, deadline: Instant) -> Result<Response> {
+        self.read_until(Some(deadline)).await
+    }
+
+    /// Read the response of a pending request for at most `timeout`, see
+    /// [`read_response_until`](#method.read_response_until).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN), no response
+    ///   within `timeout`
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// n.send_public_address_request().await?;
+    /// match n.read_response_or_retry_timeout(Duration::from_secs(2)).await {
+    ///     Ok(response) => println!("{}", response),
+    ///     Err(Error::NATPMP_TRYAGAIN) => println!("no response yet"),
+    ///     Err(e) => return Err(e),
+    /// }
+    /// ```
+    pub async fn read_response_or_retry_timeout(&self, timeout: Duration) -> Result<Response> {
+        self.read_until(Some(Instant::now() + timeout)).await
+    }
+
+    /// Read the response of a pending request until `cancel` completes, e.g. a shutdown
+    /// signal, see [`read_response_or_retry`](#method.read_response_or_retry).
+    ///
+    /// The requests stay pending when cancelled, abandon them with
+    /// [`cancel_pending_request`](#method.cancel_pending_request).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN), cancelled before
+    ///   the response
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// n.send_public_address_request().await?;
+    /// match n.read_response_or_retry_cancellable(tokio::signal::ctrl_c()).await {
+    ///     Ok(response) => println!("{}", response),
+    ///     Err(Error::NATPMP_TRYAGAIN) => n.cancel_pending_request(),
+    ///     Err(e) => return Err(e),
+    /// }
+    /// ```
+    pub async fn read_response_or_retry_cancellable<F: Future>(
+        &self,
+        cancel: F,
+    ) -> Result<Response> {
+        until_cancelled(self.read_until(None), cancel).await
+    }
+
+    /// Read the response, retransmitting the requests, until `deadline` if any.
+    async fn read_until(&self, deadline: Option<Instant>) -> Result<Response> {
+        let mut buf = [0_u8; NATPMP_MAX_RESPONSE_LEN];
+        loop {
+            let (requests, mut timeout) = self.handle_timeout()?;
+            for (request, len) in &requests {
+                self.send_bytes(&request[..*len]).await?;
+            }
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::NATPMP_TRYAGAIN);
+                }
+                timeout = timeout.min(deadline - now);
+            }
+            let n = match self.s.recv_from_timeout(&mut buf, timeout).await {
+                Ok((n, source)) => {
+                    self.check_source(source)?;
+                    n
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) if is_refused(&e) => return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT),
+                Err(e) => return Err(Error::NATPMP_ERR_RECV(e.kind())),
+            };
+            let now = Instant::now();
+            let mut state = self.state();
+            match state.proto.handle_datagram(&buf[..n], now) {
+                // not the reply, keep the announcements and read the next datagram
+                Err(Error::NATPMP_TRYAGAIN) => state.queue_announcement(&buf[..n], now),
+                result => return result,
+            }
+        }
+    }
+
+    /// Read and parse every datagram already queued on the socket, without waiting.
+    ///
+    /// Gateways retransmit responses and address change announcements, repeated datagrams are
+    /// only returned once and announcements of an unchanged public address are dropped (see
+    /// [`AnnouncementFilter`](struct.AnnouncementFilter.html)). An empty vector is returned if
+    /// nothing is queued.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_RECV`](enum.Error.html#variant.NATPMP_ERR_RECV)
+    /// * Any parse or result code error of a datagram, see [`Response::parse`](enum.Response.html#method.parse)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// for response in n.read_all_available()? {
+    ///     println!("{}", response);
+    /// }
+    /// ```
+    pub fn read_all_available(&self) -> Result<Vec<Response>> {
+        let mut responses = Vec::new();
+        let mut seen: Vec<(usize, [u8; NATPMP_MAX_RESPONSE_LEN])> = Vec::new();
+        loop {
+            let mut buf = [0_u8; NATPMP_MAX_RESPONSE_LEN];
+            let n = match self.s.try_recv_from(&mut buf) {
+                Ok((n, source)) => {
+                    self.check_source(source)?;
+                    n
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(responses),
+                Err(e) => return Err(Error::NATPMP_ERR_RECV(e.kind())),
+            };
+            if seen.contains(&(n, buf)) {
+                continue;
+            }
+            seen.push((n, buf));
+            match Response::parse(&buf[..n])? {
+                Response::Gateway(gr)
+                    if !self.state().announcements.is_new(&gr, Instant::now()) => {}
+                response => responses.push(response),
+            }
+        }
+    }
+}
+
+/// Get the public address with `n`, see the one-shot helpers of the runtimes.
+pub(crate) async fn get_public_address_with<S>(n: NatpmpAsync<S>) -> Result<Ipv4Addr>
+where
+    S: AsyncUdpSocket,
+{
+    let token = n.send_tracked_request(Request::PublicAddress).await?;
+    match n.read_response_for(token).await? {
+        Response::Gateway(gr) => Ok(*gr.public_address()),
+        _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+    }
+}
+
+/// Probe the gateway of `n` with a single request, see the probes of the runtimes.
+pub(crate) async fn probe_gateway_with<S>(n: NatpmpAsync<S>, timeout: Duration) -> Result<bool>
+where
+    S: AsyncUdpSocket,
+{
+    let token = n
+        .send_tracked_request_with_policy(Request::PublicAddress, RetryPolicy::single(timeout))
+        .await?;
+    probe_result(n.read_response_for(token).await)
+}
+
+/// Map `private_port` with `n`, see the one-shot helpers of the runtimes.
+pub(crate) async fn map_port_with<S>(
+    n: NatpmpAsync<S>,
+    protocol: Protocol,
+    private_port: u16,
+    public_port: PublicPort,
+    lifetime: MappingLifetime,
+) -> Result<MappingResponse>
+where
+    S: AsyncUdpSocket,
+{
+    let token = n
+        .send_tracked_request(Request::Mapping {
+            protocol,
+            private_port,
+            public_port: public_port.into(),
+            lifetime: lifetime.as_secs(),
+        })
+        .await?;
+    match n.read_response_for(token).await? {
+        Response::TCP(mr) | Response::UDP(mr) => Ok(mr),
+        Response::Gateway(_) => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+    }
+}
+
+/// PCP async client
+pub struct PcpAsync<S>
+where
+    S: AsyncUdpSocket,
+{
+    s: S,
+    gateway: IpAddr,
+    scope_id: u32,
+    client_address: IpAddr,
+    state: Mutex<PcpState>,
+    nonces: Mutex<Nonces>,
+    authenticator: Mutex<Option<Box<dyn PcpAuthenticator>>>,
+}
+
+/// Pending request and queued announcements of a [`PcpAsync`](struct.PcpAsync.html).
+struct PcpState {
+    proto: PcpProto,
+    announcements: VecDeque<PcpAnnounceResponse>,
+}
+
+/// Create a PCP object with async udpsocket, gateway and the local address of the socket
+pub fn new_pcp_async_with<S>(
+    s: S,
+    gateway: impl Into<IpAddr>,
+    client_address: IpAddr,
+) -> PcpAsync<S>
+where
+    S: AsyncUdpSocket,
+{
+    PcpAsync {
+        s,
+        gateway: gateway.into(),
+        scope_id: 0,
+        client_address,
+        state: Mutex::new(PcpState {
+            proto: PcpProto::new(client_address),
+            announcements: VecDeque::new(),
+        }),
+        nonces: Mutex::new(Nonces::default()),
+        authenticator: Mutex::new(None),
+    }
+}
+
+impl<S> PcpAsync<S>
+where
+    S: AsyncUdpSocket,
+{
+    /// PCP server address.
+    pub fn gateway(&self) -> &IpAddr {
+        &self.gateway
+    }
+
+    /// Scope id of an IPv6 link-local server address, see
+    /// [`get_default_gateway_v6`](fn.get_default_gateway_v6.html).
+    pub fn scope_id(&self) -> u32 {
+        self.scope_id
+    }
+
+    /// Set the scope id of an IPv6 link-local server address, the index of the interface the
+    /// server is reached through. It is ignored for an IPv4 server.
+    pub fn set_scope_id(&mut self, scope_id: u32) {
+        self.scope_id = scope_id;
+    }
+
+    /// Local address of the socket, e.g. to log the source port of the requests.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR), also
+    ///   when the socket does not implement [`AsyncUdpSocket::local_addr`](trait.AsyncUdpSocket.html#method.local_addr)
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.s
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)
+    }
+
+    /// Address the requests are sent from.
+    pub fn client_address(&self) -> &IpAddr {
+        &self.client_address
+    }
+
+    /// Set the retry policy of the following requests.
+    ///
+    /// The requests are retransmitted on the schedule of the policy, as by
+    /// [`Pcp`](struct.Pcp.html), so reading a response waits at most
+    /// [`RetryPolicy::max_wait`](struct.RetryPolicy.html#method.max_wait). After a receive
+    /// failure the next retransmission is waited with
+    /// [`AsyncUdpSocket::sleep`](trait.AsyncUdpSocket.html#method.sleep).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no delay
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> Result<()> {
+        if !policy.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.state().proto.set_retry_policy(policy);
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, PcpState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Abandon the pending request, e.g. after a cancelled read, its response is discarded.
+    pub fn cancel_pending_request(&self) {
+        self.state().proto.cancel_pending_request();
+    }
+
+    /// Pop the oldest ANNOUNCE response received while waiting for the response of a request,
+    /// see [`Pcp::pop_announcement`](struct.Pcp.html#method.pop_announcement).
+    pub fn pop_announcement(&self) -> Option<PcpAnnounceResponse> {
+        self.state().announcements.pop_front()
+    }
+
+    /// Whether the epoch of `response` indicates that the server lost its state since the
+    /// previous response, see [`Pcp::epoch_indicates_reboot`](struct.Pcp.html#method.epoch_indicates_reboot).
+    pub fn epoch_indicates_reboot(&self, response: &PcpResponse) -> bool {
+        self.state().proto.epoch_indicates_reboot(response)
+    }
+
+    fn nonces(&self) -> MutexGuard<'_, Nonces> {
+        self.nonces.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn authenticator(&self) -> MutexGuard<'_, Option<Box<dyn PcpAuthenticator>>> {
+        self.authenticator
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Authenticate the following requests and responses with `authenticator`.
+    pub fn set_authenticator(&self, authenticator: Box<dyn PcpAuthenticator>) {
+        *self.authenticator() = Some(authenticator);
+    }
+
+    /// Stop authenticating requests and responses, returns the previous authenticator.
+    pub fn take_authenticator(&self) -> Option<Box<dyn PcpAuthenticator>> {
+        self.authenticator().take()
+    }
+
+    /// Send a PCP request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_request(&self, request: &PcpRequest) -> Result<()> {
+        self.send_request_with_options(request, &[]).await
+    }
+
+    /// Send a PCP request followed by `options`, e.g. vendor specific ones.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_request_with_options(
+        &self,
+        request: &PcpRequest,
+        options: &[PcpOption],
+    ) -> Result<()> {
+        let mut packet = request.encode_with_options(self.client_address, options);
+        if let Some(authenticator) = &mut *self.authenticator() {
+            authenticator.authenticate_request(&mut packet);
+        }
+        let request = self
+            .state()
+            .proto
+            .send_packet(packet, Instant::now())
+            .to_vec();
+        self.send_bytes(&request).await
+    }
+
+    async fn send_bytes(&self, request: &[u8]) -> Result<()> {
+        let n = self
+            .s
+            .send_to(request, pcp_server(self.gateway, self.scope_id))
+            .await
+            .map_err(|e| send_error(&e))?;
+        if n != request.len() {
+            return Err(Error::NATPMP_ERR_SENDERR);
+        }
+        Ok(())
+    }
+
+    /// Send an ANNOUNCE request, the response carries the server epoch.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_announce_request(&self) -> Result<()> {
+        self.send_request(&PcpRequest::Announce).await
+    }
+
+    /// Send a MAP request.
+    ///
+    /// `external_port` is a suggestion, pass [`PublicPort::Any`](enum.PublicPort.html#variant.Any)
+    /// to let the server choose it.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let pcp = new_tokio_pcp().await?;
+    /// pcp.send_map_request(Protocol::UDP, 4020, 4020, 30).await?;
+    /// ```
+    pub async fn send_map_request(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let request = PcpRequest::Map(PcpMapRequest {
+            nonce: self.nonces().nonce(protocol, internal_port, None, None),
+            protocol,
+            internal_port,
+            external_port: external_port.into().into(),
+            external_address: unspecified(&self.client_address),
+            lifetime: lifetime.into().as_secs(),
+            third_party: None,
+        });
+        self.send_request(&request).await
+    }
+
+    /// Send a MAP request deleting the mapping of `internal_port`.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_map_delete(&self, protocol: Protocol, internal_port: u16) -> Result<()> {
+        self.send_map_request(protocol, internal_port, PublicPort::Any, 0)
+            .await
+    }
+
+    /// Send a MAP request on behalf of the internal host `third_party` with the THIRD_PARTY
+    /// option, a lifetime of 0 deletes its mapping.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_third_party_map_request(
+        &self,
+        third_party: IpAddr,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let request = PcpRequest::Map(PcpMapRequest {
+            nonce: self
+                .nonces()
+                .nonce(protocol, internal_port, None, Some(third_party)),
+            protocol,
+            internal_port,
+            external_port: external_port.into().into(),
+            external_address: unspecified(&self.client_address),
+            lifetime: lifetime.into().as_secs(),
+            third_party: Some(third_party),
+        });
+        self.send_request(&request).await
+    }
+
+    /// Send a PEER request creating or refreshing the mapping of the flow from `internal_port`
+    /// to `remote`.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_peer_request(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        remote: SocketAddr,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let request = PcpRequest::Peer(PcpPeerRequest {
+            nonce: self
+                .nonces()
+                .nonce(protocol, internal_port, Some(remote), None),
+            protocol,
+            internal_port,
+            external_port: external_port.into().into(),
+            external_address: unspecified(&self.client_address),
+            remote_port: remote.port(),
+            remote_address: remote.ip(),
+            lifetime: lifetime.into().as_secs(),
+        });
+        self.send_request(&request).await
+    }
+
+    fn is_authentic(&self, buf: &[u8]) -> bool {
+        match &mut *self.authenticator() {
+            Some(authenticator) => authenticator.verify_response(buf),
+            None => true,
+        }
+    }
+
+    /// Read PCP response if possible
+    ///
+    /// It waits for the response of the pending request, retransmitting it, until the retry
+    /// policy is exhausted. Responses not answering the pending request are discarded, so are the
+    /// responses rejected by the authenticator. Unsolicited ANNOUNCE responses received
+    /// meanwhile are queued, see [`pop_announcement`](#method.pop_announcement).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT),
+    ///   the gateway refused the request
+    /// * [`Error::NATPMP_ERR_TIMEOUT`](enum.Error.html#variant.NATPMP_ERR_TIMEOUT), the last
+    ///   attempt timed out
+    /// * [`Error::NATPMP_ERR_RECV`](enum.Error.html#variant.NATPMP_ERR_RECV), the last attempt
+    ///   failed
+    /// * See [`PcpResponse::parse`](enum.PcpResponse.html#method.parse)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let pcp = new_tokio_pcp().await?;
+    /// pcp.send_map_request(Protocol::UDP, 4020, 4020, 30).await?;
+    /// let response = pcp.read_response_or_retry().await?;
+    /// ```
+    pub async fn read_response_or_retry(&self) -> Result<PcpResponse> {
+        self.read_response_with_options()
+            .await
+            .map(|(response, _)| response)
+    }
+
+    /// Read PCP response until `cancel` completes, e.g. a shutdown signal, see
+    /// [`read_response_or_retry`](#method.read_response_or_retry).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN), cancelled before
+    ///   the response
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry)
+    pub async fn read_response_or_retry_cancellable<F: Future>(
+        &self,
+        cancel: F,
+    ) -> Result<PcpResponse> {
+        until_cancelled(self.read_response_or_retry(), cancel).await
+    }
+
+    /// Read PCP response and its options if possible, see
+    /// [`read_response_or_retry`](#method.read_response_or_retry).
+    ///
+    /// # Errors
+    /// See [`read_response_or_retry`](#method.read_response_or_retry) and
+    /// [`PcpOption::parse_all`](struct.PcpOption.html#method.parse_all).
+    pub async fn read_response_with_options(&self) -> Result<(PcpResponse, Vec<PcpOption>)> {
+        let mut buf = [0_u8; PCP_MAX_PACKET_LEN];
+        let mut error = None;
+        loop {
+            let (request, timeout) = {
+                let now = Instant::now();
+                let mut state = self.state();
+                let request = match state.proto.handle_timeout(now) {
+                    Ok(request) => request.map(<[u8]>::to_vec),
+                    // exhausted, report the failure of the last attempt
+                    Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT) => {
+                        return Err(error.unwrap_or(Error::NATPMP_ERR_TIMEOUT))
+                    }
+                    Err(e) => return Err(e),
+                };
+                (request, state.proto.timeout(now)?)
+            };
+            if let Some(request) = request {
+                error = None;
+                self.send_bytes(&request).await?;
+            }
+            let n = match self.s.recv_from_timeout(&mut buf, timeout).await {
+                // spoofed or stale
+                Ok((_, source)) if source.ip() != self.gateway || source.port() != PCP_PORT => {
+                    continue
+                }
+                Ok((n, _)) => n,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) if is_refused(&e) => return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT),
+                Err(e) => {
+                    error = Some(Error::NATPMP_ERR_RECV(e.kind()));
+                    // back off until the next retransmission instead of spinning
+                    self.s.sleep(timeout).await;
+                    continue;
+                }
+            };
+            if !self.is_authentic(&buf[..n]) {
+                continue;
+            }
+            let now = Instant::now();
+            let mut state = self.state();
+            match state.proto.handle_datagram(&buf[..n], now) {
+                // not the reply, keep the announcements of a restarting server
+                Err(Error::NATPMP_TRYAGAIN) => {
+                    if let Ok(PcpResponse::Announce(mut announce)) = PcpResponse::parse(&buf[..n]) {
+                        announce.received_at = now;
+                        state.announcements.push_back(announce);
+                    }
+                }
+                result => {
+                    self.nonces().validate(&buf[..n]);
+                    let response = result?;
+                    return Ok((response, PcpOption::parse_all(&buf[..n])?));
+                }
+            }
+        }
+    }
+}
+
+/// Whether a receive failed on the ICMP port unreachable of the gateway, which Windows reports
+/// as a reset on unconnected sockets.
+fn is_refused(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Report the exhausted attempts of a request as a timeout.
+fn timeout_error(e: Error) -> Error {
+    match e {
+        Error::NATPMP_ERR_NOGATEWAYSUPPORT => Error::NATPMP_ERR_TIMEOUT,
+        e => e,
+    }
+}
+
+/// Run `read` until `cancel` completes, whichever comes first.
+async fn until_cancelled<T>(
+    read: impl Future<Output = Result<T>>,
+    cancel: impl Future,
+) -> Result<T> {
+    let mut read = pin!(read);
+    let mut cancel = pin!(cancel);
+    poll_fn(|cx| {
+        if let Poll::Ready(result) = read.as_mut().poll(cx) {
+            return Poll::Ready(result);
+        }
+        cancel
+            .as_mut()
+            .poll(cx)
+            .map(|_| Err(Error::NATPMP_TRYAGAIN))
+    })
+    .await
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retransmission() -> Result<()> {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let n = new_natpmp_async_with_port(s, Ipv4Addr::LOCALHOST, port);
+        n.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        })?;
+        assert_eq!(
+            n.read_response_or_retry().await,
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+
+        // clones share the pending requests
+        n.clone().send_public_address_request().await?;
+        let reply = async {
+            let mut buf = [0u8; 2];
+            // the first request is lost
+            server.recv_from(&mut buf).await.unwrap();
+            let (_, client) = server.recv_from(&mut buf).await.unwrap();
+            let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+            server.send_to(&reply, client).await.unwrap();
+        };
+        let (response, ()) = tokio::join!(n.read_response_or_retry(), reply);
+        assert!(matches!(response?, Response::Gateway(_)));
+
+        n.send_public_address_request().await?;
+        assert!(n.has_pending_request());
+        assert_eq!(n.pending_request_type(), Some(Request::PublicAddress));
+        assert_eq!(n.attempts_made(), Some(1));
+        assert!(n.get_natpmp_request_timeout()? <= Duration::from_millis(10));
+        assert_eq!(
+            n.read_response_or_retry_timeout(Duration::from_millis(5))
+                .await,
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        let cancel = tokio::time::sleep(Duration::from_millis(5));
+        assert_eq!(
+            n.read_response_or_retry_cancellable(cancel).await,
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        // still pending after the deadline
+        assert_eq!(
+            n.read_response_or_retry().await,
+            Err(Error::NATPMP_ERR_TIMEOUT)
+        );
+        assert!(!n.has_pending_request());
+        assert_eq!(n.next_retry_at(), None);
+        assert_eq!(
+            n.get_natpmp_request_timeout(),
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_source() {
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let n = new_natpmp_async_with(s, Ipv4Addr::LOCALHOST);
+        let gateway = SocketAddr::from((Ipv4Addr::LOCALHOST, NATPMP_PORT));
+        assert_eq!(n.check_source(gateway), Ok(()));
+        assert_eq!(
+            n.check_source(SocketAddr::from((Ipv4Addr::LOCALHOST, 5350))),
+            Err(Error::NATPMP_ERR_WRONGPACKETSOURCE)
+        );
+        assert_eq!(
+            n.check_source(SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2), NATPMP_PORT))),
+            Err(Error::NATPMP_ERR_WRONGPACKETSOURCE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timed_udp_socket() {
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = TimedUdpSocket::new(s, crate::TokioTimer);
+        let mut buf = [0u8; 16];
+        let result = s
+            .recv_from_timeout(&mut buf, Duration::from_millis(10))
+            .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        let client = s.local_addr().unwrap();
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(&[0, 128], client).await.unwrap();
+        let (n, source) = s
+            .recv_from_timeout(&mut buf, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!((n, source), (2, peer.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_pcp_timeout() -> Result<()> {
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut pcp = new_pcp_async_with(s, Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST.into());
+        pcp.set_retry_policy(RetryPolicy {
+            attempts: 2,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        })?;
+        assert_eq!(
+            pcp.read_response_or_retry().await,
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+        pcp.send_announce_request().await?;
+        assert_eq!(
+            pcp.read_response_or_retry().await,
+            Err(Error::NATPMP_ERR_TIMEOUT)
+        );
+        Ok(())
+    }
+
+    /// Socket failing every receive at once.
+    struct FailingSocket(std::sync::atomic::AtomicU32);
+
+    impl AsyncUdpSocket for FailingSocket {
+        async fn connect(&self, _: SocketAddr) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn send_to(&self, buf: &[u8], _: SocketAddr) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        async fn recv_from(&self, _: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+
+        async fn recv_from_timeout(
+            &self,
+            buf: &mut [u8],
+            timeout: Duration,
+        ) -> io::Result<(usize, SocketAddr)> {
+            timeout_with(&crate::TokioTimer, timeout, self.recv_from(buf)).await
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            tokio::time::sleep(duration).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pcp_recv_backoff() -> Result<()> {
+        let s = FailingSocket(Default::default());
+        let mut pcp = new_pcp_async_with(s, Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST.into());
+        let policy = RetryPolicy {
+            attempts: 3,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        };
+        pcp.set_retry_policy(policy)?;
+        let start = Instant::now();
+        pcp.send_announce_request().await?;
+        assert_eq!(
+            pcp.read_response_or_retry().await,
+            Err(Error::NATPMP_ERR_RECV(io::ErrorKind::PermissionDenied))
+        );
+        assert!(start.elapsed() >= policy.max_wait());
+        assert_eq!(pcp.s.0.load(std::sync::atomic::Ordering::Relaxed), 3);
+        Ok(())
+    }
+
+    /// Gateway announcing a restart before replying to the last request.
+    #[derive(Default)]
+    struct AnnouncingSocket {
+        sent: Mutex<Vec<Vec<u8>>>,
+        received: std::sync::atomic::AtomicU32,
+    }
+
+    impl AsyncUdpSocket for AnnouncingSocket {
+        async fn connect(&self, _: SocketAddr) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn send_to(&self, buf: &[u8], _: SocketAddr) -> io::Result<usize> {
+            self.sent.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let reply = if self
+                .received
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                == 0
+            {
+                let mut announce = vec![2, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+                announce.resize(24, 0);
+                announce
+            } else {
+                let mut reply = self.sent.lock().unwrap().last().unwrap().clone();
+                reply[1] |= 0x80;
+                reply[8..12].copy_from_slice(&1000u32.to_be_bytes());
+                reply[12..24].fill(0);
+                reply
+            };
+            buf[..reply.len()].copy_from_slice(&reply);
+            Ok((
+                reply.len(),
+                SocketAddr::from((Ipv4Addr::LOCALHOST, PCP_PORT)),
+            ))
+        }
+
+        async fn recv_from_timeout(
+            &self,
+            buf: &mut [u8],
+            _: Duration,
+        ) -> io::Result<(usize, SocketAddr)> {
+            self.recv_from(buf).await
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            tokio::time::sleep(duration).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pcp_unsolicited_announce() -> Result<()> {
+        let s = AnnouncingSocket::default();
+        let pcp = new_pcp_async_with(s, Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST.into());
+        pcp.send_map_request(Protocol::UDP, 4020, 4020, 30).await?;
+        match pcp.read_response_or_retry().await? {
+            PcpResponse::Map(map) => assert_eq!(map.internal_port(), 4020),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(pcp.pop_announcement().map(|a| a.epoch()), Some(1));
+        assert_eq!(pcp.pop_announcement(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_response_for() -> Result<()> {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let n = new_natpmp_async_with_port(s, Ipv4Addr::LOCALHOST, port);
+        let mapping = |protocol| Request::Mapping {
+            protocol,
+            private_port: 4020,
+            public_port: 4020,
+            lifetime: 30,
+        };
+        let tcp = n.send_tracked_request(mapping(Protocol::TCP)).await?;
+        let udp = n.send_tracked_request(mapping(Protocol::UDP)).await?;
+
+        // both tasks wait, the UDP response is received first
+        let tcp = tokio::spawn({
+            let n = n.clone();
+            async move { n.read_response_for(tcp).await }
+        });
+        let udp = tokio::spawn({
+            let n = n.clone();
+            async move { n.read_response_for(udp).await }
+        });
+        let mut buf = [0u8; 12];
+        let (_, client) = server.recv_from(&mut buf).await.unwrap();
+        server.recv_from(&mut buf).await.unwrap();
+        for opcode in [129, 130] {
+            let reply = [0, opcode, 0, 0, 0, 0, 0, 1, 15, 180, 15, 180, 0, 0, 0, 30];
+            server.send_to(&reply, client).await.unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        let udp = tokio::time::timeout(timeout, udp).await.unwrap().unwrap()?;
+        let tcp = tokio::time::timeout(timeout, tcp).await.unwrap().unwrap()?;
+        assert!(matches!(udp, Response::UDP(_)));
+        assert!(matches!(tcp, Response::TCP(_)));
+        assert!(!n.has_pending_request());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_policy() -> Result<()> {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let n = new_natpmp_async_with_port(s, Ipv4Addr::LOCALHOST, port);
+        let probe = RetryPolicy {
+            attempts: 2,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(
+            n.send_tracked_request_with_policy(
+                Request::PublicAddress,
+                RetryPolicy {
+                    attempts: 0,
+                    ..probe
+                }
+            )
+            .await,
+            Err(Error::NATPMP_ERR_INVALIDARGS)
+        );
+
+        // the probe gives up after 2 attempts, the mapping request keeps the default schedule
+        let mapping = n
+            .send_tracked_request(Request::delete_mapping(Protocol::UDP, 4020))
+            .await?;
+        let token = n
+            .send_tracked_request_with_policy(Request::PublicAddress, probe)
+            .await?;
+        let result = tokio::time::timeout(Duration::from_secs(5), n.read_response_for(token))
+            .await
+            .unwrap();
+        assert_eq!(result, Err(Error::NATPMP_ERR_TIMEOUT));
+        assert_eq!(
+            n.pending_request_type(),
+            Some(Request::delete_mapping(Protocol::UDP, 4020))
+        );
+        assert!(n.cancel_request(mapping));
+        assert_eq!(n.retry_policy(), RetryPolicy::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_probe_gateway() -> Result<()> {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let n = new_natpmp_async_with_port(s, Ipv4Addr::LOCALHOST, port);
+        let start = Instant::now();
+        assert!(!probe_gateway_with(n, Duration::from_millis(50)).await?);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let mut buf = [0u8; 2];
+        server.recv_from(&mut buf).await.unwrap();
+
+        // an error result code is still a NAT-PMP gateway
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let n = new_natpmp_async_with_port(s, Ipv4Addr::LOCALHOST, port);
+        let gateway = tokio::spawn(async move {
+            let (_, client) = server.recv_from(&mut buf).await.unwrap();
+            let reply = [0, 128, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0];
+            server.send_to(&reply, client).await.unwrap();
+        });
+        assert!(probe_gateway_with(n, Duration::from_secs(5)).await?);
+        gateway.await.unwrap();
+        Ok(())
     }
 }
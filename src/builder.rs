@@ -0,0 +1,346 @@
+//! Configuration of a [`Natpmp`](struct.Natpmp.html) or [`NatpmpAsync`](struct.NatpmpAsync.html)
+//! client.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use crate::{
+    bind_socket, connect_socket_from, new_natpmp_async_with_port, AsyncUdpSocket, Error,
+    GatewaySource, Natpmp, NatpmpAsync, NatpmpProto, Result, RetryPolicy, NATPMP_PORT,
+};
+
+/// Builder of a [`Natpmp`](struct.Natpmp.html) client, see [`Natpmp::builder`](struct.Natpmp.html#method.builder).
+///
+/// By default the client talks to the default gateway on [`NATPMP_PORT`](constant.NATPMP_PORT.html)
+/// from `0.0.0.0:0`, and retries with the default [`RetryPolicy`](struct.RetryPolicy.html).
+#[derive(Debug, Clone)]
+pub struct NatpmpBuilder {
+    gateway: GatewaySource,
+    gateway_port: u16,
+    bind_address: SocketAddrV4,
+    retry: RetryPolicy,
+    ttl: Option<u32>,
+    #[cfg(all(
+        feature = "bind-device",
+        any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+    ))]
+    device: Option<String>,
+}
+
+impl Default for NatpmpBuilder {
+    fn default() -> Self {
+        NatpmpBuilder::new()
+    }
+}
+
+impl NatpmpBuilder {
+    /// Create a builder with the default configuration.
+    pub fn new() -> NatpmpBuilder {
+        NatpmpBuilder {
+            gateway: GatewaySource::Auto,
+            gateway_port: NATPMP_PORT,
+            bind_address: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            retry: RetryPolicy::default(),
+            ttl: None,
+            #[cfg(all(
+                feature = "bind-device",
+                any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+            ))]
+            device: None,
+        }
+    }
+
+    /// Gateway address, the default gateway if not set.
+    pub fn gateway(mut self, gateway: Ipv4Addr) -> NatpmpBuilder {
+        self.gateway = GatewaySource::Fixed(gateway);
+        self
+    }
+
+    /// Where the gateway address comes from, e.g. [`GatewaySource::Env`](enum.GatewaySource.html#variant.Env)
+    /// to let operators override it.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// // NATPMP_GATEWAY=10.0.0.1 in a container
+    /// let n = Natpmp::builder()
+    ///     .gateway_source(GatewaySource::Env)
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn gateway_source(mut self, source: GatewaySource) -> NatpmpBuilder {
+        self.gateway = source;
+        self
+    }
+
+    /// Gateway port, e.g. of a test server.
+    pub fn gateway_port(mut self, port: u16) -> NatpmpBuilder {
+        self.gateway_port = port;
+        self
+    }
+
+    /// Local address and port to bind the socket to, e.g. to choose the source interface of a
+    /// multi-homed host.
+    pub fn bind_address(mut self, address: SocketAddrV4) -> NatpmpBuilder {
+        self.bind_address = address;
+        self
+    }
+
+    /// Number of times a request is sent before giving up with
+    /// [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT).
+    pub fn attempts(mut self, attempts: u32) -> NatpmpBuilder {
+        self.retry.attempts = attempts;
+        self
+    }
+
+    /// Delay before the first retry, doubled for each following one.
+    pub fn initial_retry_delay(mut self, delay: Duration) -> NatpmpBuilder {
+        self.retry.initial_delay = delay;
+        self
+    }
+
+    /// Retry policy of the requests.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> NatpmpBuilder {
+        self.retry = policy;
+        self
+    }
+
+    /// Time-to-live of the sent packets.
+    pub fn ttl(mut self, ttl: u32) -> NatpmpBuilder {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Network interface the requests leave from regardless of the routing table
+    /// (`SO_BINDTODEVICE`), e.g. one uplink of a multi-homed router. Binding to a device may
+    /// require the `CAP_NET_RAW` capability.
+    #[cfg(all(
+        feature = "bind-device",
+        any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+    ))]
+    pub fn bind_device(mut self, device: &str) -> NatpmpBuilder {
+        self.device = Some(device.to_owned());
+        self
+    }
+
+    fn gateway_address(&self) -> Result<Ipv4Addr> {
+        if !self.retry.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.gateway.resolve()
+    }
+
+    /// Bind the socket, connected to the NAT-PMP server of the gateway if `connect`.
+    fn socket(&self, connect: bool) -> Result<(UdpSocket, Ipv4Addr)> {
+        let gateway = self.gateway_address()?;
+        let local = SocketAddr::V4(self.bind_address);
+        let server = SocketAddrV4::new(gateway, self.gateway_port).into();
+        let server = Some(server).filter(|_| connect);
+        let s = self.bind(local, server)?;
+        if let Some(ttl) = self.ttl {
+            s.set_ttl(ttl).map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        }
+        Ok((s, gateway))
+    }
+
+    fn bind(&self, local: SocketAddr, server: Option<SocketAddr>) -> Result<UdpSocket> {
+        #[cfg(all(
+            feature = "bind-device",
+            any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+        ))]
+        if let Some(device) = &self.device {
+            return device_socket(device, local, server);
+        }
+        match server {
+            Some(server) => connect_socket_from(local, server),
+            None => bind_socket(local),
+        }
+    }
+
+    /// Create the client.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no retry delay
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    /// * See [`GatewaySource::resolve`](enum.GatewaySource.html#method.resolve)
+    pub fn build(self) -> Result<Natpmp> {
+        let (s, gateway) = self.socket(true)?;
+        let mut proto = NatpmpProto::new();
+        proto.set_retry_policy(self.retry);
+        #[allow(unused_mut)]
+        let mut n = Natpmp::with_socket(s, self.bind_address.into(), gateway, proto);
+        #[cfg(all(
+            feature = "bind-device",
+            any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+        ))]
+        {
+            n.device = self.device;
+        }
+        Ok(n)
+    }
+
+    /// Create a tokio client, must be called within a tokio runtime.
+    ///
+    /// The socket is not connected, the async clients send to the gateway address.
+    ///
+    /// # Errors
+    /// Same as [`build`](#method.build), except
+    /// [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR).
+    #[cfg(feature = "tokio")]
+    pub fn build_tokio(self) -> Result<NatpmpAsync<tokio::net::UdpSocket>> {
+        let (s, gateway) = self.socket(false)?;
+        let s = tokio::net::UdpSocket::from_std(s).map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        self.build_async(s, gateway)
+    }
+
+    /// Create an async-std client.
+    ///
+    /// The socket is not connected, the async clients send to the gateway address.
+    ///
+    /// # Errors
+    /// Same as [`build`](#method.build), except
+    /// [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR).
+    #[cfg(feature = "async-std")]
+    pub fn build_async_std(self) -> Result<NatpmpAsync<async_std::net::UdpSocket>> {
+        let (s, gateway) = self.socket(false)?;
+        self.build_async(s.into(), gateway)
+    }
+
+    /// Create an async client with a socket created by the caller, e.g. for another runtime.
+    ///
+    /// The requests are sent to the gateway address and port, the socket settings of the
+    /// builder do not apply.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no retry delay
+    /// * See [`GatewaySource::resolve`](enum.GatewaySource.html#method.resolve)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// let s = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    /// let n = NatpmpAsync::builder()
+    ///     .gateway(Ipv4Addr::new(192, 168, 0, 1))
+    ///     .attempts(4)
+    ///     .build_with_socket(s)?;
+    /// ```
+    pub fn build_with_socket<S>(self, s: S) -> Result<NatpmpAsync<S>>
+    where
+        S: AsyncUdpSocket,
+    {
+        let gateway = self.gateway_address()?;
+        self.build_async(s, gateway)
+    }
+
+    fn build_async<S>(&self, s: S, gateway: Ipv4Addr) -> Result<NatpmpAsync<S>>
+    where
+        S: AsyncUdpSocket,
+    {
+        let n = new_natpmp_async_with_port(s, gateway, self.gateway_port);
+        n.set_retry_policy(self.retry)?;
+        Ok(n)
+    }
+}
+
+/// Bind a non-blocking udp socket to `device` and `local`, and connect it to `server` if any.
+#[cfg(all(
+    feature = "bind-device",
+    any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+))]
+pub(crate) fn device_socket(
+    device: &str,
+    local: SocketAddr,
+    server: Option<SocketAddr>,
+) -> Result<UdpSocket> {
+    use socket2::{Domain, Socket, Type};
+
+    let s = Socket::new(Domain::for_address(local), Type::DGRAM, None)
+        .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+    s.bind_device(Some(device.as_bytes()))
+        .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+    s.bind(&local.into())
+        .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+    s.set_nonblocking(true)
+        .map_err(|_| Error::NATPMP_ERR_FCNTLERROR)?;
+    if let Some(server) = server {
+        s.connect(&server.into())
+            .map_err(|_| Error::NATPMP_ERR_CONNECTERR)?;
+    }
+    Ok(s.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Protocol, Response};
+
+    #[test]
+    fn test_builder() {
+        assert_eq!(
+            NatpmpBuilder::new().attempts(0).build().err(),
+            Some(Error::NATPMP_ERR_INVALIDARGS)
+        );
+
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let n = Natpmp::builder()
+            .gateway(Ipv4Addr::LOCALHOST)
+            .gateway_port(server.local_addr().unwrap().port())
+            .bind_address(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .attempts(2)
+            .initial_retry_delay(Duration::from_millis(10))
+            .ttl(2)
+            .build()
+            .unwrap();
+        n.send_port_mapping_request(Protocol::TCP, 4020, 4020, 30)
+            .unwrap();
+        assert!(n.get_natpmp_request_timeout().unwrap() <= Duration::from_millis(10));
+
+        let mut buf = [0u8; 12];
+        let (len, client) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0, 2, 0, 0, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30]
+        );
+        let reply = [
+            0, 130, 0, 0, 0, 0, 0, 1, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+        ];
+        server.send_to(&reply, client).unwrap();
+        let response = loop {
+            match n.read_response_or_retry() {
+                Err(Error::NATPMP_TRYAGAIN) => std::thread::sleep(Duration::from_millis(5)),
+                r => break r.unwrap(),
+            }
+        };
+        assert!(matches!(response, Response::TCP(mr) if mr.public_port() == 4020));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_build_with_socket() {
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let n = NatpmpAsync::builder()
+            .gateway(Ipv4Addr::LOCALHOST)
+            .gateway_port(15351)
+            .attempts(2)
+            .build_with_socket(s)
+            .unwrap();
+        assert_eq!(n.gateway(), &Ipv4Addr::LOCALHOST);
+        assert_eq!(n.retry_policy().attempts, 2);
+
+        let s = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let result = NatpmpBuilder::new()
+            .gateway(Ipv4Addr::LOCALHOST)
+            .attempts(0)
+            .build_with_socket(s);
+        assert!(matches!(result, Err(Error::NATPMP_ERR_INVALIDARGS)));
+    }
+}
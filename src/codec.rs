@@ -0,0 +1,96 @@
+//! tokio-util codec of the NAT-PMP datagrams.
+
+use std::io;
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::protocol::NATPMP_MAX_REQUEST_LEN;
+use crate::{Request, Response};
+
+/// Codec of the NAT-PMP requests and responses, e.g. for a `tokio_util::udp::UdpFramed`.
+///
+/// Each datagram is one frame. Invalid responses and non-success result codes are reported
+/// as `io::ErrorKind::InvalidData` errors wrapping the [`Error`](enum.Error.html).
+///
+/// # Examples
+/// ```
+/// use std::net::{Ipv4Addr, SocketAddr};
+/// use futures::{SinkExt, StreamExt};
+/// use tokio_util::udp::UdpFramed;
+/// use natpmp::*;
+///
+/// let s = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+/// let mut framed = UdpFramed::new(s, NatpmpCodec);
+/// let gateway = SocketAddr::from((get_default_gateway()?, NATPMP_PORT));
+/// framed.send((Request::PublicAddress, gateway)).await?;
+/// if let Some(Ok((response, source))) = framed.next().await {
+///     println!("{} from {}", response, source);
+/// }
+/// ```
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NatpmpCodec;
+
+impl Encoder<Request> for NatpmpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, request: Request, dst: &mut BytesMut) -> io::Result<()> {
+        let mut buf = [0u8; NATPMP_MAX_REQUEST_LEN];
+        let n = request.encode_into(&mut buf);
+        dst.extend_from_slice(&buf[..n]);
+        Ok(())
+    }
+}
+
+impl Decoder for NatpmpCodec {
+    type Item = Response;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Response>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        // the whole datagram is consumed, valid or not
+        let datagram = src.split();
+        Response::parse(&datagram)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Protocol};
+
+    #[test]
+    fn test_codec() {
+        let mut buf = BytesMut::new();
+        let request = Request::Mapping {
+            protocol: Protocol::UDP,
+            private_port: 4020,
+            public_port: 4020,
+            lifetime: 30,
+        };
+        NatpmpCodec.encode(request, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 1, 0, 0, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30]);
+
+        let mut buf = BytesMut::from(&[0, 128, 0, 0, 0, 0, 0, 1, 10, 0, 0, 1][..]);
+        assert!(matches!(
+            NatpmpCodec.decode(&mut buf),
+            Ok(Some(Response::Gateway(_)))
+        ));
+        assert!(buf.is_empty());
+        assert!(NatpmpCodec.decode(&mut buf).unwrap().is_none());
+
+        // not authorized
+        let mut buf = BytesMut::from(&[0, 128, 0, 2, 0, 0, 0, 1, 10, 0, 0, 1][..]);
+        let e = NatpmpCodec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(&Error::NATPMP_ERR_NOTAUTHORIZED)
+        );
+        assert!(buf.is_empty());
+    }
+}
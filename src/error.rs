@@ -1,13 +1,18 @@
-use std::fmt;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
+
+use crate::{PcpResultCode, ResultCode};
 
 /// NAT-PMP error.
 ///
 /// # Note
 ///
 /// These errors are for compatibility only:
-/// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS)
 /// * [`Error::NATPMP_ERR_CLOSEERR`](enum.Error.html#variant.NATPMP_ERR_CLOSEERR)
 /// * [`Error::NATPMP_ERR_GETTIMEOFDAYERR`](enum.Error.html#variant.NATPMP_ERR_GETTIMEOFDAYERR)
+/// * [`Error::NATPMP_ERR_UNDEFINEDERROR`](enum.Error.html#variant.NATPMP_ERR_UNDEFINEDERROR), superseded by
+///   [`Error::NATPMP_ERR_UNKNOWNRESULTCODE`](enum.Error.html#variant.NATPMP_ERR_UNKNOWNRESULTCODE)
 ///
 /// # Examples
 /// ```
@@ -19,6 +24,7 @@ use std::fmt;
 ///
 #[allow(non_camel_case_types)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Error {
     /// Invalid arguments
     NATPMP_ERR_INVALIDARGS,
@@ -76,6 +82,65 @@ pub enum Error {
 
     /// Try again
     NATPMP_TRYAGAIN,
+
+    /// Received packet is too short
+    NATPMP_ERR_TRUNCATEDPACKET,
+
+    /// No response to any attempt of the retry policy, the async clients report it instead of
+    /// `NATPMP_ERR_NOGATEWAYSUPPORT` or `NATPMP_ERR_RECVFROM` so that a later retry may succeed
+    NATPMP_ERR_TIMEOUT,
+
+    /// Failed to recvfrom the socket of an async client, with the kind of the I/O error, e.g.
+    /// to tell a network going down from a closed socket. It can not be serialized
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    NATPMP_ERR_RECV(io::ErrorKind),
+
+    /// Unknown NAT-PMP result code from server, e.g. a vendor specific one
+    NATPMP_ERR_UNKNOWNRESULTCODE(u16),
+
+    /// Non-success PCP result code from server and the lifetime of the error in seconds
+    PCP_ERR_RESULTCODE(PcpResultCode, u32),
+}
+
+impl Error {
+    /// NAT-PMP result code behind the error, if it is reported by the server.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// assert_eq!(Error::NATPMP_ERR_NOTAUTHORIZED.result_code(), Some(ResultCode::NotAuthorized));
+    /// assert_eq!(Error::NATPMP_ERR_SENDERR.result_code(), None);
+    /// ```
+    pub fn result_code(&self) -> Option<ResultCode> {
+        match *self {
+            Error::NATPMP_ERR_UNSUPPORTEDVERSION => Some(ResultCode::UnsupportedVersion),
+            Error::NATPMP_ERR_NOTAUTHORIZED => Some(ResultCode::NotAuthorized),
+            Error::NATPMP_ERR_NETWORKFAILURE => Some(ResultCode::NetworkFailure),
+            Error::NATPMP_ERR_OUTOFRESOURCES => Some(ResultCode::OutOfResources),
+            Error::NATPMP_ERR_UNSUPPORTEDOPCODE => Some(ResultCode::UnsupportedOpcode),
+            Error::NATPMP_ERR_UNKNOWNRESULTCODE(code) => Some(ResultCode::Other(code)),
+            _ => None,
+        }
+    }
+
+    /// PCP result code and the lifetime of the error in seconds, if it is reported by the server.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let err = Error::PCP_ERR_RESULTCODE(PcpResultCode::NoResources, 30);
+    /// assert_eq!(err.pcp_result_code(), Some((PcpResultCode::NoResources, 30)));
+    /// assert_eq!(Error::NATPMP_ERR_SENDERR.pcp_result_code(), None);
+    /// ```
+    pub fn pcp_result_code(&self) -> Option<(PcpResultCode, u32)> {
+        match *self {
+            Error::PCP_ERR_RESULTCODE(code, lifetime) => Some((code, lifetime)),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -108,8 +173,22 @@ impl fmt::Display for Error {
             Error::NATPMP_ERR_NETWORKFAILURE => write!(f, "network failure"),
             Error::NATPMP_ERR_OUTOFRESOURCES => write!(f, "nat-pmp server out of resources"),
             Error::NATPMP_TRYAGAIN => write!(f, "try again"),
+            Error::NATPMP_ERR_TRUNCATEDPACKET => write!(f, "truncated nat-pmp packet"),
+            Error::NATPMP_ERR_TIMEOUT => write!(f, "no response from the gateway"),
+            #[cfg(feature = "std")]
+            Error::NATPMP_ERR_RECV(kind) => write!(f, "recvfrom failed: {}", kind),
+            Error::NATPMP_ERR_UNKNOWNRESULTCODE(code) => {
+                write!(f, "unknown nat-pmp result code {} from server", code)
+            }
+            Error::PCP_ERR_RESULTCODE(code, lifetime) => write!(
+                f,
+                "pcp result code {} from server, error lifetime {} seconds",
+                u8::from(*code),
+                lifetime
+            ),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
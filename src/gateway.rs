@@ -0,0 +1,386 @@
+//! Discovery of the gateways of the host.
+
+use std::env;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{is_answered_by, NATPMP_MAX_REQUEST_LEN, NATPMP_MAX_RESPONSE_LEN};
+use crate::{
+    get_default_gateway, probe_result, send_error, set_read_timeout, Error, Request, Response,
+    Result, RetryPolicy, NATPMP_PORT,
+};
+
+/// Environment variable read by [`GatewaySource::Env`](enum.GatewaySource.html#variant.Env).
+pub const NATPMP_GATEWAY_ENV: &str = "NATPMP_GATEWAY";
+
+/// Where the gateway of a client comes from, see
+/// [`NatpmpBuilder::gateway_source`](struct.NatpmpBuilder.html#method.gateway_source).
+///
+/// # Examples
+/// ```
+/// use std::net::Ipv4Addr;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let source = GatewaySource::Fixed(Ipv4Addr::new(10, 0, 0, 1));
+/// assert_eq!(source.resolve()?, Ipv4Addr::new(10, 0, 0, 1));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum GatewaySource {
+    /// The gateway of the default route, see [`get_default_gateway`](fn.get_default_gateway.html).
+    #[default]
+    Auto,
+    /// A given gateway, e.g. a router the routing table of a container or VM does not show.
+    Fixed(Ipv4Addr),
+    /// The address in the [`NATPMP_GATEWAY`](constant.NATPMP_GATEWAY_ENV.html) environment
+    /// variable, so that operators can force a router, else the gateway of the default route.
+    Env,
+}
+
+impl GatewaySource {
+    /// Get the gateway address.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), the
+    ///   environment variable is set but is not an IPv4 address
+    /// * See [`get_default_gateway`](fn.get_default_gateway.html) for the default route
+    pub fn resolve(&self) -> Result<Ipv4Addr> {
+        self.resolve_with(|name| env::var(name).ok(), get_default_gateway)
+    }
+
+    /// Get the gateway address, reading environment variables with `var` and the gateway of
+    /// the default route with `default_gateway`.
+    fn resolve_with<V, G>(&self, var: V, default_gateway: G) -> Result<Ipv4Addr>
+    where
+        V: FnOnce(&str) -> Option<String>,
+        G: FnOnce() -> Result<Ipv4Addr>,
+    {
+        match self {
+            GatewaySource::Auto => default_gateway(),
+            GatewaySource::Fixed(gateway) => Ok(*gateway),
+            GatewaySource::Env => match var(NATPMP_GATEWAY_ENV) {
+                Some(gateway) => gateway
+                    .trim()
+                    .parse()
+                    .map_err(|_| Error::NATPMP_ERR_INVALIDARGS),
+                None => default_gateway(),
+            },
+        }
+    }
+}
+
+/// Gateway resolved once per time-to-live, for applications creating clients often without
+/// reading the routing table each time.
+///
+/// Errors are not cached, the gateway is resolved again on the next call. Invalidate the
+/// cache when the network changes, e.g. on the events of a
+/// [`GatewayWatcher`](struct.GatewayWatcher.html).
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let cache = GatewayCache::new(Duration::from_secs(60));
+/// let n = Natpmp::new_with(cache.get()?)?;
+/// // after roaming
+/// cache.invalidate();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct GatewayCache {
+    source: GatewaySource,
+    ttl: Duration,
+    cached: Mutex<Option<(Ipv4Addr, Instant)>>,
+}
+
+impl GatewayCache {
+    /// Create a cache of the default gateway, resolved again after `ttl`.
+    pub fn new(ttl: Duration) -> GatewayCache {
+        GatewayCache::with_source(GatewaySource::Auto, ttl)
+    }
+
+    /// Create a cache of the gateway of `source`, resolved again after `ttl`.
+    pub fn with_source(source: GatewaySource, ttl: Duration) -> GatewayCache {
+        GatewayCache {
+            source,
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn cached(&self) -> MutexGuard<'_, Option<(Ipv4Addr, Instant)>> {
+        self.cached.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the gateway, resolving it if the cached one is older than the time-to-live.
+    ///
+    /// # Errors
+    /// * See [`GatewaySource::resolve`](enum.GatewaySource.html#method.resolve)
+    pub fn get(&self) -> Result<Ipv4Addr> {
+        let now = Instant::now();
+        let mut cached = self.cached();
+        if let Some((gateway, resolved_at)) = *cached {
+            if now.saturating_duration_since(resolved_at) < self.ttl {
+                return Ok(gateway);
+            }
+        }
+        let gateway = self.source.resolve()?;
+        *cached = Some((gateway, now));
+        Ok(gateway)
+    }
+
+    /// Forget the cached gateway, the next [`get`](#method.get) resolves it again.
+    pub fn invalidate(&self) {
+        *self.cached() = None;
+    }
+}
+
+/// Gateway of a network interface, see [`get_gateways`](fn.get_gateways.html).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct GatewayInfo {
+    /// Address of the gateway.
+    pub address: Ipv4Addr,
+    /// Name of the interface the gateway is reached through.
+    pub interface: String,
+    /// Index of the interface.
+    pub index: u32,
+    /// Whether it is the gateway of the default route, the one of
+    /// [`get_default_gateway`](fn.get_default_gateway.html).
+    pub default: bool,
+}
+
+/// Get the IPv4 gateways of every network interface, e.g. to choose the router to ask for
+/// mappings on a multi-homed host.
+///
+/// The gateway of the default route comes first, the other ones follow in the order of the
+/// interfaces. The routing metrics are not reported by every system, they are not used.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_CANNOTGETGATEWAY`](enum.Error.html#variant.NATPMP_ERR_CANNOTGETGATEWAY), no
+///   gateway at all
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// for gateway in get_gateways()? {
+///     println!("{} via {}", gateway.address, gateway.interface);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn get_gateways() -> Result<Vec<GatewayInfo>> {
+    let mut gateways: Vec<GatewayInfo> = netdev::get_interfaces()
+        .into_iter()
+        .flat_map(|interface| {
+            let addresses = interface.gateway.map(|g| g.ipv4).unwrap_or_default();
+            addresses.into_iter().map(move |address| GatewayInfo {
+                address,
+                interface: interface.name.clone(),
+                index: interface.index,
+                default: interface.default,
+            })
+        })
+        .collect();
+    if gateways.is_empty() {
+        return Err(Error::NATPMP_ERR_CANNOTGETGATEWAY);
+    }
+    // stable, the interfaces keep their order
+    gateways.sort_by_key(|g| !g.default);
+    Ok(gateways)
+}
+
+/// Find the gateway speaking NAT-PMP among the gateways of every interface, see
+/// [`get_gateways`](fn.get_gateways.html), e.g. on a network with several routers.
+///
+/// A public address request is sent to all the candidates at once from a single socket and
+/// retransmitted to them with the default [`RetryPolicy`](struct.RetryPolicy.html). The first
+/// gateway to answer wins, even with an error result code, the later answers are ignored.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_CANNOTGETGATEWAY`](enum.Error.html#variant.NATPMP_ERR_CANNOTGETGATEWAY), no
+///   gateway at all
+/// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT), no
+///   answer after the last attempt
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+/// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+/// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR), no candidate
+///   could be sent the request
+/// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let gateway = discover_natpmp_gateway()?;
+/// let n = Natpmp::new_with(gateway)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn discover_natpmp_gateway() -> Result<Ipv4Addr> {
+    let mut candidates: Vec<SocketAddrV4> = Vec::new();
+    for gateway in get_gateways()? {
+        let candidate = SocketAddrV4::new(gateway.address, NATPMP_PORT);
+        if !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    discover_among(&candidates, &RetryPolicy::default())
+}
+
+/// Ask `candidates` until one answers or `policy` is exhausted.
+fn discover_among(candidates: &[SocketAddrV4], policy: &RetryPolicy) -> Result<Ipv4Addr> {
+    let s =
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+    let mut request = [0u8; NATPMP_MAX_REQUEST_LEN];
+    let len = Request::PublicAddress.encode_into(&mut request);
+    let request = &request[..len];
+    let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+    for try_number in 1..=policy.attempts {
+        let mut sent = 0;
+        let mut error = Error::NATPMP_ERR_SENDERR;
+        for candidate in candidates {
+            // an unreachable candidate does not stop the others from being asked
+            match s.send_to(request, candidate) {
+                Ok(_) => sent += 1,
+                Err(e) => error = send_error(&e),
+            }
+        }
+        if sent == 0 {
+            return Err(error);
+        }
+        let deadline = Instant::now() + policy.delay(try_number);
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            set_read_timeout(&s, deadline - now)?;
+            match s.recv_from(&mut buf) {
+                Ok((n, SocketAddr::V4(source)))
+                    if candidates.contains(&source) && is_answered_by(request, &buf[..n]) =>
+                {
+                    if probe_result(Response::parse(&buf[..n])) == Ok(true) {
+                        return Ok(*source.ip());
+                    }
+                }
+                Ok(_) => {}
+                // timed out, or an ICMP error of a candidate not speaking NAT-PMP
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionReset
+                    ) => {}
+                Err(_) => return Err(Error::NATPMP_ERR_RECVFROM),
+            }
+        }
+    }
+    Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_gateway_source() {
+        let gateway = Ipv4Addr::new(10, 0, 0, 1);
+        assert_eq!(GatewaySource::default(), GatewaySource::Auto);
+        assert_eq!(GatewaySource::Fixed(gateway).resolve(), Ok(gateway));
+
+        let router = Ipv4Addr::new(192, 168, 0, 1);
+        let resolve = |source: GatewaySource, value: Option<&str>| {
+            source.resolve_with(
+                |name| {
+                    assert_eq!(name, NATPMP_GATEWAY_ENV);
+                    value.map(str::to_owned)
+                },
+                || Ok(router),
+            )
+        };
+        assert_eq!(resolve(GatewaySource::Auto, Some("10.0.0.1")), Ok(router));
+        assert_eq!(
+            resolve(GatewaySource::Env, Some(" 10.0.0.1\n")),
+            Ok(gateway)
+        );
+        assert_eq!(
+            resolve(GatewaySource::Env, Some("router")),
+            Err(Error::NATPMP_ERR_INVALIDARGS)
+        );
+        assert_eq!(resolve(GatewaySource::Env, None), Ok(router));
+    }
+
+    #[test]
+    fn test_gateway_cache() -> Result<()> {
+        let gateway = Ipv4Addr::new(10, 0, 0, 1);
+        let cache =
+            GatewayCache::with_source(GatewaySource::Fixed(gateway), Duration::from_secs(60));
+        assert_eq!(cache.get()?, gateway);
+        let (_, resolved_at) = cache.cached().unwrap();
+        assert_eq!(cache.get()?, gateway);
+        assert_eq!(cache.cached().map(|(_, at)| at), Some(resolved_at));
+        cache.invalidate();
+        assert!(cache.cached().is_none());
+
+        // expired right away
+        let cache = GatewayCache::with_source(GatewaySource::Fixed(gateway), Duration::ZERO);
+        cache.get()?;
+        let (_, resolved_at) = cache.cached().unwrap();
+        assert_eq!(cache.get()?, gateway);
+        assert!(cache.cached().map(|(_, at)| at) >= Some(resolved_at));
+        Ok(())
+    }
+
+    #[test]
+    fn test_discover_among() -> Result<()> {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let policy = RetryPolicy {
+            attempts: 2,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        };
+        let candidate = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        assert_eq!(
+            discover_among(&[candidate], &policy),
+            Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+        );
+
+        // the second candidate answers
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gateway = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 2];
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+            server.send_to(&reply, client).unwrap();
+        });
+        let policy = RetryPolicy {
+            attempts: 3,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(
+            discover_among(&[candidate, gateway], &policy),
+            Ok(Ipv4Addr::LOCALHOST)
+        );
+        handle.join().unwrap();
+        Ok(())
+    }
+}
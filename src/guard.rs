@@ -0,0 +1,187 @@
+//! Port mapping kept alive for the lifetime of a guard.
+
+use tokio::runtime::Handle;
+
+use crate::asynchronous::map_port_with;
+use crate::{
+    AsyncUdpSocket, MappingLifetime, MappingRenewer, MappingResponse, NatpmpAsync, Protocol,
+    PublicPort, RenewalPolicy, Request, Result,
+};
+
+/// Port mapping renewed while the guard is alive and deleted when it is dropped, see
+/// [`NatpmpAsync::map`](struct.NatpmpAsync.html#method.map).
+///
+/// On drop the deletion is sent from a task spawned on the runtime the mapping was created
+/// on. Use [`close`](#method.close) to wait for the deletion and learn whether it succeeded.
+pub struct MappingGuard<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    n: NatpmpAsync<S>,
+    protocol: Protocol,
+    private_port: u16,
+    renewer: Option<MappingRenewer>,
+    handle: Handle,
+}
+
+impl<S> NatpmpAsync<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    /// Request a mapping of `private_port` and keep it renewed until the returned guard is
+    /// dropped or closed, it must be called from a tokio runtime.
+    ///
+    /// The mapping is renewed with the default [`RenewalPolicy`](struct.RenewalPolicy.html) on a
+    /// task sharing this client, other tasks read their responses with
+    /// [`read_response_for`](#method.read_response_for) meanwhile.
+    ///
+    /// # Errors
+    /// The errors of [`read_response_or_retry`](#method.read_response_or_retry), and
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// let guard = n.map(Protocol::TCP, 8080, 8080, 3600).await?;
+    /// // serve on port 8080, the mapping is deleted when the guard goes out of scope
+    /// ```
+    pub async fn map(
+        &self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<MappingGuard<S>> {
+        let public_port = public_port.into();
+        let lifetime = lifetime.into();
+        let mr = map_port_with(self.clone(), protocol, private_port, public_port, lifetime).await?;
+        let renewer = MappingRenewer::new(self.clone(), RenewalPolicy::default())?;
+        renewer.add_granted(mr, public_port, lifetime);
+        Ok(MappingGuard {
+            n: self.clone(),
+            protocol,
+            private_port,
+            renewer: Some(renewer),
+            handle: Handle::current(),
+        })
+    }
+}
+
+impl<S> MappingGuard<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    /// Mapping protocol.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Private port of the mapping.
+    pub fn private_port(&self) -> u16 {
+        self.private_port
+    }
+
+    /// Last response of the gateway, `None` while the mapping is expired and requested again.
+    pub fn mapping(&self) -> Option<MappingResponse> {
+        self.renewer
+            .as_ref()
+            .and_then(|renewer| renewer.status().first().and_then(|status| status.mapping))
+    }
+
+    /// Stop renewing the mapping and delete it on the gateway, waiting for the response.
+    ///
+    /// # Errors
+    /// The errors of [`NatpmpAsync::read_response_or_retry`](struct.NatpmpAsync.html#method.read_response_or_retry),
+    /// and
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = new_tokio_natpmp().await?;
+    /// let guard = n.map(Protocol::UDP, 4020, 4020, 3600).await?;
+    /// guard.close().await?;
+    /// ```
+    pub async fn close(mut self) -> Result<()> {
+        drop(self.renewer.take());
+        delete(&self.n, self.protocol, self.private_port).await
+    }
+}
+
+impl<S> Drop for MappingGuard<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        if self.renewer.take().is_none() {
+            // closed
+            return;
+        }
+        let n = self.n.clone();
+        let (protocol, private_port) = (self.protocol, self.private_port);
+        // best effort, the mapping expires anyway
+        self.handle.spawn(async move {
+            let _ = delete(&n, protocol, private_port).await;
+        });
+    }
+}
+
+async fn delete<S>(n: &NatpmpAsync<S>, protocol: Protocol, private_port: u16) -> Result<()>
+where
+    S: AsyncUdpSocket,
+{
+    let token = n
+        .send_tracked_request(Request::delete_mapping(protocol, private_port))
+        .await?;
+    n.read_response_for(token).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::new_natpmp_async_with_port;
+
+    #[tokio::test]
+    async fn test_mapping_guard() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let n = new_natpmp_async_with_port(s, Ipv4Addr::LOCALHOST, port);
+
+        let gateway = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let mut buf = [0u8; 12];
+                let (_, client) = server.recv_from(&mut buf).await.unwrap();
+                let reply = [
+                    0, 130, 0, 0, 0, 0, 0, 1, buf[4], buf[5], buf[6], buf[7], buf[8], buf[9],
+                    buf[10], buf[11],
+                ];
+                server.send_to(&reply, client).await.unwrap();
+                requests.push(buf);
+            }
+            requests
+        });
+
+        let guard = n.map(Protocol::TCP, 8080, 8080, 3600).await?;
+        assert_eq!(guard.mapping().map(|mr| mr.public_port()), Some(8080));
+        drop(guard);
+        let requests = tokio::time::timeout(Duration::from_secs(5), gateway)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(requests[0], [0, 2, 0, 0, 31, 144, 31, 144, 0, 0, 14, 16]);
+        // deletion on drop
+        assert_eq!(requests[1], [0, 2, 0, 0, 31, 144, 0, 0, 0, 0, 0, 0]);
+        Ok(())
+    }
+}
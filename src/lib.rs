@@ -2,22 +2,83 @@
 //!
 //! `natpmp` is a NAT-PMP [IETF RFC 6886](https://tools.ietf.org/html/rfc6886) client library in rust.
 //! It is a rust implementation of the c library [natpmp](https://github.com/miniupnp/natpmp).
+//! PCP [IETF RFC 6887](https://tools.ietf.org/html/rfc6887) servers are supported by [`Pcp`].
+//!
+//! # Features
+//!
+//! * `std` (default): sockets, gateway discovery, PCP and the sync/async clients. Without it the
+//!   crate is `no_std` and only provides the allocation-free request/response codec
+//!   ([`Request`], [`Response::parse`]).
+//! * `tokio` (default), `async-std`: async clients for the runtimes.
+//! * `serde`: `Serialize`/`Deserialize` for the response types, [`Protocol`] and [`Error`].
+//! * `bind-device`: `NatpmpBuilder::bind_device` to send the requests through a given network
+//!   interface (Linux, Android and Fuchsia only).
+//! * `mio`: `mio::event::Source` for [`Natpmp`] and [`Pcp`], to drive them from a mio `Poll` loop
+//!   (unix only).
+//! * `embedded-nal-async`: `NatpmpNal`, a `no_std` async client on the `embedded-nal-async` UDP
+//!   traits, e.g. for embassy-net firmware.
 
-use std::io;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::ops::Add;
-use std::result;
-use std::time::{Duration, Instant};
+#![cfg_attr(not(feature = "std"), no_std)]
 
-use netdev;
+use core::fmt;
+use core::net::Ipv4Addr;
+use core::result;
+use core::str::FromStr;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+#[cfg(all(feature = "mio", unix))]
+use std::os::fd::AsRawFd;
+#[cfg(feature = "std")]
+use std::sync::{Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "std")]
+use std::time::Instant;
 
+#[cfg(feature = "std")]
+mod announcements;
+#[cfg(feature = "std")]
 mod asynchronous;
+#[cfg(feature = "std")]
+mod builder;
+#[cfg(feature = "codec")]
+mod codec;
 mod error;
+#[cfg(feature = "std")]
+mod gateway;
+#[cfg(feature = "std")]
+mod listener;
+#[cfg(feature = "std")]
+mod mapper;
+#[cfg(all(test, feature = "std"))]
+mod mock;
+#[cfg(feature = "std")]
+mod multi;
+#[cfg(feature = "embedded-nal-async")]
+mod nal;
+#[cfg(feature = "std")]
+mod pcp;
+mod protocol;
 
 #[cfg(feature = "tokio")]
 mod a_tokio;
 #[cfg(feature = "tokio")]
 pub use a_tokio::*;
+#[cfg(feature = "tokio")]
+mod guard;
+#[cfg(feature = "tokio")]
+pub use guard::*;
+#[cfg(feature = "tokio")]
+mod renewer;
+#[cfg(feature = "tokio")]
+pub use renewer::*;
+#[cfg(feature = "tokio")]
+mod watcher;
+#[cfg(feature = "tokio")]
+pub use watcher::*;
 
 #[cfg(feature = "async-std")]
 mod a_std;
@@ -25,13 +86,27 @@ mod a_std;
 pub use a_std::*;
 
 pub use crate::error::*;
+#[cfg(feature = "std")]
+pub use announcements::*;
+#[cfg(feature = "std")]
 pub use asynchronous::*;
-
-/// NAT-PMP mini wait milli-seconds
-const NATPMP_MIN_WAIT: u64 = 250;
-
-/// NAT-PMP max retry
-const NATPMP_MAX_ATTEMPS: u32 = 9;
+#[cfg(feature = "std")]
+pub use builder::*;
+#[cfg(feature = "codec")]
+pub use codec::*;
+#[cfg(feature = "std")]
+pub use gateway::*;
+#[cfg(feature = "std")]
+pub use listener::*;
+#[cfg(feature = "std")]
+pub use mapper::*;
+#[cfg(feature = "std")]
+pub use multi::*;
+#[cfg(feature = "embedded-nal-async")]
+pub use nal::*;
+#[cfg(feature = "std")]
+pub use pcp::*;
+pub use protocol::*;
 
 /// NAT-PMP server port as defined by rfc6886.
 pub const NATPMP_PORT: u16 = 5351;
@@ -41,6 +116,15 @@ pub type Result<T> = result::Result<T, Error>;
 
 /// Get default gateway.
 ///
+/// It is the gateway of the default route, read from the routing table of the system by the
+/// pure Rust `netdev` crate on every platform, so no C toolchain is needed to build the crate.
+///
+/// Android restricts the routing table to apps from API level 30. When it cannot be read,
+/// get the gateway on the Java side from `ConnectivityManager.getLinkProperties(network)`
+/// and `LinkProperties.getRoutes()`, then pass it through JNI to
+/// [`Natpmp::new_with`](struct.Natpmp.html#method.new_with) or
+/// [`NatpmpBuilder::gateway`](struct.NatpmpBuilder.html#method.gateway).
+///
 /// # Errors
 /// * [`Error::NATPMP_ERR_CANNOTGETGATEWAY`](enum.Error.html#variant.NATPMP_ERR_CANNOTGETGATEWAY)
 ///
@@ -51,22 +135,291 @@ pub type Result<T> = result::Result<T, Error>;
 /// let r = get_default_gateway();
 /// assert_eq!(r.is_ok(), true);
 /// ```
+#[cfg(feature = "std")]
 pub fn get_default_gateway() -> Result<Ipv4Addr> {
-    if let Ok(ipv4_addrs) = netdev::get_default_gateway().map(|g| g.ipv4) {
-        if let Some(gw) = ipv4_addrs.get(0) {
-            return Ok(gw.clone());
-        }
+    netdev::get_default_gateway()
+        .ok()
+        .and_then(|g| g.ipv4.first().copied())
+        .ok_or(Error::NATPMP_ERR_CANNOTGETGATEWAY)
+}
+
+/// Get the IPv6 default router, e.g. for a PCP client over IPv6.
+///
+/// Routers usually advertise a link-local address, only reachable through the interface it
+/// was learned on. The scope id to connect to the router is returned with it: the index of
+/// the interface for a link-local address, 0 otherwise.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_CANNOTGETGATEWAY`](enum.Error.html#variant.NATPMP_ERR_CANNOTGETGATEWAY)
+///
+/// # Examples
+/// ```
+/// use std::net::SocketAddrV6;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let (gateway, scope_id) = get_default_gateway_v6()?;
+/// let server = SocketAddrV6::new(gateway, PCP_PORT, 0, scope_id);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+pub fn get_default_gateway_v6() -> Result<(Ipv6Addr, u32)> {
+    let interface =
+        netdev::get_default_interface().map_err(|_| Error::NATPMP_ERR_CANNOTGETGATEWAY)?;
+    let gateway = interface
+        .gateway
+        .and_then(|g| g.ipv6.first().copied())
+        .ok_or(Error::NATPMP_ERR_CANNOTGETGATEWAY)?;
+    // fe80::/10
+    let scope_id = if gateway.segments()[0] & 0xffc0 == 0xfe80 {
+        interface.index
+    } else {
+        0
+    };
+    Ok((gateway, scope_id))
+}
+
+/// Get the public address from the default gateway, waiting for the response.
+///
+/// The request is retransmitted with the default [`RetryPolicy`](struct.RetryPolicy.html),
+/// use a [`Natpmp`](struct.Natpmp.html) client to control the retries or to avoid blocking.
+///
+/// # Errors
+/// * See [`Natpmp::new`](struct.Natpmp.html#method.new) and
+///   [`Natpmp::wait_response`](struct.Natpmp.html#method.wait_response)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let public_address = get_public_address()?;
+/// println!("public address: {}", public_address);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+pub fn get_public_address() -> Result<Ipv4Addr> {
+    let n = Natpmp::new()?;
+    n.send_public_address_request()?;
+    match n.wait_response()? {
+        Response::Gateway(gr) => Ok(*gr.public_address()),
+        _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+    }
+}
+
+/// Map `private_port` on the default gateway, waiting for the response.
+///
+/// `public_port` and `lifetime` are the same as for
+/// [`Natpmp::send_port_mapping_request`](struct.Natpmp.html#method.send_port_mapping_request).
+/// The mapping expires after its lifetime unless it is requested again.
+///
+/// # Errors
+/// * See [`Natpmp::new`](struct.Natpmp.html#method.new) and
+///   [`Natpmp::wait_response`](struct.Natpmp.html#method.wait_response)
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mapping = map_port(Protocol::TCP, 4020, 4020, 3600)?;
+/// println!("mapped to public port {}", mapping.public_port());
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+pub fn map_port(
+    protocol: Protocol,
+    private_port: u16,
+    public_port: impl Into<PublicPort>,
+    lifetime: impl Into<MappingLifetime>,
+) -> Result<MappingResponse> {
+    let n = Natpmp::new()?;
+    n.send_port_mapping_request(protocol, private_port, public_port, lifetime)?;
+    match n.wait_response()? {
+        Response::TCP(mr) | Response::UDP(mr) => Ok(mr),
+        Response::Gateway(_) => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+    }
+}
+
+/// Check whether `gateway` speaks NAT-PMP, with a single public address request waiting
+/// `timeout` for the response.
+///
+/// The request is not retransmitted, so an application falls back to UPnP or a relay after
+/// about `timeout` instead of the 2 minutes of retransmissions of rfc6886. A response with an
+/// error result code still counts as NAT-PMP. A lost datagram looks like no support, prefer a
+/// timeout of a second or so to a few milliseconds.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), zero `timeout`
+/// * See [`Natpmp::new_with`](struct.Natpmp.html#method.new_with) and
+///   [`Natpmp::wait_response`](struct.Natpmp.html#method.wait_response)
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let gateway = get_default_gateway()?;
+/// if !probe_gateway(gateway, Duration::from_secs(1))? {
+///     println!("no NAT-PMP, trying UPnP");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+pub fn probe_gateway(gateway: Ipv4Addr, timeout: Duration) -> Result<bool> {
+    let mut n = Natpmp::new_with(gateway)?;
+    n.set_retry_policy(RetryPolicy::single(timeout))?;
+    n.send_public_address_request()?;
+    probe_result(n.wait_response())
+}
+
+/// Whether the result of a probe comes from a NAT-PMP gateway.
+#[cfg(feature = "std")]
+pub(crate) fn probe_result(result: Result<Response>) -> Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(Error::NATPMP_ERR_UNDEFINEDERROR) => Ok(true),
+        Err(e) if e.result_code().is_some() => Ok(true),
+        Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT) | Err(Error::NATPMP_ERR_TIMEOUT) => Ok(false),
+        Err(e) => Err(e),
     }
-    Err(Error::NATPMP_ERR_CANNOTGETGATEWAY)
 }
 
 /// NAT-PMP mapping protocol.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Protocol {
     UDP,
     TCP,
 }
 
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::UDP => write!(f, "udp"),
+            Protocol::TCP => write!(f, "tcp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = Error;
+
+    /// Parse `"udp"` or `"tcp"`, case insensitive.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// assert_eq!("udp".parse(), Ok(Protocol::UDP));
+    /// assert_eq!("TCP".parse(), Ok(Protocol::TCP));
+    /// assert_eq!("sctp".parse::<Protocol>(), Err(Error::NATPMP_ERR_INVALIDARGS));
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("udp") {
+            Ok(Protocol::UDP)
+        } else if s.eq_ignore_ascii_case("tcp") {
+            Ok(Protocol::TCP)
+        } else {
+            Err(Error::NATPMP_ERR_INVALIDARGS)
+        }
+    }
+}
+
+/// Public port of a port mapping request.
+///
+/// A plain `u16` converts into it, `0` meaning [`PublicPort::Any`](#variant.Any).
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let n = Natpmp::new()?;
+/// n.send_port_mapping_request(Protocol::UDP, 4020, PublicPort::Any, 30)?;
+/// // the port chosen by the gateway is in MappingResponse::public_port()
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum PublicPort {
+    /// Let the gateway choose the public port.
+    Any,
+    /// Suggested public port, the gateway may still assign another one.
+    Port(u16),
+}
+
+impl From<u16> for PublicPort {
+    fn from(port: u16) -> Self {
+        match port {
+            0 => PublicPort::Any,
+            _ => PublicPort::Port(port),
+        }
+    }
+}
+
+impl From<PublicPort> for u16 {
+    fn from(port: PublicPort) -> Self {
+        match port {
+            PublicPort::Any => 0,
+            PublicPort::Port(port) => port,
+        }
+    }
+}
+
+/// Lifetime of a port mapping request.
+///
+/// Converts from seconds as `u32` or from a `Duration`, which saturates at `u32::MAX` seconds and
+/// rounds fractional seconds up so that a non-zero duration never requests a deletion.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// assert_eq!(MappingLifetime::from(Duration::from_secs(3600)).as_secs(), 3600);
+/// assert_eq!(MappingLifetime::from(Duration::from_millis(1500)).as_secs(), 2);
+/// assert_eq!(MappingLifetime::from(Duration::MAX).as_secs(), u32::MAX);
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct MappingLifetime(u32);
+
+impl MappingLifetime {
+    /// Lifetime in seconds.
+    pub fn as_secs(&self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for MappingLifetime {
+    fn from(secs: u32) -> Self {
+        MappingLifetime(secs)
+    }
+}
+
+impl From<Duration> for MappingLifetime {
+    fn from(duration: Duration) -> Self {
+        let secs = duration
+            .as_secs()
+            .saturating_add(u64::from(duration.subsec_nanos() > 0));
+        MappingLifetime(u32::try_from(secs).unwrap_or(u32::MAX))
+    }
+}
+
+impl From<MappingLifetime> for Duration {
+    fn from(lifetime: MappingLifetime) -> Self {
+        Duration::from_secs(lifetime.0.into())
+    }
+}
+
 /// NAT-PMP response type.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum ResponseType {
@@ -77,6 +430,7 @@ pub enum ResponseType {
 
 /// Gateway response.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GatewayResponse {
     epoch: u32,
     public_address: Ipv4Addr,
@@ -96,13 +450,34 @@ impl GatewayResponse {
     }
 }
 
+impl fmt::Display for GatewayResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "public address {} (epoch {})",
+            self.public_address, self.epoch
+        )
+    }
+}
+
 /// Mapping response.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+///
+/// With the `serde` feature the receipt time is serialized as a `SystemTime`, so that
+/// [`expires_at`](#method.expires_at) still holds in another process, as long as the wall clock
+/// did not jump in between.
+///
+/// Responses compare equal when their parsed values are equal, whenever they were received.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MappingResponse {
     epoch: u32,
+    protocol: Protocol,
     private_port: u16,
     public_port: u16,
     lifetime: Duration,
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "serde", serde(with = "received_at"))]
+    received_at: Instant,
 }
 
 impl MappingResponse {
@@ -113,12 +488,20 @@ impl MappingResponse {
         self.epoch
     }
 
+    /// Mapping protocol.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
     /// Private/internal port.
     pub fn private_port(&self) -> u16 {
         self.private_port
     }
 
-    /// Public/external port.
+    /// Public/external port assigned by the gateway.
+    ///
+    /// It may differ from the requested one, and is always chosen by the gateway for
+    /// [`PublicPort::Any`](enum.PublicPort.html#variant.Any) requests.
     pub fn public_port(&self) -> u16 {
         self.public_port
     }
@@ -127,327 +510,1496 @@ impl MappingResponse {
     pub fn lifetime(&self) -> &Duration {
         &self.lifetime
     }
+
+    /// Whether the response acknowledges a mapping deletion (lifetime 0).
+    pub fn is_deleted(&self) -> bool {
+        self.lifetime.is_zero()
+    }
+
+    /// Whether the response acknowledges the deletion of all mappings of the client (private
+    /// port, public port and lifetime 0).
+    pub fn is_all_deleted(&self) -> bool {
+        self.is_deleted() && self.private_port == 0 && self.public_port == 0
+    }
+
+    /// When the response was received.
+    #[cfg(feature = "std")]
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// When the mapping expires.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+    /// // later
+    /// if let Ok(Response::UDP(ur)) = n.read_response_or_retry() {
+    ///     // renew the mapping at half of its lifetime
+    ///     let renew_at = ur.expires_at() - *ur.lifetime() / 2;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn expires_at(&self) -> Instant {
+        self.received_at + self.lifetime
+    }
+
+    /// Remaining lifetime of the mapping, zero once it expired.
+    #[cfg(feature = "std")]
+    pub fn remaining(&self) -> Duration {
+        self.expires_at().saturating_duration_since(Instant::now())
+    }
+}
+
+impl PartialEq for MappingResponse {
+    fn eq(&self, other: &Self) -> bool {
+        self.epoch == other.epoch
+            && self.protocol == other.protocol
+            && self.private_port == other.private_port
+            && self.public_port == other.public_port
+            && self.lifetime == other.lifetime
+    }
+}
+
+impl Eq for MappingResponse {}
+
+/// Receipt time as a wall-clock time, an `Instant` is only meaningful within the process.
+#[cfg(all(feature = "std", feature = "serde"))]
+mod received_at {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        received_at: &Instant,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let at = SystemTime::now()
+            .checked_sub(received_at.elapsed())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        at.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        let at = SystemTime::deserialize(deserializer)?;
+        // a receipt in the future, e.g. the clock went back, is taken as now
+        let age = SystemTime::now()
+            .duration_since(at)
+            .unwrap_or(Duration::ZERO);
+        Instant::now()
+            .checked_sub(age)
+            .ok_or_else(|| de::Error::custom("receipt time out of range"))
+    }
+}
+
+impl fmt::Display for MappingResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_deleted() {
+            write!(
+                f,
+                "{} mapping {} deleted (epoch {})",
+                self.protocol, self.private_port, self.epoch
+            )
+        } else {
+            write!(
+                f,
+                "{} mapping {} -> {}, lifetime {}s (epoch {})",
+                self.protocol,
+                self.private_port,
+                self.public_port,
+                self.lifetime.as_secs(),
+                self.epoch
+            )
+        }
+    }
 }
 
 /// NAT-PMP response.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Response {
     Gateway(GatewayResponse),
     UDP(MappingResponse),
     TCP(MappingResponse),
 }
 
-/// NAT-PMP main struct.
-///
-/// # Examples
-/// ```
-/// use std::thread;
-/// use std::time::Duration;
-/// use natpmp::*;
-///
-/// # fn main() -> Result<()> {
-/// let mut n = Natpmp::new()?;
-/// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
-/// thread::sleep(Duration::from_millis(100));
-/// let response = n.read_response_or_retry()?;
-///  match response {
-///      Response::UDP(ur) => {
-///          assert_eq!(ur.private_port(), 4020);
-///          assert_eq!(ur.public_port(), 4020);
-///      }
-///      _ => panic!("Not a udp mapping response"),
-/// }
-/// # Ok(())
-/// # }
-/// ```
-#[derive(Debug)]
-pub struct Natpmp {
-    s: UdpSocket,
-    gateway: Ipv4Addr,
-    has_pending_request: bool,
-    pending_request: [u8; 12],
-    pending_request_len: usize,
-    try_number: u32,
-    retry_time: Instant,
+impl fmt::Display for Response {
+    /// Human readable summary of the response.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let buf = [0, 130, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0, 30];
+    /// let response = Response::parse(&buf)?;
+    /// assert_eq!(response.to_string(), "tcp mapping 4020 -> 4021, lifetime 30s (epoch 9)");
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Response::Gateway(gr) => gr.fmt(f),
+            Response::UDP(mr) | Response::TCP(mr) => mr.fmt(f),
+        }
+    }
 }
 
-impl Natpmp {
-    /// Create a NAT-PMP object with default gateway.
+impl Response {
+    /// Parse a NAT-PMP response datagram.
+    ///
+    /// Useful when the datagram is received from a socket or a packet capture owned by the
+    /// application itself.
     ///
     /// # Errors
-    /// See [`get_default_gateway`](fn.get_default_gateway.html) and [`Natpmp::new_with`](struct.Natpmp.html#method.new_with).
+    /// * [`Error::NATPMP_ERR_TRUNCATEDPACKET`](enum.Error.html#variant.NATPMP_ERR_TRUNCATEDPACKET)
+    /// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
+    /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDOPCODE)
+    /// * [`Error::NATPMP_ERR_NOTAUTHORIZED`](enum.Error.html#variant.NATPMP_ERR_NOTAUTHORIZED)
+    /// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
+    /// * [`Error::NATPMP_ERR_OUTOFRESOURCES`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
+    /// * [`Error::NATPMP_ERR_UNKNOWNRESULTCODE`](enum.Error.html#variant.NATPMP_ERR_UNKNOWNRESULTCODE)
     ///
     /// # Examples
     /// ```
     /// use natpmp::*;
     ///
-    /// let n = Natpmp::new();
-    /// assert_eq!(n.is_ok(), true);
+    /// # fn main() -> Result<()> {
+    /// let buf = [0, 130, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30];
+    /// match Response::parse(&buf)? {
+    ///     Response::TCP(tr) => assert_eq!(tr.public_port(), 4020),
+    ///     _ => panic!("Not a tcp mapping response"),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn parse(buf: &[u8]) -> Result<Response> {
+        protocol::parse_response(
+            buf,
+            #[cfg(feature = "std")]
+            Instant::now(),
+        )
+    }
+
+    /// Seconds since epoch of the gateway.
+    ///
+    /// **Note: May be not accurate.**
+    pub fn epoch(&self) -> u32 {
+        match self {
+            Response::Gateway(gr) => gr.epoch(),
+            Response::UDP(mr) | Response::TCP(mr) => mr.epoch(),
+        }
+    }
+
+    /// The mapping response of a UDP or TCP response.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let buf = [0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0, 30];
+    /// let response = Response::parse(&buf)?;
+    /// assert_eq!(response.epoch(), 9);
+    /// assert_eq!(response.as_mapping().map(|m| m.public_port()), Some(4021));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn as_mapping(&self) -> Option<&MappingResponse> {
+        match self {
+            Response::Gateway(_) => None,
+            Response::UDP(mr) | Response::TCP(mr) => Some(mr),
+        }
+    }
+
+    /// Result code of the response.
+    ///
+    /// Always [`ResultCode::Success`](enum.ResultCode.html#variant.Success), failed results are
+    /// returned as errors, see [`Error::result_code`](enum.Error.html#method.result_code).
+    pub fn result_code(&self) -> ResultCode {
+        ResultCode::Success
+    }
+}
+
+/// Bind a non-blocking udp socket connected to `server`.
+#[cfg(feature = "std")]
+pub(crate) fn connect_socket(server: SocketAddr) -> Result<UdpSocket> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    connect_socket_from(local, server)
+}
+
+/// Bind a non-blocking udp socket to `local` and connect it to `server`.
+#[cfg(feature = "std")]
+pub(crate) fn connect_socket_from(local: SocketAddr, server: SocketAddr) -> Result<UdpSocket> {
+    let s = bind_socket(local)?;
+    if s.connect(server).is_err() {
+        return Err(Error::NATPMP_ERR_CONNECTERR);
+    }
+    Ok(s)
+}
+
+/// Bind a non-blocking udp socket to `local`.
+#[cfg(feature = "std")]
+pub(crate) fn bind_socket(local: SocketAddr) -> Result<UdpSocket> {
+    let s: UdpSocket;
+    if let Ok(udpsock) = UdpSocket::bind(local) {
+        s = udpsock;
+    } else {
+        return Err(Error::NATPMP_ERR_SOCKETERROR);
+    }
+    if s.set_nonblocking(true).is_err() {
+        return Err(Error::NATPMP_ERR_FCNTLERROR);
+    }
+    Ok(s)
+}
+
+/// Source address of the datagrams sent to `server`, looked up by connecting a socket, which
+/// sends nothing.
+#[cfg(feature = "std")]
+pub(crate) fn route_source(server: SocketAddr) -> Result<IpAddr> {
+    connect_socket(server)?
+        .local_addr()
+        .map(|local| local.ip())
+        .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn send_request(s: &UdpSocket, request: &[u8]) -> Result<()> {
+    match s.send(request) {
+        Ok(n) if n == request.len() => Ok(()),
+        Ok(_) => Err(Error::NATPMP_ERR_SENDERR),
+        Err(e) => Err(send_error(&e)),
+    }
+}
+
+/// Error of a failed send, an ICMP error of a previous request may surface there instead of on
+/// the receive path.
+#[cfg(feature = "std")]
+pub(crate) fn send_error(e: &io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable => Error::NATPMP_ERR_NOGATEWAYSUPPORT,
+        _ => Error::NATPMP_ERR_SENDERR,
+    }
+}
+
+/// Interval between polls of the non-blocking socket in blocking calls.
+#[cfg(feature = "std")]
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait for a datagram until the next retransmission of a blocking socket, in `timeout`.
+#[cfg(feature = "std")]
+pub(crate) fn set_read_timeout(s: &UdpSocket, timeout: Duration) -> Result<()> {
+    // a zero timeout would wait forever
+    s.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+        .map_err(|_| Error::NATPMP_ERR_FCNTLERROR)
+}
+
+/// Receive a datagram from `gateway`, without waiting unless the socket is blocking.
+#[cfg(feature = "std")]
+pub(crate) fn recv_datagram(s: &UdpSocket, gateway: IpAddr, buf: &mut [u8]) -> Result<usize> {
+    match s.recv_from(buf) {
+        Err(e) => match e.kind() {
+            // timed out on a blocking socket
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Err(Error::NATPMP_TRYAGAIN),
+            io::ErrorKind::ConnectionRefused => Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT),
+            _ => Err(Error::NATPMP_ERR_RECVFROM),
+        },
+        Ok((n, sockaddr)) => {
+            // check gateway address
+            if sockaddr.ip() != gateway {
+                return Err(Error::NATPMP_ERR_WRONGPACKETSOURCE);
+            }
+            Ok(n)
+        }
+    }
+}
+
+/// Progress of the pending requests, see [`Natpmp::poll_response`](struct.Natpmp.html#method.poll_response).
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResponsePoll {
+    /// Response of a pending request.
+    Ready(Response),
+    /// No response yet, poll again once the socket is readable or at `deadline` at the latest
+    /// to retransmit the requests.
+    Pending { deadline: Instant },
+}
+
+/// NAT-PMP main struct.
+///
+/// Requests are sent and responses read through `&self`, so a client can be shared by
+/// several threads, e.g. in an `Arc`, see [`read_response_for`](#method.read_response_for).
+///
+/// # Examples
+/// ```
+/// use std::thread;
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let n = Natpmp::new()?;
+/// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+/// thread::sleep(Duration::from_millis(100));
+/// let response = n.read_response_or_retry()?;
+///  match response {
+///      Response::UDP(ur) => {
+///          assert_eq!(ur.private_port(), 4020);
+///          assert_eq!(ur.public_port(), 4020);
+///      }
+///      _ => panic!("Not a udp mapping response"),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Natpmp {
+    s: UdpSocket,
+    local: SocketAddr,
+    #[cfg(all(
+        feature = "bind-device",
+        any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+    ))]
+    device: Option<String>,
+    gateway: Ipv4Addr,
+    state: Mutex<State>,
+    blocking: bool,
+}
+
+/// Request state of a [`Natpmp`](struct.Natpmp.html) client, shared by the threads using it.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+struct State {
+    proto: NatpmpProto,
+    announcements: AnnouncementFilter,
+    announcement_queue: VecDeque<GatewayResponse>,
+    responses: VecDeque<(RequestToken, Result<Response>)>,
+    public_address: Option<GatewayResponse>,
+}
+
+#[cfg(feature = "std")]
+impl State {
+    fn queue_announcement(&mut self, buf: &[u8], now: Instant) {
+        if self.proto.is_late_response(buf) {
+            return;
+        }
+        if let Ok(Response::Gateway(gr)) = Response::parse(buf) {
+            if self.announcements.is_new(&gr, now) {
+                self.announcement_queue.push_back(gr);
+                self.public_address = None;
+            }
+        }
+    }
+
+    /// Cache the public address of a response, forget it if the gateway rebooted.
+    fn cache_public_address(&mut self, response: &Response) {
+        match response {
+            Response::Gateway(gr) => self.public_address = Some(*gr),
+            _ if self.proto.epoch_indicates_reboot(response) => self.public_address = None,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Natpmp {
+    /// Create a NAT-PMP object with default gateway.
+    ///
+    /// # Errors
+    /// See [`get_default_gateway`](fn.get_default_gateway.html) and [`Natpmp::new_with`](struct.Natpmp.html#method.new_with).
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = Natpmp::new();
+    /// assert_eq!(n.is_ok(), true);
+    /// ```
+    pub fn new() -> Result<Natpmp> {
+        let gateway = get_default_gateway()?;
+        Natpmp::new_with(gateway)
+    }
+
+    /// Create a NAT-PMP object with a specified gateway.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::str::FromStr;
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// let n = Natpmp::new_with("192.168.0.1".parse().unwrap()).unwrap();
+    /// ```
+    pub fn new_with(gateway: Ipv4Addr) -> Result<Natpmp> {
+        Natpmp::new_with_port(gateway, NATPMP_PORT)
+    }
+
+    /// Create a NAT-PMP object with a specified gateway listening on a non-default `port`, e.g.
+    /// a test server or a port-forwarded gateway.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// let n = Natpmp::new_with_port(Ipv4Addr::LOCALHOST, 15351).unwrap();
+    /// ```
+    pub fn new_with_port(gateway: Ipv4Addr, port: u16) -> Result<Natpmp> {
+        let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+        Natpmp::new_bound(local, SocketAddrV4::new(gateway, port))
+    }
+
+    /// Create a NAT-PMP object with its socket bound to `local`, e.g. to choose the source
+    /// interface of a multi-homed host, talking to the gateway at `gateway`.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::{Ipv4Addr, SocketAddrV4};
+    /// use natpmp::*;
+    ///
+    /// let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    /// let gateway = SocketAddrV4::new(Ipv4Addr::LOCALHOST, NATPMP_PORT);
+    /// let n = Natpmp::new_bound(local, gateway).unwrap();
+    /// ```
+    pub fn new_bound(local: SocketAddrV4, gateway: SocketAddrV4) -> Result<Natpmp> {
+        let s = connect_socket_from(local.into(), gateway.into())?;
+        Ok(Natpmp::with_socket(
+            s,
+            local.into(),
+            *gateway.ip(),
+            NatpmpProto::new(),
+        ))
+    }
+
+    /// Create a NAT-PMP object from a socket configured by the caller, e.g. with a TTL or a
+    /// type of service.
+    ///
+    /// `s` must be connected to the NAT-PMP server of `gateway`, it is switched to non-blocking
+    /// mode. Get it back with [`into_inner`](#method.into_inner).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::{Ipv4Addr, UdpSocket};
+    /// use natpmp::*;
+    ///
+    /// let gateway = Ipv4Addr::new(192, 168, 0, 1);
+    /// let s = UdpSocket::bind("0.0.0.0:0").unwrap();
+    /// s.set_ttl(2).unwrap();
+    /// s.connect((gateway, NATPMP_PORT)).unwrap();
+    /// let n = Natpmp::from_socket(s, gateway).unwrap();
+    /// let s = n.into_inner();
+    /// ```
+    pub fn from_socket(s: UdpSocket, gateway: Ipv4Addr) -> Result<Natpmp> {
+        s.set_nonblocking(true)
+            .map_err(|_| Error::NATPMP_ERR_FCNTLERROR)?;
+        // any port on rebind
+        let local = s
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?
+            .ip();
+        Ok(Natpmp::with_socket(
+            s,
+            (local, 0).into(),
+            gateway,
+            NatpmpProto::new(),
+        ))
+    }
+
+    /// Consume the object and return its socket.
+    pub fn into_inner(self) -> UdpSocket {
+        self.s
+    }
+
+    fn with_socket(
+        s: UdpSocket,
+        local: SocketAddr,
+        gateway: Ipv4Addr,
+        proto: NatpmpProto,
+    ) -> Natpmp {
+        Natpmp {
+            s,
+            local,
+            #[cfg(all(
+                feature = "bind-device",
+                any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+            ))]
+            device: None,
+            gateway,
+            state: Mutex::new(State {
+                proto,
+                announcements: AnnouncementFilter::new(),
+                announcement_queue: VecDeque::new(),
+                responses: VecDeque::new(),
+                public_address: None,
+            }),
+            blocking: false,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Create a builder to configure the gateway, the socket and the retry schedule.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::builder()
+    ///     .gateway(Ipv4Addr::new(192, 168, 0, 1))
+    ///     .attempts(4)
+    ///     .initial_retry_delay(Duration::from_millis(100))
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> NatpmpBuilder {
+        NatpmpBuilder::new()
+    }
+
+    /// NAT-PMP gateway address.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let gateway = Ipv4Addr::from([192, 168, 0, 1]);
+    /// let n = Natpmp::new_with(gateway)?;
+    /// assert_eq!(n.gateway(), &gateway);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    /// Local address of the socket, e.g. to log the source port of the requests.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.s
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)
+    }
+
+    /// Create a new client with a duplicate of the underlying socket, see
+    /// [`UdpSocket::try_clone`](https://doc.rust-lang.org/std/net/struct.UdpSocket.html#method.try_clone).
+    ///
+    /// The clone starts with a copy of the pending request, the retry schedule and the queued
+    /// announcements, which are not shared afterwards: a response is read by only one of the
+    /// clients and matched against the requests that client sent. The clone starts in the
+    /// blocking mode of the client, see [`set_blocking`](#method.set_blocking).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::thread;
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let sender = Natpmp::new()?;
+    /// let receiver = sender.try_clone()?;
+    /// thread::spawn(move || loop {
+    ///     for response in receiver.read_all_available().unwrap_or_default() {
+    ///         println!("{}", response);
+    ///     }
+    ///     thread::sleep(Duration::from_millis(100));
+    /// });
+    /// sender.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_clone(&self) -> Result<Natpmp> {
+        let s = self
+            .s
+            .try_clone()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        Ok(Natpmp {
+            s,
+            local: self.local,
+            #[cfg(all(
+                feature = "bind-device",
+                any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+            ))]
+            device: self.device.clone(),
+            gateway: self.gateway,
+            state: Mutex::new(self.state().clone()),
+            blocking: self.blocking,
+        })
+    }
+
+    /// Point the client at a new gateway, e.g. after roaming to another network.
+    ///
+    /// The socket is connected to the NAT-PMP port of `gateway`. The pending requests, the
+    /// kept responses, the queued announcements and the epoch of the previous gateway are
+    /// dropped, the retry policy and the blocking mode are kept.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// // network changed
+    /// n.set_gateway(get_default_gateway()?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_gateway(&mut self, gateway: Ipv4Addr) -> Result<()> {
+        let port = self
+            .s
+            .peer_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?
+            .port();
+        self.s
+            .connect(SocketAddrV4::new(gateway, port))
+            .map_err(|_| Error::NATPMP_ERR_CONNECTERR)?;
+        // e.g. port unreachable from the previous gateway
+        let _ = self.s.take_error();
+        self.gateway = gateway;
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let mut proto = NatpmpProto::new();
+        proto.set_retry_policy(*state.proto.retry_policy());
+        *state = State {
+            proto,
+            announcements: AnnouncementFilter::new(),
+            announcement_queue: VecDeque::new(),
+            responses: VecDeque::new(),
+            public_address: None,
+        };
+        Ok(())
+    }
+
+    /// Replace the socket by a new one bound to the same local address, and device with the
+    /// `bind-device` feature, and connected to the same gateway, e.g. after the local interface
+    /// changed its address.
+    ///
+    /// The blocking mode and the TTL of the socket are kept, as well as the pending requests,
+    /// which are retransmitted from the new socket. Sources registered with the old socket,
+    /// e.g. in a `mio` registry, must be registered again. A fixed local port is released by
+    /// closing the old socket first, the client has no usable socket if the new one then
+    /// cannot be bound, until `rebind` succeeds.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    pub fn rebind(&mut self) -> Result<()> {
+        let server = self
+            .s
+            .peer_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        let ttl = self.s.ttl().map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        if self.local.port() != 0 {
+            self.s = bind_socket((Ipv4Addr::UNSPECIFIED, 0).into())?;
+        }
+        let s = self.bind(server)?;
+        s.set_ttl(ttl).map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        self.s = s;
+        Ok(())
+    }
+
+    /// Bind a socket to the local address and device, and connect it to `server`.
+    fn bind(&self, server: SocketAddr) -> Result<UdpSocket> {
+        #[cfg(all(
+            feature = "bind-device",
+            any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+        ))]
+        if let Some(device) = &self.device {
+            return builder::device_socket(device, self.local, Some(server));
+        }
+        connect_socket_from(self.local, server)
+    }
+
+    /// Set the retry policy of the following requests.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no delay
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// // give up after about 2 seconds
+    /// n.set_retry_policy(RetryPolicy {
+    ///     attempts: 3,
+    ///     initial_delay: Duration::from_millis(250),
+    ///     ..RetryPolicy::default()
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> Result<()> {
+        if !policy.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.state().proto.set_retry_policy(policy);
+        Ok(())
+    }
+
+    /// Retry policy of the following requests.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        *self.state().proto.retry_policy()
+    }
+
+    /// Switch the client to blocking mode, so that
+    /// [`read_response_or_retry`](#method.read_response_or_retry) waits for the response and
+    /// retransmits the request itself instead of returning
+    /// [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN).
+    ///
+    /// The client is non-blocking by default. The socket itself stays non-blocking, as it is
+    /// shared with the clones of the client and a mio registration: the blocking calls poll it
+    /// every 10ms. It never fails.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// n.set_blocking(true)?;
+    /// n.send_public_address_request()?;
+    /// // no sleep loop
+    /// let response = n.read_response_or_retry()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_blocking(&mut self, blocking: bool) -> Result<()> {
+        self.blocking = blocking;
+        Ok(())
+    }
+
+    /// Whether the client is in blocking mode, see [`set_blocking`](#method.set_blocking).
+    pub fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    /// Abandon the pending requests, e.g. to give up early. Their responses and the responses
+    /// kept by [`read_response_for`](#method.read_response_for) are discarded.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// n.cancel_pending_request();
+    /// assert_eq!(n.read_response_or_retry(), Err(Error::NATPMP_ERR_NOPENDINGREQ));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cancel_pending_request(&self) {
+        let mut state = self.state();
+        state.proto.cancel_pending_request();
+        state.responses.clear();
+    }
+
+    /// Abandon the request of `token`, returns whether it was waiting for its response.
+    pub fn cancel_request(&self, token: RequestToken) -> bool {
+        let mut state = self.state();
+        state.responses.retain(|(t, _)| *t != token);
+        state.proto.cancel_request(token)
+    }
+
+    /// Most recent request waiting for its response, if any.
+    pub fn pending_request_type(&self) -> Option<Request> {
+        self.state().proto.pending_request_type()
+    }
+
+    /// Number of times the most recent request waiting for its response was sent so far,
+    /// counting the first transmission.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Instant;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// if let (Some(attempt), Some(next)) = (n.attempts_made(), n.next_retry_at()) {
+    ///     println!(
+    ///         "retry {}/{}, next in {:?}",
+    ///         attempt,
+    ///         n.retry_policy().attempts,
+    ///         next.saturating_duration_since(Instant::now())
+    ///     );
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn attempts_made(&self) -> Option<u32> {
+        self.state().proto.attempts_made()
+    }
+
+    /// Time of the next retransmission of the pending requests, or of giving up on them once
+    /// their attempts are exhausted.
+    pub fn next_retry_at(&self) -> Option<Instant> {
+        self.state().proto.next_retry_at()
+    }
+
+    /// Get timeout duration of the currently pending NAT-PMP request.
+    ///
+    /// # Errors:
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// // do something
+    /// let duration = n.get_natpmp_request_timeout()?;
+    /// if duration <= Duration::from_millis(10) {
+    ///     // read response ...
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_natpmp_request_timeout(&self) -> Result<Duration> {
+        self.state().proto.timeout(Instant::now())
+    }
+
+    /// Send public address request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT),
+    ///   a previous request was refused with an ICMP error
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// // do something then read response
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_public_address_request(&self) -> Result<()> {
+        let mut state = self.state();
+        let request = state.proto.send_public_address_request(Instant::now());
+        send_request(&self.s, request)
+    }
+
+    /// Send new port mapping request.
+    ///
+    /// `public_port` is a suggestion, pass [`PublicPort::Any`](enum.PublicPort.html#variant.Any) to let
+    /// the gateway choose it. `lifetime` is either seconds or a `Duration`, see
+    /// [`MappingLifetime`](struct.MappingLifetime.html).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT),
+    ///   a previous request was refused with an ICMP error
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+    /// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, Duration::from_secs(30))?;
+    /// // do something then read response
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_port_mapping_request(
+        &self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let request = state.proto.send_port_mapping_request(
+            protocol,
+            private_port,
+            public_port,
+            lifetime,
+            Instant::now(),
+        );
+        send_request(&self.s, request)
+    }
+
+    /// Send port mapping deletion request.
+    ///
+    /// The response is a mapping response with [`is_deleted`](struct.MappingResponse.html#method.is_deleted).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT),
+    ///   a previous request was refused with an ICMP error
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_port_mapping_delete(Protocol::UDP, 4020)?;
+    /// // do something then read response
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_port_mapping_delete(&self, protocol: Protocol, private_port: u16) -> Result<()> {
+        let mut state = self.state();
+        let request = state
+            .proto
+            .send_port_mapping_delete(protocol, private_port, Instant::now());
+        send_request(&self.s, request)
+    }
+
+    /// Send request to delete all mappings of the client for `protocol`.
+    ///
+    /// The response is a mapping response with [`is_all_deleted`](struct.MappingResponse.html#method.is_all_deleted).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT),
+    ///   a previous request was refused with an ICMP error
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_delete_all_mappings(Protocol::TCP)?;
+    /// // do something then read response
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_delete_all_mappings(&self, protocol: Protocol) -> Result<()> {
+        let mut state = self.state();
+        let request = state
+            .proto
+            .send_delete_all_mappings(protocol, Instant::now());
+        send_request(&self.s, request)
+    }
+
+    /// Send a request and return its token, to read its response with
+    /// [`read_response_for`](#method.read_response_for) while other requests are pending.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT),
+    ///   a previous request was refused with an ICMP error
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// let tcp = n.send_tracked_request(Request::Mapping {
+    ///     protocol: Protocol::TCP,
+    ///     private_port: 4020,
+    ///     public_port: 4020,
+    ///     lifetime: 30,
+    /// })?;
+    /// let udp = n.send_tracked_request(Request::Mapping {
+    ///     protocol: Protocol::UDP,
+    ///     private_port: 4020,
+    ///     public_port: 4020,
+    ///     lifetime: 30,
+    /// })?;
+    /// // later
+    /// let udp_response = n.read_response_for(udp);
+    /// let tcp_response = n.read_response_for(tcp);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_tracked_request(&self, request: Request) -> Result<RequestToken> {
+        let mut state = self.state();
+        let (token, request) = state.proto.send_tracked_request(request, Instant::now());
+        send_request(&self.s, request)?;
+        Ok(token)
+    }
+
+    /// Read the response of the request of `token` if possible, retransmitting the pending
+    /// requests when it is time to.
+    ///
+    /// Responses of the other pending requests received meanwhile are kept for them, and
+    /// returned by the following calls. In blocking mode it waits for the response.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ), the
+    ///   response was already read or the request was replaced
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry)
+    pub fn read_response_for(&self, token: RequestToken) -> Result<Response> {
+        let mut deadline;
+        loop {
+            {
+                let mut state = self.state();
+                if let Some(i) = state.responses.iter().position(|(t, _)| *t == token) {
+                    if let Some((_, result)) = state.responses.remove(i) {
+                        return result;
+                    }
+                }
+                if !state.proto.is_pending(token) {
+                    return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+                }
+                deadline = self.deadline(&state)?;
+            }
+            match self.demux_response(deadline) {
+                Ok(()) => {}
+                Err(Error::NATPMP_TRYAGAIN) => {
+                    let now = Instant::now();
+                    let mut state = self.state();
+                    let state = &mut *state;
+                    while let Some((t, result)) = state.proto.handle_request_timeout(now) {
+                        match result {
+                            Ok(request) => send_request(&self.s, request)?,
+                            Err(e) => state.responses.push_back((t, Err(e))),
+                        }
+                    }
+                    if !self.blocking && state.responses.iter().all(|(t, _)| *t != token) {
+                        return Err(Error::NATPMP_TRYAGAIN);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read a datagram and keep it as the response of the request it answers.
+    fn demux_response(&self, deadline: Option<Instant>) -> Result<()> {
+        let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+        let n = self.recv_until(&mut buf, deadline)?;
+        let now = Instant::now();
+        let mut state = self.state();
+        match state.proto.demux_datagram(&buf[..n], now) {
+            Some(response) => {
+                if let (_, Ok(response)) = &response {
+                    state.cache_public_address(response);
+                }
+                state.responses.push_back(response)
+            }
+            None => state.queue_announcement(&buf[..n], now),
+        }
+        Ok(())
+    }
+
+    /// Read the response of a pending request, received at `now` or when read, waiting for it
+    /// until `deadline` if any.
+    fn read_response(&self, now: Option<Instant>, deadline: Option<Instant>) -> Result<Response> {
+        let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+        loop {
+            let n = self.recv_until(&mut buf, deadline)?;
+            let now = now.unwrap_or_else(Instant::now);
+            let mut state = self.state();
+            match state.proto.handle_datagram(&buf[..n], now) {
+                // not the reply, keep the announcements and read the next datagram
+                Err(Error::NATPMP_TRYAGAIN) => state.queue_announcement(&buf[..n], now),
+                result => {
+                    if let Ok(response) = &result {
+                        state.cache_public_address(response);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Receive a datagram from the gateway, polling the non-blocking socket until `deadline`
+    /// if any. The socket is shared with the clones and a mio registration, its mode and
+    /// timeouts are left alone.
+    fn recv_until(&self, buf: &mut [u8], deadline: Option<Instant>) -> Result<usize> {
+        loop {
+            match recv_datagram(&self.s, self.gateway.into(), buf) {
+                Err(Error::NATPMP_TRYAGAIN) => {
+                    let now = Instant::now();
+                    match deadline {
+                        Some(deadline) if now < deadline => {
+                            std::thread::sleep((deadline - now).min(POLL_INTERVAL))
+                        }
+                        _ => return Err(Error::NATPMP_TRYAGAIN),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Time to wait for a datagram until the next retransmission, in blocking mode.
+    fn deadline(&self, state: &State) -> Result<Option<Instant>> {
+        if !self.blocking {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        Ok(Some(now + state.proto.timeout(now)?))
+    }
+
+    /// Take the oldest unsolicited public address announcement received while reading a
+    /// response.
+    ///
+    /// [`read_response_or_retry`](#method.read_response_or_retry) queues the announcements
+    /// arriving before the reply to a port mapping request and keeps waiting for the reply.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+    /// // later
+    /// let response = n.read_response_or_retry();
+    /// while let Some(announcement) = n.pop_announcement() {
+    ///     println!("public address changed: {}", announcement);
+    /// }
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn new() -> Result<Natpmp> {
-        let gateway = get_default_gateway()?;
-        Natpmp::new_with(gateway)
+    pub fn pop_announcement(&self) -> Option<GatewayResponse> {
+        self.state().announcement_queue.pop_front()
     }
 
-    /// Create a NAT-PMP object with a specified gateway.
+    /// Read and parse every datagram already queued on the socket, without waiting.
+    ///
+    /// Gateways retransmit responses and address change announcements, repeated datagrams are
+    /// only returned once. Datagrams not answering the pending request, such as unsolicited
+    /// announcements, are parsed as is, announcements of an unchanged public address are
+    /// dropped (see [`AnnouncementFilter`](struct.AnnouncementFilter.html)). An empty vector
+    /// is returned if nothing is queued.
     ///
     /// # Errors
-    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
-    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
-    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    /// * [`Error::NATPMP_ERR_WRONGPACKETSOURCE`](enum.Error.html#variant.NATPMP_ERR_WRONGPACKETSOURCE)
+    /// * Any parse or result code error of a datagram, see [`Response::parse`](enum.Response.html#method.parse)
     ///
     /// # Examples
     /// ```
-    /// use std::str::FromStr;
-    /// use std::net::Ipv4Addr;
     /// use natpmp::*;
     ///
-    /// let n = Natpmp::new_with("192.168.0.1".parse().unwrap()).unwrap();
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// for response in n.read_all_available()? {
+    ///     println!("{}", response);
+    /// }
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn new_with(gateway: Ipv4Addr) -> Result<Natpmp> {
-        let s: UdpSocket;
-        if let Ok(udpsock) = UdpSocket::bind("0.0.0.0:0") {
-            s = udpsock;
-        } else {
-            return Err(Error::NATPMP_ERR_SOCKETERROR);
-        }
-        if s.set_nonblocking(true).is_err() {
-            return Err(Error::NATPMP_ERR_FCNTLERROR);
-        }
-        let gateway_sockaddr = SocketAddrV4::new(gateway, NATPMP_PORT);
-        if s.connect(gateway_sockaddr).is_err() {
-            return Err(Error::NATPMP_ERR_CONNECTERR);
+    pub fn read_all_available(&self) -> Result<Vec<Response>> {
+        let mut responses = Vec::new();
+        let mut seen: Vec<(usize, [u8; NATPMP_MAX_RESPONSE_LEN])> = Vec::new();
+        loop {
+            let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+            let n = match recv_datagram(&self.s, self.gateway.into(), &mut buf) {
+                Ok(n) => n,
+                Err(Error::NATPMP_TRYAGAIN) => return Ok(responses),
+                Err(e) => return Err(e),
+            };
+            if seen.contains(&(n, buf)) {
+                continue;
+            }
+            seen.push((n, buf));
+            let now = Instant::now();
+            let mut state = self.state();
+            let response = match state.proto.handle_datagram(&buf[..n], now) {
+                Err(Error::NATPMP_TRYAGAIN) if state.proto.is_late_response(&buf[..n]) => continue,
+                Err(Error::NATPMP_TRYAGAIN) | Err(Error::NATPMP_ERR_NOPENDINGREQ) => {
+                    match Response::parse(&buf[..n])? {
+                        Response::Gateway(gr) if !state.announcements.is_new(&gr, now) => continue,
+                        response => {
+                            if let Response::Gateway(_) = response {
+                                state.public_address = None;
+                            }
+                            response
+                        }
+                    }
+                }
+                result => {
+                    let response = result?;
+                    if let Response::Gateway(gr) = &response {
+                        state.announcements.is_new(gr, now);
+                    }
+                    state.cache_public_address(&response);
+                    response
+                }
+            };
+            responses.push(response);
         }
-        let n = Natpmp {
-            s,
-            gateway,
-            has_pending_request: false,
-            pending_request: [0u8; 12],
-            pending_request_len: 0,
-            try_number: 0,
-            retry_time: Instant::now(),
-        };
-        Ok(n)
     }
 
-    /// NAT-PMP gateway address.
+    /// Public address of the last public address response, without asking the gateway.
+    ///
+    /// The cached address is forgotten when a response shows that the gateway rebooted or when
+    /// the gateway announces an address change, the address must then be requested again.
     ///
     /// # Examples
     /// ```
-    /// use std::net::Ipv4Addr;
     /// use natpmp::*;
     ///
     /// # fn main() -> Result<()> {
-    /// let gateway = Ipv4Addr::from([192, 168, 0, 1]);
-    /// let n = Natpmp::new_with(gateway)?;
-    /// assert_eq!(n.gateway(), &gateway);
+    /// let n = Natpmp::new()?;
+    /// let public_address = match n.cached_public_address() {
+    ///     Some(gr) => *gr.public_address(),
+    ///     None => {
+    ///         n.send_public_address_request()?;
+    ///         match n.wait_response()? {
+    ///             Response::Gateway(gr) => *gr.public_address(),
+    ///             _ => panic!("Not a gateway response"),
+    ///         }
+    ///     }
+    /// };
+    /// assert_eq!(n.cached_public_address().map(|gr| *gr.public_address()), Some(public_address));
     /// # Ok(())
     /// # }
     /// ```
-    pub fn gateway(&self) -> &Ipv4Addr {
-        &self.gateway
-    }
-
-    fn send_pending_request(&self) -> Result<()> {
-        if let Ok(n) = self
-            .s
-            .send(&self.pending_request[0..self.pending_request_len])
-        {
-            if n == self.pending_request_len {
-                return Ok(());
-            }
-        }
-        Err(Error::NATPMP_ERR_SENDERR)
-    }
-
-    fn send_natpmp_request(&mut self) -> Result<()> {
-        self.has_pending_request = true;
-        self.try_number = 1;
-        let result = self.send_pending_request();
-        self.retry_time = Instant::now();
-        self.retry_time = self.retry_time.add(Duration::from_millis(250));
-        result
+    pub fn cached_public_address(&self) -> Option<GatewayResponse> {
+        self.state().public_address
     }
 
-    /// Get timeout duration of the currently pending NAT-PMP request.
-    ///
-    /// # Errors:
-    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// Whether the epoch of `response` indicates that the gateway rebooted since the previous
+    /// response, in which case all port mappings must be recreated (rfc6886 section 3.6).
     ///
     /// # Examples
     /// ```
-    /// use std::time::Duration;
     /// use natpmp::*;
     ///
     /// # fn main() -> Result<()> {
-    /// let mut n = Natpmp::new()?;
+    /// let n = Natpmp::new()?;
     /// n.send_public_address_request()?;
-    /// // do something
-    /// let duration = n.get_natpmp_request_timeout()?;
-    /// if duration <= Duration::from_millis(10) {
-    ///     // read response ...
+    /// // later
+    /// if let Ok(response) = n.read_response_or_retry() {
+    ///     if n.epoch_indicates_reboot(&response) {
+    ///         // recreate port mappings
+    ///     }
     /// }
     /// # Ok(())
     /// # }
     /// ```
-    pub fn get_natpmp_request_timeout(&self) -> Result<Duration> {
-        if !self.has_pending_request {
-            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
-        }
-        let now = Instant::now();
-        if now > self.retry_time {
-            return Ok(Duration::from_millis(0));
-        }
-        let duration = self.retry_time - now;
-        Ok(duration)
+    pub fn epoch_indicates_reboot(&self, response: &Response) -> bool {
+        self.state().proto.epoch_indicates_reboot(response)
     }
 
-    /// Send public address request.
+    /// Wait for the response of a pending request until `deadline`, retransmitting the
+    /// requests when it is time to.
+    ///
+    /// It waits whether the client is in blocking mode or not.
     ///
     /// # Errors
-    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN), no response
+    ///   before `deadline`
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry)
     ///
     /// # Examples
     /// ```
+    /// use std::time::{Duration, Instant};
     /// use natpmp::*;
     ///
     /// # fn main() -> Result<()> {
-    /// let mut n = Natpmp::new()?;
+    /// let n = Natpmp::new()?;
     /// n.send_public_address_request()?;
-    /// // do something then read response
+    /// let response = n.read_response_until(Instant::now() + Duration::from_secs(2))?;
     /// # Ok(())
     /// # }
     /// ```
-    pub fn send_public_address_request(&mut self) -> Result<()> {
-        self.pending_request[0] = 0;
-        self.pending_request[1] = 0;
-        self.pending_request_len = 2;
-        self.send_natpmp_request()
+    pub fn read_response_until(&self, deadline: Instant) -> Result<Response> {
+        self.wait(Some(deadline))
     }
 
-    /// Send new port mapping request.
+    /// Wait for the response of a pending request, retransmitting the requests until the
+    /// retry policy is exhausted.
+    ///
+    /// With the default [`RetryPolicy`](struct.RetryPolicy.html) it follows rfc6886 section
+    /// 3.1: 9 attempts, 250ms apart then doubling, that is about 2 minutes without gateway.
+    /// Cap it with [`set_retry_policy`](#method.set_retry_policy) before sending the request.
     ///
     /// # Errors
-    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT),
+    ///   no response after the last attempt
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry), except
+    ///   [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
     ///
     /// # Examples
     /// ```
     /// use natpmp::*;
     ///
     /// # fn main() -> Result<()> {
-    /// let mut n = Natpmp::new()?;
-    /// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
-    /// // do something then read response
+    /// let n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// match n.wait_response() {
+    ///     Ok(response) => println!("{}", response),
+    ///     Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT) => println!("no NAT-PMP gateway"),
+    ///     Err(e) => return Err(e),
+    /// }
     /// # Ok(())
     /// # }
     /// ```
-    pub fn send_port_mapping_request(
-        &mut self,
-        protocol: Protocol,
-        private_port: u16,
-        public_port: u16,
-        lifetime: u32,
-    ) -> Result<()> {
-        self.pending_request[0] = 0;
-        self.pending_request[1] = match protocol {
-            Protocol::UDP => 1,
-            _ => 2,
-        };
-        self.pending_request[2] = 0; // reserved
-        self.pending_request[3] = 0; // reserved
-                                     // private port
-        self.pending_request[4] = (private_port >> 8 & 0xff) as u8;
-        self.pending_request[5] = (private_port & 0xff) as u8;
-        // public port
-        self.pending_request[6] = (public_port >> 8 & 0xff) as u8;
-        self.pending_request[7] = (public_port & 0xff) as u8;
-        // lifetime
-        self.pending_request[8] = ((lifetime >> 24) & 0xff) as u8;
-        self.pending_request[9] = ((lifetime >> 16) & 0xff) as u8;
-        self.pending_request[10] = ((lifetime >> 8) & 0xff) as u8;
-        self.pending_request[11] = (lifetime & 0xff) as u8;
-        self.pending_request_len = 12;
-        self.send_natpmp_request()
-    }
-
-    fn read_response(&self) -> Result<Response> {
-        let mut buf = [0u8; 16];
-        match self.s.recv_from(&mut buf) {
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => return Err(Error::NATPMP_TRYAGAIN),
-                io::ErrorKind::ConnectionRefused => return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT),
-                _ => {
-                    return Err(Error::NATPMP_ERR_RECVFROM);
+    pub fn wait_response(&self) -> Result<Response> {
+        self.wait(None)
+    }
+
+    /// Wait for a response until `deadline` if any.
+    fn wait(&self, deadline: Option<Instant>) -> Result<Response> {
+        let mut wait_until;
+        loop {
+            {
+                let mut state = self.state();
+                // kept by read_response_for
+                if let Some((_, result)) = state.responses.pop_front() {
+                    return result;
                 }
-            },
-            Ok((_, sockaddr)) => {
-                // check gateway address
-                if let SocketAddr::V4(s) = sockaddr {
-                    if s.ip() != &self.gateway {
-                        return Err(Error::NATPMP_ERR_WRONGPACKETSOURCE);
+                let now = Instant::now();
+                let mut timeout = state.proto.timeout(now)?;
+                if let Some(deadline) = deadline {
+                    if now >= deadline {
+                        return Err(Error::NATPMP_TRYAGAIN);
                     }
+                    timeout = timeout.min(deadline - now);
                 }
-                // version
-                if buf[0] != 0 {
-                    return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
-                }
-                // opcode
-                if buf[1] < 128 || buf[1] > 130 {
-                    return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE);
+                wait_until = now + timeout;
+            }
+            match self.read_response(None, Some(wait_until)) {
+                Err(Error::NATPMP_TRYAGAIN) => {
+                    let now = Instant::now();
+                    let mut state = self.state();
+                    while let Some(request) = state.proto.handle_timeout(now)? {
+                        send_request(&self.s, request)?;
+                    }
                 }
-                // result code
-                let resultcode = u16::from_be_bytes([buf[2], buf[3]]);
-                if resultcode != 0 {
-                    return Err(match resultcode {
-                        1 => Error::NATPMP_ERR_UNSUPPORTEDVERSION,
-                        2 => Error::NATPMP_ERR_NOTAUTHORIZED,
-                        3 => Error::NATPMP_ERR_NETWORKFAILURE,
-                        4 => Error::NATPMP_ERR_OUTOFRESOURCES,
-                        5 => Error::NATPMP_ERR_UNSUPPORTEDOPCODE,
-                        _ => Error::NATPMP_ERR_UNDEFINEDERROR,
-                    });
+                result => return result,
+            }
+        }
+    }
+
+    /// Read the response of a pending request if one was received, or retransmit the
+    /// requests due at `now`, without waiting.
+    ///
+    /// Unlike [`read_response_or_retry`](#method.read_response_or_retry), the time is given
+    /// by the caller and the next deadline is returned, so the client can be driven by a
+    /// custom reactor or by tests with a simulated clock. The socket should be non-blocking.
+    ///
+    /// # Errors
+    /// * See [`read_response_or_retry`](#method.read_response_or_retry), except
+    ///   [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::thread;
+    /// use std::time::Instant;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// let response = loop {
+    ///     let now = Instant::now();
+    ///     match n.poll_response(now)? {
+    ///         ResponsePoll::Ready(response) => break response,
+    ///         // or wait for the socket to be readable
+    ///         ResponsePoll::Pending { deadline } => {
+    ///             thread::sleep(deadline.saturating_duration_since(now))
+    ///         }
+    ///     }
+    /// };
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn poll_response(&self, now: Instant) -> Result<ResponsePoll> {
+        {
+            let mut state = self.state();
+            // kept by read_response_for
+            if let Some((_, result)) = state.responses.pop_front() {
+                return result.map(ResponsePoll::Ready);
+            }
+            if !state.proto.has_pending_request() {
+                return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+            }
+        }
+        match self.read_response(Some(now), None) {
+            Ok(response) => Ok(ResponsePoll::Ready(response)),
+            Err(Error::NATPMP_TRYAGAIN) => {
+                let mut state = self.state();
+                while let Some(request) = state.proto.handle_timeout(now)? {
+                    send_request(&self.s, request)?;
                 }
-                // epoch
-                let epoch = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
-                // result
-                let rsp_type = buf[1] & 0x7f;
-                return Ok(match rsp_type {
-                    0 => Response::Gateway(GatewayResponse {
-                        epoch,
-                        public_address: Ipv4Addr::from(u32::from_be_bytes([
-                            buf[8], buf[9], buf[10], buf[11],
-                        ])),
-                    }),
-                    _ => {
-                        let private_port = u16::from_be_bytes([buf[8], buf[9]]);
-                        let public_port = u16::from_be_bytes([buf[10], buf[11]]);
-                        let lifetime = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
-                        let lifetime = Duration::from_secs(lifetime.into());
-                        let m = MappingResponse {
-                            epoch,
-                            private_port,
-                            public_port,
-                            lifetime,
-                        };
-                        if rsp_type == 1 {
-                            Response::UDP(m)
-                        } else {
-                            Response::TCP(m)
-                        }
-                    }
-                });
+                let deadline = now + state.proto.timeout(now)?;
+                Ok(ResponsePoll::Pending { deadline })
             }
+            Err(e) => Err(e),
         }
     }
 
     /// Read NAT-PMP response if possible
     ///
+    /// Several requests may be pending, e.g. the TCP and UDP mappings of a port, the response
+    /// of any of them is returned and tells which request it answers. Pending requests are
+    /// retransmitted independently.
+    ///
+    /// In blocking mode (see [`set_blocking`](#method.set_blocking)) it waits for the response,
+    /// retransmitting the request, until the retry policy is exhausted.
+    ///
+    /// Unsolicited public address announcements received meanwhile are queued, see
+    /// [`pop_announcement`](#method.pop_announcement).
+    ///
     /// # Errors
     /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
     /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
     /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
     /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
     /// * [`Error::NATPMP_ERR_WRONGPACKETSOURCE`](enum.Error.html#variant.NATPMP_ERR_WRONGPACKETSOURCE)
+    /// * [`Error::NATPMP_ERR_TRUNCATEDPACKET`](enum.Error.html#variant.NATPMP_ERR_TRUNCATEDPACKET)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDOPCODE)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
@@ -455,7 +2007,7 @@ impl Natpmp {
     /// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
     /// * [`Error::NATPMP_ERR_OUTOFRESOURCES`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
-    /// * [`Error::NATPMP_ERR_UNDEFINEDERROR`](enum.Error.html#variant.NATPMP_ERR_UNDEFINEDERROR)
+    /// * [`Error::NATPMP_ERR_UNKNOWNRESULTCODE`](enum.Error.html#variant.NATPMP_ERR_UNKNOWNRESULTCODE)
     ///
     /// # Examples
     /// ```
@@ -464,7 +2016,7 @@ impl Natpmp {
     /// use natpmp::*;
     ///
     /// # fn main() -> Result<()> {
-    /// let mut n = Natpmp::new()?;
+    /// let n = Natpmp::new()?;
     /// n.send_public_address_request()?;
     /// thread::sleep(Duration::from_millis(250));
     /// let response = n.read_response_or_retry()?;
@@ -472,35 +2024,119 @@ impl Natpmp {
     /// # }
     ///
     /// ```
-    pub fn read_response_or_retry(&mut self) -> Result<Response> {
-        if !self.has_pending_request {
-            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
-        }
-        let result = self.read_response();
-        if let Err(e) = result {
-            match e {
-                Error::NATPMP_TRYAGAIN => {
-                    let now = Instant::now();
-                    // time to retry or not
-                    if now >= self.retry_time {
-                        if self.try_number >= NATPMP_MAX_ATTEMPS {
-                            return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT);
-                        }
-                        // double dealy
-                        let delay = NATPMP_MIN_WAIT * (1 << self.try_number); // ms
-                        self.retry_time = self.retry_time.add(Duration::from_millis(delay)); // next time
-                        self.try_number += 1;
-                        self.send_pending_request()?;
-                    }
+    pub fn read_response_or_retry(&self) -> Result<Response> {
+        let mut deadline;
+        loop {
+            {
+                let mut state = self.state();
+                // kept by read_response_for
+                if let Some((_, result)) = state.responses.pop_front() {
+                    return result;
+                }
+                if !state.proto.has_pending_request() {
+                    return Err(Error::NATPMP_ERR_NOPENDINGREQ);
                 }
-                _ => return Err(e),
+                deadline = self.deadline(&state)?;
             }
+            let result = self.read_response(None, deadline);
+            if let Err(Error::NATPMP_TRYAGAIN) = result {
+                let now = Instant::now();
+                let mut state = self.state();
+                while let Some(request) = state.proto.handle_timeout(now)? {
+                    send_request(&self.s, request)?;
+                }
+                if self.blocking {
+                    continue;
+                }
+            }
+            return result;
         }
-        result
     }
 }
 
-#[cfg(test)]
+#[cfg(all(feature = "std", unix))]
+impl std::os::fd::AsRawFd for Natpmp {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.s.as_raw_fd()
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+impl std::os::fd::AsFd for Natpmp {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.s.as_fd()
+    }
+}
+
+/// Readiness of the socket in a mio `Poll` loop, unix only.
+///
+/// Events are edge-triggered: once readable, read until
+/// [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN). Keep the socket in
+/// non-blocking mode and wait at most [`get_natpmp_request_timeout`](struct.Natpmp.html#method.get_natpmp_request_timeout)
+/// to retransmit in time.
+///
+/// # Examples
+/// ```no_run
+/// use mio::{Events, Interest, Poll, Token};
+/// use natpmp::*;
+///
+/// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+/// let mut poll = Poll::new()?;
+/// let mut events = Events::with_capacity(8);
+/// let mut n = Natpmp::new()?;
+/// poll.registry()
+///     .register(&mut n, Token(0), Interest::READABLE)?;
+/// n.send_public_address_request()?;
+/// let response = loop {
+///     poll.poll(&mut events, Some(n.get_natpmp_request_timeout()?))?;
+///     match n.read_response_or_retry() {
+///         Err(Error::NATPMP_TRYAGAIN) => continue,
+///         r => break r?,
+///     }
+/// };
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(all(feature = "mio", unix))]
+impl mio::event::Source for Natpmp {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.s.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.s.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.s.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(all(feature = "std", windows))]
+impl std::os::windows::io::AsRawSocket for Natpmp {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.s.as_raw_socket()
+    }
+}
+
+#[cfg(all(feature = "std", windows))]
+impl std::os::windows::io::AsSocket for Natpmp {
+    fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+        self.s.as_socket()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use std::thread;
     use std::time::Duration;
@@ -523,7 +2159,7 @@ mod tests {
 
     #[test]
     fn test_get_public_address() -> Result<()> {
-        let mut n = Natpmp::new()?;
+        let n = Natpmp::new()?;
         n.send_public_address_request()?;
         thread::sleep(Duration::from_millis(250));
         let r = n.read_response_or_retry()?;
@@ -536,7 +2172,7 @@ mod tests {
 
     #[test]
     fn test_tcp_mapping() -> Result<()> {
-        let mut n = Natpmp::new()?;
+        let n = Natpmp::new()?;
         n.send_port_mapping_request(Protocol::TCP, 14020, 14020, 10)?;
         thread::sleep(Duration::from_millis(250));
         let r = n.read_response_or_retry()?;
@@ -552,7 +2188,7 @@ mod tests {
 
     #[test]
     fn test_udp_mapping() -> Result<()> {
-        let mut n = Natpmp::new()?;
+        let n = Natpmp::new()?;
         n.send_port_mapping_request(Protocol::UDP, 14020, 14020, 10)?;
         thread::sleep(Duration::from_millis(250));
         let r = n.read_response_or_retry()?;
@@ -568,7 +2204,7 @@ mod tests {
 
     #[test]
     fn test_error() -> Result<()> {
-        let mut n: Natpmp = Natpmp::new()?;
+        let n: Natpmp = Natpmp::new()?;
         n.send_port_mapping_request(Protocol::UDP, 14020, 14020, 30)?;
         thread::sleep(Duration::from_millis(250));
         n.read_response_or_retry()?;
@@ -584,4 +2220,425 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_blocking() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut n =
+            Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        n.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            initial_delay: Duration::from_millis(20),
+            ..RetryPolicy::default()
+        })?;
+        n.set_blocking(true)?;
+        assert!(n.is_blocking());
+
+        // only the retransmission is answered
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 2];
+            server.recv_from(&mut buf).unwrap();
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+            server.send_to(&reply, client).unwrap();
+            server
+        });
+        n.send_public_address_request()?;
+        match n.read_response_or_retry()? {
+            Response::Gateway(gr) => {
+                assert_eq!(*gr.public_address(), Ipv4Addr::new(203, 0, 113, 1))
+            }
+            _ => panic!("Not a gateway response"),
+        }
+        assert!(n.read_all_available()?.is_empty());
+
+        // no answer at all
+        let _server = handle.join().unwrap();
+        n.send_public_address_request()?;
+        assert_eq!(
+            n.read_response_or_retry().err(),
+            Some(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_clone() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sender =
+            Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        sender.set_blocking(true)?;
+        sender.send_public_address_request()?;
+        let receiver = sender.try_clone()?;
+        assert!(receiver.is_blocking());
+        assert_eq!(receiver.gateway(), sender.gateway());
+
+        let mut buf = [0u8; 2];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+        server.send_to(&reply, client).unwrap();
+        assert!(matches!(
+            receiver.read_response_or_retry()?,
+            Response::Gateway(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_socket() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        s.set_ttl(3).unwrap();
+        s.connect(server.local_addr().unwrap()).unwrap();
+        let local = s.local_addr().unwrap();
+        #[cfg(unix)]
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&s);
+
+        let n = Natpmp::from_socket(s, Ipv4Addr::LOCALHOST)?;
+        assert_eq!(n.local_addr()?, local);
+        #[cfg(unix)]
+        assert_eq!(std::os::fd::AsRawFd::as_raw_fd(&n), fd);
+        n.send_public_address_request()?;
+        let mut buf = [0u8; 2];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(client, local);
+        assert_eq!(
+            n.read_response_or_retry().err(),
+            Some(Error::NATPMP_TRYAGAIN)
+        );
+
+        let s = n.into_inner();
+        assert_eq!(s.ttl().unwrap(), 3);
+        assert_eq!(s.local_addr().unwrap(), local);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_response_for() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut n =
+            Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        n.set_blocking(true)?;
+        let mapping = |protocol| Request::Mapping {
+            protocol,
+            private_port: 4020,
+            public_port: 4020,
+            lifetime: 30,
+        };
+        let udp = n.send_tracked_request(mapping(Protocol::UDP))?;
+        let tcp = n.send_tracked_request(mapping(Protocol::TCP))?;
+        assert_ne!(udp, tcp);
+
+        let mut buf = [0u8; 12];
+        for _ in 0..2 {
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            let mut reply = [0, buf[1] | 0x80, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 30];
+            reply[8..12].copy_from_slice(&buf[4..8]);
+            server.send_to(&reply, client).unwrap();
+        }
+        assert!(matches!(n.read_response_for(tcp)?, Response::TCP(_)));
+        // kept while reading the tcp response
+        assert!(matches!(n.read_response_for(udp)?, Response::UDP(_)));
+        assert_eq!(
+            n.read_response_for(udp).err(),
+            Some(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared() -> Result<()> {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Natpmp>();
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let n = Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        let n = std::sync::Arc::new(n);
+        let handles: Vec<_> = (4020..4022)
+            .map(|port| {
+                let n = n.clone();
+                thread::spawn(move || n.send_port_mapping_request(Protocol::UDP, port, port, 30))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(n.state().proto.pending_requests().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_response() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let n = Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        n.send_public_address_request()?;
+        let now = Instant::now();
+        let deadline = match n.poll_response(now)? {
+            ResponsePoll::Pending { deadline } => deadline,
+            r => panic!("unexpected {:?}", r),
+        };
+        assert!(deadline <= now + Duration::from_millis(250));
+
+        // retransmitted at the deadline, without waiting for it
+        let later = deadline;
+        assert_eq!(
+            n.poll_response(later)?,
+            ResponsePoll::Pending {
+                deadline: later + Duration::from_millis(500)
+            }
+        );
+        let mut buf = [0u8; 2];
+        server.recv_from(&mut buf).unwrap();
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+        server.send_to(&reply, client).unwrap();
+        loop {
+            match n.poll_response(later)? {
+                ResponsePoll::Ready(response) => {
+                    assert!(matches!(response, Response::Gateway(_)));
+                    break;
+                }
+                ResponsePoll::Pending { .. } => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_response_until() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let n = Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        n.send_public_address_request()?;
+        let start = Instant::now();
+        assert_eq!(
+            n.read_response_until(start + Duration::from_millis(50)),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(!n.is_blocking());
+
+        // a clone is not blocked by the wait on the shared socket
+        let clone = n.try_clone()?;
+        let waiting = thread::spawn(move || {
+            clone.read_response_until(Instant::now() + Duration::from_millis(200))
+        });
+        thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        assert_eq!(n.read_all_available(), Ok(Vec::new()));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(waiting.join().unwrap(), Err(Error::NATPMP_TRYAGAIN));
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 2];
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+            server.send_to(&reply, client).unwrap();
+        });
+        n.send_public_address_request()?;
+        let response = n.read_response_until(Instant::now() + Duration::from_secs(5))?;
+        assert!(matches!(response, Response::Gateway(_)));
+        handle.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_response() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut n =
+            Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        n.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        })?;
+        n.send_public_address_request()?;
+        assert_eq!(n.wait_response(), Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT));
+        let mut buf = [0u8; 2];
+        for _ in 0..3 {
+            server.recv_from(&mut buf).unwrap();
+        }
+        assert_eq!(n.wait_response(), Err(Error::NATPMP_ERR_NOPENDINGREQ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_progress() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut n =
+            Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        n.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        })?;
+        assert_eq!(n.attempts_made(), None);
+        assert_eq!(n.next_retry_at(), None);
+        assert_eq!(n.pending_request_type(), None);
+
+        n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+        assert_eq!(n.attempts_made(), Some(1));
+        assert_eq!(
+            n.pending_request_type(),
+            Some(Request::Mapping {
+                protocol: Protocol::UDP,
+                private_port: 4020,
+                public_port: 4020,
+                lifetime: 30,
+            })
+        );
+        let next = n.next_retry_at().unwrap();
+        while Instant::now() < next {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(n.read_response_or_retry(), Err(Error::NATPMP_TRYAGAIN));
+        assert_eq!(n.attempts_made(), Some(2));
+        assert!(n.next_retry_at().unwrap() > next);
+        assert_eq!(n.retry_policy().attempts, 3);
+
+        n.cancel_pending_request();
+        assert_eq!(n.attempts_made(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_gateway() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut n = Natpmp::new_with_port(Ipv4Addr::new(127, 0, 0, 2), port)?;
+        n.set_retry_policy(RetryPolicy {
+            attempts: 2,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        })?;
+        n.send_public_address_request()?;
+        n.set_gateway(Ipv4Addr::LOCALHOST)?;
+        assert_eq!(n.gateway(), &Ipv4Addr::LOCALHOST);
+        assert!(n.pending_request_type().is_none());
+        assert_eq!(n.retry_policy().attempts, 2);
+
+        n.set_blocking(true)?;
+        n.rebind()?;
+        assert!(n.is_blocking());
+        n.set_blocking(false)?;
+
+        n.send_public_address_request()?;
+        let old = n.local_addr()?;
+        n.rebind()?;
+        assert_ne!(n.local_addr()?, old);
+        assert_eq!(n.pending_request_type(), Some(Request::PublicAddress));
+
+        // the pending request is retransmitted from the new socket
+        let mut buf = [0u8; 2];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(client, old);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(n.read_response_or_retry(), Err(Error::NATPMP_TRYAGAIN));
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+        server.send_to(&reply, client).unwrap();
+        n.set_blocking(true)?;
+        assert!(matches!(n.read_response_or_retry()?, Response::Gateway(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebind_bound() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gateway = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let mut n = Natpmp::new_bound(local, gateway)?;
+        n.rebind()?;
+        assert_eq!(n.local_addr()?, SocketAddr::V4(local));
+        n.send_public_address_request()?;
+        let mut buf = [0u8; 2];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(client, SocketAddr::V4(local));
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_port_unreachable() -> Result<()> {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let n = Natpmp::new_with_port(Ipv4Addr::LOCALHOST, port)?;
+        n.send_public_address_request()?;
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            n.send_public_address_request(),
+            Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_public_address() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut n =
+            Natpmp::new_with_port(Ipv4Addr::LOCALHOST, server.local_addr().unwrap().port())?;
+        n.set_blocking(true)?;
+        assert_eq!(n.cached_public_address(), None);
+
+        let mut buf = [0u8; 12];
+        n.send_public_address_request()?;
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        server
+            .send_to(&[0, 128, 0, 0, 0, 0, 0x10, 0, 203, 0, 113, 1], client)
+            .unwrap();
+        n.read_response_or_retry()?;
+        assert_eq!(
+            n.cached_public_address().map(|gr| *gr.public_address()),
+            Some(Ipv4Addr::new(203, 0, 113, 1))
+        );
+
+        // address change announced while waiting for a mapping
+        n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+        server.recv_from(&mut buf).unwrap();
+        server
+            .send_to(&[0, 128, 0, 0, 0, 0, 0x10, 1, 203, 0, 113, 2], client)
+            .unwrap();
+        server
+            .send_to(
+                &[
+                    0, 129, 0, 0, 0, 0, 0x10, 1, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+                ],
+                client,
+            )
+            .unwrap();
+        n.read_response_or_retry()?;
+        assert_eq!(n.cached_public_address(), None);
+
+        n.send_public_address_request()?;
+        server.recv_from(&mut buf).unwrap();
+        server
+            .send_to(&[0, 128, 0, 0, 0, 0, 0x10, 1, 203, 0, 113, 2], client)
+            .unwrap();
+        n.read_response_or_retry()?;
+        assert!(n.cached_public_address().is_some());
+
+        // rebooted
+        n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)?;
+        server.recv_from(&mut buf).unwrap();
+        server
+            .send_to(
+                &[
+                    0, 129, 0, 0, 0, 0, 0, 1, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+                ],
+                client,
+            )
+            .unwrap();
+        n.read_response_or_retry()?;
+        assert_eq!(n.cached_public_address(), None);
+        Ok(())
+    }
 }
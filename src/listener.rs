@@ -0,0 +1,211 @@
+//! Listener of the multicast ANNOUNCE responses of a restarting PCP server.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Instant;
+
+use crate::pcp::PCP_MAX_PACKET_LEN;
+use crate::{EpochTracker, Error, PcpAnnounceResponse, PcpResponse, Result, PCP_ANNOUNCE_PORT};
+
+/// IPv4 all hosts group the ANNOUNCE responses are multicast to.
+pub const PCP_ANNOUNCE_GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+/// IPv6 link-local all nodes group the ANNOUNCE responses are multicast to.
+pub const PCP_ANNOUNCE_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// Event of a PCP server seen by a [`PcpListener`](struct.PcpListener.html).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PcpServerEvent {
+    /// The server announced itself with an epoch consistent with the previous one, e.g. a
+    /// retransmitted announcement.
+    Announced(PcpAnnounceResponse),
+    /// The server restarted or lost its state, all mappings must be re-created.
+    Restarted(PcpAnnounceResponse),
+}
+
+impl PcpServerEvent {
+    /// Announcement behind the event.
+    pub fn announce(&self) -> &PcpAnnounceResponse {
+        match self {
+            PcpServerEvent::Announced(announce) | PcpServerEvent::Restarted(announce) => announce,
+        }
+    }
+}
+
+/// Listener of the ANNOUNCE responses a PCP server multicasts to port
+/// [`PCP_ANNOUNCE_PORT`](constant.PCP_ANNOUNCE_PORT.html) after a restart (rfc6887 section 14.1.3).
+///
+/// The announced epochs are checked with the PCP rule (see [`EpochTracker::new_pcp`](struct.EpochTracker.html#method.new_pcp)),
+/// so a mapping manager learns about a restart right away instead of on the next renewal.
+/// Epochs of the unicast responses should be recorded too with
+/// [`record_epoch`](#method.record_epoch), otherwise the first announcement can not be told
+/// apart from a restart and is reported as announced.
+///
+/// # Examples
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut listener = PcpListener::new(get_default_gateway()?)?;
+/// loop {
+///     match listener.read_event() {
+///         Ok(PcpServerEvent::Restarted(_)) => println!("re-create the mappings"),
+///         Ok(PcpServerEvent::Announced(_)) => {}
+///         Err(Error::NATPMP_TRYAGAIN) => thread::sleep(Duration::from_millis(100)),
+///         Err(e) => return Err(e),
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PcpListener {
+    s: UdpSocket,
+    gateway: IpAddr,
+    epoch: EpochTracker,
+}
+
+impl PcpListener {
+    /// Create a listener of the announcements of the PCP server `gateway`.
+    ///
+    /// The socket joins the multicast group of the gateway address family on the default
+    /// interface.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    pub fn new(gateway: impl Into<IpAddr>) -> Result<PcpListener> {
+        let gateway = gateway.into();
+        let s = match gateway {
+            IpAddr::V4(_) => {
+                let s = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PCP_ANNOUNCE_PORT))
+                    .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+                s.join_multicast_v4(&PCP_ANNOUNCE_GROUP_V4, &Ipv4Addr::UNSPECIFIED)
+                    .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+                s
+            }
+            IpAddr::V6(_) => {
+                let s = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, PCP_ANNOUNCE_PORT))
+                    .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+                s.join_multicast_v6(&PCP_ANNOUNCE_GROUP_V6, 0)
+                    .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+                s
+            }
+        };
+        s.set_nonblocking(true)
+            .map_err(|_| Error::NATPMP_ERR_FCNTLERROR)?;
+        Ok(PcpListener {
+            s,
+            gateway,
+            epoch: EpochTracker::new_pcp(),
+        })
+    }
+
+    /// PCP server address.
+    pub fn gateway(&self) -> &IpAddr {
+        &self.gateway
+    }
+
+    /// Epochs announced by the server.
+    pub fn epoch_tracker(&self) -> &EpochTracker {
+        &self.epoch
+    }
+
+    /// Record the epoch of a response received from the server by other means, returns
+    /// whether it indicates a restart.
+    pub fn record_epoch(&mut self, epoch: u32, received_at: Instant) -> bool {
+        self.epoch.update(epoch, received_at)
+    }
+
+    /// Read the next server event if possible.
+    ///
+    /// Datagrams from other sources and invalid datagrams are discarded.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    pub fn read_event(&mut self) -> Result<PcpServerEvent> {
+        let mut buf = [0u8; PCP_MAX_PACKET_LEN];
+        loop {
+            let (n, source) = match self.s.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Err(Error::NATPMP_TRYAGAIN)
+                }
+                Err(_) => return Err(Error::NATPMP_ERR_RECVFROM),
+            };
+            if let Some(event) = self.handle_datagram(&buf[..n], source, Instant::now()) {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn handle_datagram(
+        &mut self,
+        buf: &[u8],
+        source: SocketAddr,
+        now: Instant,
+    ) -> Option<PcpServerEvent> {
+        if source.ip() != self.gateway {
+            return None;
+        }
+        match PcpResponse::parse(buf) {
+            Ok(PcpResponse::Announce(mut announce)) => {
+                announce.received_at = now;
+                if self.epoch.update(announce.epoch(), now) {
+                    Some(PcpServerEvent::Restarted(announce))
+                } else {
+                    Some(PcpServerEvent::Announced(announce))
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn announce(epoch: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; 24];
+        buf[0] = 2;
+        buf[1] = 0x80;
+        buf[8..12].copy_from_slice(&epoch.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_handle_datagram() {
+        let now = Instant::now();
+        let gateway = SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 5351));
+        let mut listener = PcpListener {
+            s: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            gateway: gateway.ip(),
+            epoch: EpochTracker::new_pcp(),
+        };
+        listener.record_epoch(1000, now);
+
+        // consistent retransmission
+        let later = now + Duration::from_secs(4);
+        match listener.handle_datagram(&announce(1004), gateway, later) {
+            Some(PcpServerEvent::Announced(a)) => {
+                assert_eq!(a.epoch(), 1004);
+                assert_eq!(a.received_at(), later);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        // other source
+        let other = SocketAddr::from((Ipv4Addr::new(192, 168, 0, 9), 5351));
+        assert_eq!(listener.handle_datagram(&announce(0), other, later), None);
+
+        // epoch reset
+        assert!(matches!(
+            listener.handle_datagram(&announce(0), gateway, later),
+            Some(PcpServerEvent::Restarted(_))
+        ));
+    }
+}
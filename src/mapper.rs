@@ -0,0 +1,456 @@
+//! Port mapping client negotiating PCP or NAT-PMP with the gateway.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    get_default_gateway, Error, MappingLifetime, MappingResponse, Natpmp, Pcp, PcpMapResponse,
+    PcpResponse, Protocol, PublicPort, Response, Result, PCP_PORT, POLL_INTERVAL,
+};
+
+/// Internal port (discard, rfc863) of the short lived PCP mapping used to learn the external
+/// address.
+const DISCARD_PORT: u16 = 9;
+
+/// Port mapping protocol spoken with the gateway.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum MappingVersion {
+    Natpmp,
+    Pcp,
+}
+
+/// Port mapping obtained with either protocol.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Mapping {
+    version: MappingVersion,
+    epoch: u32,
+    protocol: Protocol,
+    internal_port: u16,
+    external_port: u16,
+    external_address: Option<IpAddr>,
+    lifetime: Duration,
+    received_at: Instant,
+}
+
+impl Mapping {
+    /// Protocol the mapping was obtained with.
+    pub fn version(&self) -> MappingVersion {
+        self.version
+    }
+
+    /// Gateway epoch in seconds.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Mapping protocol.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Internal port of the mapping.
+    pub fn internal_port(&self) -> u16 {
+        self.internal_port
+    }
+
+    /// Assigned external port.
+    pub fn external_port(&self) -> u16 {
+        self.external_port
+    }
+
+    /// Assigned external address, NAT-PMP mapping responses do not carry it.
+    pub fn external_address(&self) -> Option<&IpAddr> {
+        self.external_address.as_ref()
+    }
+
+    /// Lifetime of the mapping, zero if it was deleted.
+    pub fn lifetime(&self) -> &Duration {
+        &self.lifetime
+    }
+
+    /// `Instant` the response was received at.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    fn from_natpmp(mr: &MappingResponse) -> Mapping {
+        Mapping {
+            version: MappingVersion::Natpmp,
+            epoch: mr.epoch(),
+            protocol: mr.protocol(),
+            internal_port: mr.private_port(),
+            external_port: mr.public_port(),
+            external_address: None,
+            lifetime: *mr.lifetime(),
+            received_at: mr.received_at(),
+        }
+    }
+
+    fn from_pcp(map: &PcpMapResponse) -> Mapping {
+        Mapping {
+            version: MappingVersion::Pcp,
+            epoch: map.epoch(),
+            protocol: map.protocol(),
+            internal_port: map.internal_port(),
+            external_port: map.external_port(),
+            external_address: Some(*map.external_address()),
+            lifetime: *map.lifetime(),
+            received_at: map.received_at(),
+        }
+    }
+}
+
+/// Blocking port mapping operations, implemented by every client so that callers can be
+/// generic over the protocol.
+///
+/// Each call sends a request and waits for its response, retransmitting it on schedule, so
+/// it may block until the gateway is considered unreachable.
+///
+/// # Examples
+/// ```no_run
+/// use natpmp::*;
+///
+/// fn forward(client: &mut impl PortMappingClient, port: u16) -> Result<Mapping> {
+///     println!("external address {}", client.external_address()?);
+///     client.map(Protocol::TCP, port, port, 3600)
+/// }
+///
+/// # fn main() -> Result<()> {
+/// forward(&mut Natpmp::new()?, 4020)?;
+/// forward(&mut Pcp::new()?, 4020)?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait PortMappingClient {
+    /// Get the external address of the gateway.
+    ///
+    /// PCP has no such request, a short lived mapping of the discard port is created and
+    /// deleted instead.
+    ///
+    /// # Errors
+    /// See the `read_response_or_retry` method of the client.
+    fn external_address(&mut self) -> Result<IpAddr>;
+
+    /// Create a port mapping, `external_port` is a suggestion only.
+    ///
+    /// # Errors
+    /// See the `read_response_or_retry` method of the client.
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<Mapping>;
+
+    /// Delete a port mapping.
+    ///
+    /// # Errors
+    /// See the `read_response_or_retry` method of the client.
+    fn unmap(&mut self, protocol: Protocol, internal_port: u16) -> Result<()> {
+        self.map(protocol, internal_port, PublicPort::Any, 0)
+            .map(|_| ())
+    }
+
+    /// Renew a port mapping with its external port and lifetime.
+    ///
+    /// # Errors
+    /// See the `read_response_or_retry` method of the client.
+    fn renew(&mut self, mapping: &Mapping) -> Result<Mapping> {
+        self.map(
+            mapping.protocol(),
+            mapping.internal_port(),
+            mapping.external_port(),
+            *mapping.lifetime(),
+        )
+    }
+}
+
+/// Poll `read` until it no longer asks to try again.
+fn wait<T>(mut read: impl FnMut() -> Result<T>) -> Result<T> {
+    loop {
+        match read() {
+            Err(Error::NATPMP_TRYAGAIN) => thread::sleep(POLL_INTERVAL),
+            r => return r,
+        }
+    }
+}
+
+impl PortMappingClient for Natpmp {
+    fn external_address(&mut self) -> Result<IpAddr> {
+        self.send_public_address_request()?;
+        match wait(|| self.read_response_or_retry())? {
+            Response::Gateway(gr) => Ok(IpAddr::V4(*gr.public_address())),
+            _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        }
+    }
+
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<Mapping> {
+        self.send_port_mapping_request(protocol, internal_port, external_port, lifetime)?;
+        match wait(|| self.read_response_or_retry())? {
+            Response::UDP(mr) | Response::TCP(mr) => Ok(Mapping::from_natpmp(&mr)),
+            Response::Gateway(_) => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        }
+    }
+}
+
+impl PortMappingClient for Pcp {
+    fn external_address(&mut self) -> Result<IpAddr> {
+        let mapping = self.map(Protocol::UDP, DISCARD_PORT, PublicPort::Any, 1)?;
+        self.unmap(Protocol::UDP, DISCARD_PORT)?;
+        mapping
+            .external_address()
+            .copied()
+            .ok_or(Error::NATPMP_ERR_UNSUPPORTEDOPCODE)
+    }
+
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<Mapping> {
+        self.send_map_request(protocol, internal_port, external_port, lifetime)?;
+        match wait(|| self.read_response_or_retry())? {
+            PcpResponse::Map(map) => Ok(Mapping::from_pcp(&map)),
+            _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Backend {
+    Natpmp(Natpmp),
+    Pcp(Pcp),
+}
+
+/// Port mapping client speaking PCP, or NAT-PMP with gateways that do not support PCP.
+///
+/// Requests are sent with PCP first. A NAT-PMP only gateway answers them with an unsupported
+/// version error (rfc6887 section 9), the client then switches to NAT-PMP and resends the
+/// pending request, so the caller sees a single mapping API.
+///
+/// # Examples
+/// ```
+/// use std::thread;
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut mapper = PortMapper::new()?;
+/// mapper.send_map_request(Protocol::UDP, 4020, 4020, 30)?;
+/// loop {
+///     match mapper.read_response_or_retry() {
+///         Ok(mapping) => {
+///             println!("{:?} mapped {}", mapping.version(), mapping.external_port());
+///             break;
+///         }
+///         Err(Error::NATPMP_TRYAGAIN) => thread::sleep(Duration::from_millis(10)),
+///         Err(e) => return Err(e),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PortMapper {
+    gateway: Ipv4Addr,
+    port: u16,
+    backend: Backend,
+    pending: Option<(Protocol, u16, PublicPort, MappingLifetime)>,
+}
+
+impl PortMapper {
+    /// Create a port mapping client with default gateway.
+    ///
+    /// # Errors
+    /// See [`get_default_gateway`](fn.get_default_gateway.html) and [`Pcp::new_with`](struct.Pcp.html#method.new_with).
+    pub fn new() -> Result<PortMapper> {
+        let gateway = get_default_gateway()?;
+        PortMapper::new_with(gateway)
+    }
+
+    /// Create a port mapping client with a specified gateway.
+    ///
+    /// # Errors
+    /// See [`Pcp::new_with`](struct.Pcp.html#method.new_with).
+    pub fn new_with(gateway: Ipv4Addr) -> Result<PortMapper> {
+        PortMapper::new_with_port(gateway, PCP_PORT)
+    }
+
+    /// Create a port mapping client with a specified gateway listening on a non-default
+    /// `port`, e.g. a test server or a port-forwarded gateway, used for both PCP and NAT-PMP.
+    ///
+    /// # Errors
+    /// See [`Pcp::new_with`](struct.Pcp.html#method.new_with).
+    pub fn new_with_port(gateway: Ipv4Addr, port: u16) -> Result<PortMapper> {
+        Ok(PortMapper {
+            gateway,
+            port,
+            backend: Backend::Pcp(Pcp::new_with_port(gateway, port)?),
+            pending: None,
+        })
+    }
+
+    /// Gateway address.
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    /// Protocol currently spoken with the gateway, PCP until the gateway turns out to be
+    /// NAT-PMP only.
+    pub fn version(&self) -> MappingVersion {
+        match self.backend {
+            Backend::Natpmp(_) => MappingVersion::Natpmp,
+            Backend::Pcp(_) => MappingVersion::Pcp,
+        }
+    }
+
+    /// Get timeout duration of the currently pending request.
+    ///
+    /// # Errors:
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    pub fn get_request_timeout(&self) -> Result<Duration> {
+        match &self.backend {
+            Backend::Natpmp(n) => n.get_natpmp_request_timeout(),
+            Backend::Pcp(pcp) => pcp.get_pcp_request_timeout(),
+        }
+    }
+
+    fn send_pending(&mut self) -> Result<()> {
+        let (protocol, internal_port, external_port, lifetime) = match self.pending {
+            Some(pending) => pending,
+            None => return Err(Error::NATPMP_ERR_NOPENDINGREQ),
+        };
+        match &mut self.backend {
+            Backend::Natpmp(n) => {
+                n.send_port_mapping_request(protocol, internal_port, external_port, lifetime)
+            }
+            Backend::Pcp(pcp) => {
+                pcp.send_map_request(protocol, internal_port, external_port, lifetime)
+            }
+        }
+    }
+
+    /// Send a port mapping request.
+    ///
+    /// `external_port` is a suggestion, pass [`PublicPort::Any`](enum.PublicPort.html#variant.Any)
+    /// to let the gateway choose it.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn send_map_request(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        self.pending = Some((
+            protocol,
+            internal_port,
+            external_port.into(),
+            lifetime.into(),
+        ));
+        self.send_pending()
+    }
+
+    /// Send a port mapping deletion request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn send_map_delete(&mut self, protocol: Protocol, internal_port: u16) -> Result<()> {
+        self.send_map_request(protocol, internal_port, PublicPort::Any, 0)
+    }
+
+    /// Read the mapping response if possible.
+    ///
+    /// When a NAT-PMP only gateway rejects the PCP request, the request is sent again with
+    /// NAT-PMP and `NATPMP_TRYAGAIN` is returned.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    /// * See [`Natpmp::read_response_or_retry`](struct.Natpmp.html#method.read_response_or_retry)
+    ///   and [`Pcp::read_response_or_retry`](struct.Pcp.html#method.read_response_or_retry)
+    pub fn read_response_or_retry(&mut self) -> Result<Mapping> {
+        match &mut self.backend {
+            Backend::Natpmp(n) => match n.read_response_or_retry()? {
+                Response::UDP(mr) | Response::TCP(mr) => Ok(Mapping::from_natpmp(&mr)),
+                Response::Gateway(_) => Err(Error::NATPMP_TRYAGAIN),
+            },
+            Backend::Pcp(pcp) => match pcp.read_response_or_retry() {
+                Ok(PcpResponse::Map(map)) => Ok(Mapping::from_pcp(&map)),
+                Ok(_) => Err(Error::NATPMP_TRYAGAIN),
+                Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION) => {
+                    // NAT-PMP only gateway
+                    self.backend = Backend::Natpmp(Natpmp::new_with_port(self.gateway, self.port)?);
+                    self.send_pending()?;
+                    Err(Error::NATPMP_TRYAGAIN)
+                }
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+impl PortMappingClient for PortMapper {
+    fn external_address(&mut self) -> Result<IpAddr> {
+        match &mut self.backend {
+            Backend::Natpmp(n) => n.external_address(),
+            Backend::Pcp(_) => {
+                let mapping = self.map(Protocol::UDP, DISCARD_PORT, PublicPort::Any, 1)?;
+                self.unmap(Protocol::UDP, DISCARD_PORT)?;
+                match mapping.external_address() {
+                    Some(address) => Ok(*address),
+                    // The gateway turned out to be NAT-PMP only
+                    None => self.external_address(),
+                }
+            }
+        }
+    }
+
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<Mapping> {
+        self.send_map_request(protocol, internal_port, external_port, lifetime)?;
+        wait(|| self.read_response_or_retry())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockPcpServer, MOCK_EXTERNAL_ADDRESS};
+
+    #[test]
+    fn test_natpmp_fallback() {
+        let server = MockPcpServer::start();
+        server.behavior().natpmp_only = true;
+        let address = server.address();
+        let mut mapper = PortMapper::new_with_port(*address.ip(), address.port()).unwrap();
+        assert_eq!(mapper.version(), MappingVersion::Pcp);
+
+        let mapping = mapper
+            .map(Protocol::UDP, 4020, PublicPort::Any, 60)
+            .unwrap();
+        assert_eq!(mapper.version(), MappingVersion::Natpmp);
+        assert_eq!(mapping.version(), MappingVersion::Natpmp);
+        assert_eq!(mapping.external_port(), 4020);
+        assert_eq!(
+            mapper.external_address(),
+            Ok(IpAddr::V4(MOCK_EXTERNAL_ADDRESS))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! In-process PCP server for the tests.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::PCP_VERSION;
+
+/// External address of the mappings.
+pub(crate) const MOCK_EXTERNAL_ADDRESS: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+
+/// Server behavior, may be changed while it runs.
+#[derive(Debug)]
+pub(crate) struct Behavior {
+    /// Epoch of the first second, set it back to simulate a restart.
+    pub(crate) epoch: u32,
+    /// Result code of the PCP responses.
+    pub(crate) result_code: u8,
+    /// Answer like a NAT-PMP only server.
+    pub(crate) natpmp_only: bool,
+    /// Answer with a wrong nonce.
+    pub(crate) corrupt_nonce: bool,
+    /// Requests received so far.
+    pub(crate) requests: Vec<Vec<u8>>,
+}
+
+/// PCP (or NAT-PMP only) server answering on an ephemeral port of `127.0.0.1`, so that each
+/// test runs its own server, even if a PCP daemon holds `PCP_PORT`.
+pub(crate) struct MockPcpServer {
+    address: SocketAddrV4,
+    behavior: Arc<Mutex<Behavior>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MockPcpServer {
+    /// Start a server, the clients are given its port.
+    pub(crate) fn start() -> MockPcpServer {
+        let s = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = match s.local_addr().unwrap() {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        s.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let behavior = Arc::new(Mutex::new(Behavior {
+            epoch: 1000,
+            result_code: 0,
+            natpmp_only: false,
+            corrupt_nonce: false,
+            requests: Vec::new(),
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let behavior = behavior.clone();
+            let stop = stop.clone();
+            thread::spawn(move || serve(s, &behavior, &stop))
+        };
+        MockPcpServer {
+            address,
+            behavior,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Address the server answers on.
+    pub(crate) fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    pub(crate) fn behavior(&self) -> MutexGuard<'_, Behavior> {
+        self.behavior.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for MockPcpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(s: UdpSocket, behavior: &Mutex<Behavior>, stop: &AtomicBool) {
+    let started = Instant::now();
+    let mut buf = [0u8; 1100];
+    while !stop.load(Ordering::Relaxed) {
+        let (n, client): (usize, SocketAddr) = match s.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        let mut behavior = behavior.lock().unwrap_or_else(PoisonError::into_inner);
+        behavior.requests.push(buf[..n].to_vec());
+        let epoch = behavior.epoch + started.elapsed().as_secs() as u32;
+        if let Some(reply) = respond(&buf[..n], &behavior, epoch) {
+            let _ = s.send_to(&reply, client);
+        }
+    }
+}
+
+fn respond(request: &[u8], behavior: &Behavior, epoch: u32) -> Option<Vec<u8>> {
+    if request.len() < 2 {
+        return None;
+    }
+    match request[0] {
+        0 if behavior.natpmp_only => Some(respond_natpmp(request, epoch)),
+        PCP_VERSION if behavior.natpmp_only => {
+            // rfc6887 section 9, unsupported version
+            let mut reply = vec![0, request[1] | 0x80, 0, 1];
+            reply.extend_from_slice(&epoch.to_be_bytes());
+            Some(reply)
+        }
+        PCP_VERSION if request.len() >= 24 => Some(respond_pcp(request, behavior, epoch)),
+        _ => None,
+    }
+}
+
+fn respond_pcp(request: &[u8], behavior: &Behavior, epoch: u32) -> Vec<u8> {
+    let mut reply = request.to_vec();
+    reply[1] |= 0x80;
+    reply[3] = behavior.result_code;
+    if behavior.result_code != 0 {
+        reply[4..8].copy_from_slice(&30u32.to_be_bytes());
+    }
+    reply[8..12].copy_from_slice(&epoch.to_be_bytes());
+    reply[12..24].fill(0);
+    // MAP and PEER
+    if matches!(request[1], 1 | 2) && request.len() >= 60 {
+        if reply[42..44] == [0, 0] {
+            reply.copy_within(40..42, 42);
+        }
+        reply[44..60].copy_from_slice(&MOCK_EXTERNAL_ADDRESS.to_ipv6_mapped().octets());
+        if behavior.corrupt_nonce {
+            reply[24] ^= 0xff;
+        }
+    }
+    reply
+}
+
+fn respond_natpmp(request: &[u8], epoch: u32) -> Vec<u8> {
+    let mut reply = vec![0, request[1] | 0x80, 0, 0];
+    reply.extend_from_slice(&epoch.to_be_bytes());
+    match request[1] {
+        0 => reply.extend_from_slice(&MOCK_EXTERNAL_ADDRESS.octets()),
+        _ if request.len() >= 12 => {
+            reply.extend_from_slice(&request[4..6]);
+            match &request[6..8] {
+                [0, 0] => reply.extend_from_slice(&request[4..6]),
+                port => reply.extend_from_slice(port),
+            }
+            reply.extend_from_slice(&request[8..12]);
+        }
+        _ => reply[3] = 5, // unsupported opcode
+    }
+    reply
+}
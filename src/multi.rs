@@ -0,0 +1,344 @@
+//! NAT-PMP client of several gateways sharing an unconnected socket.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::protocol::NATPMP_MAX_RESPONSE_LEN;
+use crate::{
+    send_error, Error, MappingLifetime, NatpmpProto, Protocol, PublicPort, Request, Response,
+    Result, RetryPolicy, NATPMP_PORT,
+};
+
+/// NAT-PMP client of several gateways, e.g. the routers of a dual-WAN setup.
+///
+/// The socket is not connected: the requests are sent to each gateway of the allow-list and
+/// the datagrams received from other addresses are discarded. Each gateway has its own pending
+/// requests and retry schedules, so the gateways are probed concurrently.
+///
+/// # Examples
+/// ```
+/// use std::net::Ipv4Addr;
+/// use std::thread;
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let wan1 = Ipv4Addr::new(192, 168, 0, 1);
+/// let wan2 = Ipv4Addr::new(192, 168, 1, 1);
+/// let mut n = NatpmpMulti::new(&[wan1, wan2])?;
+/// n.send_public_address_request(wan1)?;
+/// n.send_public_address_request(wan2)?;
+/// thread::sleep(Duration::from_millis(100));
+/// match n.read_response_or_retry() {
+///     Ok((gateway, Ok(response))) => println!("{}: {}", gateway, response),
+///     Ok((gateway, Err(e))) => println!("{}: {}", gateway, e),
+///     Err(Error::NATPMP_TRYAGAIN) => {}
+///     Err(e) => return Err(e),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct NatpmpMulti {
+    s: UdpSocket,
+    port: u16,
+    policy: RetryPolicy,
+    gateways: Vec<(Ipv4Addr, NatpmpProto)>,
+}
+
+impl NatpmpMulti {
+    /// Create a client of `gateways` with a socket bound to `0.0.0.0:0`.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    pub fn new(gateways: &[Ipv4Addr]) -> Result<NatpmpMulti> {
+        NatpmpMulti::new_with_port(gateways, NATPMP_PORT)
+    }
+
+    /// Create a client of `gateways` answering on `port`, e.g. test servers.
+    ///
+    /// # Errors
+    /// Same as [`new`](#method.new).
+    pub fn new_with_port(gateways: &[Ipv4Addr], port: u16) -> Result<NatpmpMulti> {
+        let s = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        s.set_nonblocking(true)
+            .map_err(|_| Error::NATPMP_ERR_FCNTLERROR)?;
+        let mut n = NatpmpMulti {
+            s,
+            port,
+            policy: RetryPolicy::default(),
+            gateways: Vec::new(),
+        };
+        for gateway in gateways {
+            n.add_gateway(*gateway);
+        }
+        Ok(n)
+    }
+
+    /// Gateways of the allow-list.
+    pub fn gateways(&self) -> impl Iterator<Item = &Ipv4Addr> {
+        self.gateways.iter().map(|(gateway, _)| gateway)
+    }
+
+    /// Add `gateway` to the allow-list, returns whether it was not in it.
+    pub fn add_gateway(&mut self, gateway: Ipv4Addr) -> bool {
+        if self.gateways.iter().any(|(g, _)| *g == gateway) {
+            return false;
+        }
+        let mut proto = NatpmpProto::new();
+        proto.set_retry_policy(self.policy);
+        self.gateways.push((gateway, proto));
+        true
+    }
+
+    /// Remove `gateway` from the allow-list with its pending requests, returns whether it was
+    /// in it.
+    pub fn remove_gateway(&mut self, gateway: Ipv4Addr) -> bool {
+        let len = self.gateways.len();
+        self.gateways.retain(|(g, _)| *g != gateway);
+        self.gateways.len() != len
+    }
+
+    /// Local address of the socket.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.s
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)
+    }
+
+    /// Set the retry policy of the following requests to all gateways.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no delay
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> Result<()> {
+        if !policy.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.policy = policy;
+        for (_, proto) in &mut self.gateways {
+            proto.set_retry_policy(policy);
+        }
+        Ok(())
+    }
+
+    /// Whether a request to `gateway` is waiting for its response.
+    pub fn has_pending_request(&self, gateway: Ipv4Addr) -> bool {
+        self.proto(gateway)
+            .is_some_and(NatpmpProto::has_pending_request)
+    }
+
+    /// Abandon the pending requests to all gateways.
+    pub fn cancel_pending_request(&mut self) {
+        for (_, proto) in &mut self.gateways {
+            proto.cancel_pending_request();
+        }
+    }
+
+    /// Send `request` to `gateway`, it replaces a pending request to the same gateway with
+    /// the same opcode and private port.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), `gateway`
+    ///   is not in the allow-list
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn send_request(&mut self, gateway: Ipv4Addr, request: Request) -> Result<()> {
+        let server = SocketAddrV4::new(gateway, self.port);
+        let proto = self
+            .gateways
+            .iter_mut()
+            .find(|(g, _)| *g == gateway)
+            .map(|(_, proto)| proto)
+            .ok_or(Error::NATPMP_ERR_INVALIDARGS)?;
+        let request = proto.send_request(request, Instant::now());
+        send_request_to(&self.s, request, server)
+    }
+
+    /// Send public address request to `gateway`.
+    ///
+    /// # Errors
+    /// Same as [`send_request`](#method.send_request).
+    pub fn send_public_address_request(&mut self, gateway: Ipv4Addr) -> Result<()> {
+        self.send_request(gateway, Request::PublicAddress)
+    }
+
+    /// Send port mapping request to `gateway`.
+    ///
+    /// # Errors
+    /// Same as [`send_request`](#method.send_request).
+    pub fn send_port_mapping_request(
+        &mut self,
+        gateway: Ipv4Addr,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let request = Request::Mapping {
+            protocol,
+            private_port,
+            public_port: public_port.into().into(),
+            lifetime: lifetime.into().as_secs(),
+        };
+        self.send_request(gateway, request)
+    }
+
+    /// Duration until the first pending request must be retransmitted.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    pub fn get_natpmp_request_timeout(&self) -> Result<Duration> {
+        let now = Instant::now();
+        self.gateways
+            .iter()
+            .filter_map(|(_, proto)| proto.timeout(now).ok())
+            .min()
+            .ok_or(Error::NATPMP_ERR_NOPENDINGREQ)
+    }
+
+    /// Read the response of a pending request if any, retransmitting the requests when it is
+    /// time to.
+    ///
+    /// The response, or the error the gateway answered with, is returned with the gateway it
+    /// comes from. A gateway whose attempts are exhausted is reported with
+    /// [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT).
+    /// Datagrams from addresses out of the allow-list and unsolicited announcements are
+    /// discarded.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn read_response_or_retry(&mut self) -> Result<(Ipv4Addr, Result<Response>)> {
+        if !self
+            .gateways
+            .iter()
+            .any(|(_, proto)| proto.has_pending_request())
+        {
+            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+        }
+        let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+        loop {
+            let (n, source) = match self.s.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // ICMP error of a gateway not speaking NAT-PMP, its attempts run out
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    continue
+                }
+                Err(_) => return Err(Error::NATPMP_ERR_RECVFROM),
+            };
+            let gateway = match source {
+                SocketAddr::V4(source) if source.port() == self.port => *source.ip(),
+                _ => continue,
+            };
+            let proto = match self.gateways.iter_mut().find(|(g, _)| *g == gateway) {
+                Some((_, proto)) => proto,
+                None => continue,
+            };
+            match proto.handle_datagram(&buf[..n], Instant::now()) {
+                Err(Error::NATPMP_TRYAGAIN) | Err(Error::NATPMP_ERR_NOPENDINGREQ) => {}
+                result => return Ok((gateway, result)),
+            }
+        }
+
+        let now = Instant::now();
+        for (gateway, proto) in &mut self.gateways {
+            let server = SocketAddrV4::new(*gateway, self.port);
+            while let Some((_, result)) = proto.handle_request_timeout(now) {
+                match result {
+                    Ok(request) => send_request_to(&self.s, request, server)?,
+                    Err(e) => return Ok((*gateway, Err(e))),
+                }
+            }
+        }
+        Err(Error::NATPMP_TRYAGAIN)
+    }
+
+    fn proto(&self, gateway: Ipv4Addr) -> Option<&NatpmpProto> {
+        self.gateways
+            .iter()
+            .find(|(g, _)| *g == gateway)
+            .map(|(_, proto)| proto)
+    }
+}
+
+fn send_request_to(s: &UdpSocket, request: &[u8], server: SocketAddrV4) -> Result<()> {
+    match s.send_to(request, server) {
+        Ok(n) if n == request.len() => Ok(()),
+        Ok(_) => Err(Error::NATPMP_ERR_SENDERR),
+        Err(e) => Err(send_error(&e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_multiple_gateways() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let absent = Ipv4Addr::new(127, 0, 0, 2);
+        let mut n = NatpmpMulti::new_with_port(&[Ipv4Addr::LOCALHOST, absent], port)?;
+        n.set_retry_policy(RetryPolicy {
+            attempts: 2,
+            initial_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        })?;
+        assert_eq!(
+            n.send_public_address_request(Ipv4Addr::new(127, 0, 0, 3)),
+            Err(Error::NATPMP_ERR_INVALIDARGS)
+        );
+        n.send_public_address_request(Ipv4Addr::LOCALHOST)?;
+        n.send_public_address_request(absent)?;
+        assert!(n.has_pending_request(absent));
+
+        // not in the allow-list
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = SocketAddr::from((Ipv4Addr::LOCALHOST, n.local_addr()?.port()));
+        let reply = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+        other.send_to(&reply, client).unwrap();
+
+        let mut buf = [0u8; 2];
+        server.recv_from(&mut buf).unwrap();
+        server.send_to(&reply, client).unwrap();
+        let response = loop {
+            match n.read_response_or_retry() {
+                Err(Error::NATPMP_TRYAGAIN) => thread::sleep(Duration::from_millis(5)),
+                r => break r?,
+            }
+        };
+        assert!(matches!(
+            response,
+            (gateway, Ok(Response::Gateway(_))) if gateway == Ipv4Addr::LOCALHOST
+        ));
+
+        let response = loop {
+            match n.read_response_or_retry() {
+                Err(Error::NATPMP_TRYAGAIN) => thread::sleep(Duration::from_millis(5)),
+                r => break r?,
+            }
+        };
+        assert_eq!(response, (absent, Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)));
+        assert_eq!(
+            n.read_response_or_retry(),
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//! NAT-PMP client on the `embedded-nal-async` UDP traits, for `no_std` firmware.
+
+use core::net::{Ipv4Addr, SocketAddr};
+
+use embedded_nal_async::{ConnectedUdp, UdpStack};
+
+use crate::protocol::{is_answered_by, NATPMP_MAX_REQUEST_LEN, NATPMP_MAX_RESPONSE_LEN};
+use crate::{Error, Protocol, Request, Response, Result, NATPMP_PORT};
+
+/// NAT-PMP async client on an `embedded-nal-async` connected UDP socket, e.g. of embassy-net.
+///
+/// It needs neither `std` nor an allocator. The stack provides no timer, so the client does
+/// not retransmit the requests on its own: wrap [`read_response`](#method.read_response) in a
+/// timeout of the runtime and call [`retransmit`](#method.retransmit) when it expires,
+/// doubling the delay from 250ms (rfc6886 section 3.1).
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let mut n = new_natpmp_nal(&stack, gateway).await?;
+/// n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 3600).await?;
+/// let response = loop {
+///     match embassy_time::with_timeout(delay, n.read_response()).await {
+///         Ok(response) => break response?,
+///         Err(_) => n.retransmit().await?,
+///     }
+/// };
+/// ```
+#[derive(Debug)]
+pub struct NatpmpNal<U>
+where
+    U: ConnectedUdp,
+{
+    s: U,
+    request: [u8; NATPMP_MAX_REQUEST_LEN],
+    len: usize,
+}
+
+/// Connect a client to the NAT-PMP server of `gateway` with `stack`.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+pub async fn new_natpmp_nal<T>(stack: &T, gateway: Ipv4Addr) -> Result<NatpmpNal<T::Connected>>
+where
+    T: UdpStack,
+{
+    let s = stack
+        .connect(SocketAddr::from((gateway, NATPMP_PORT)))
+        .await
+        .map_err(|_| Error::NATPMP_ERR_CONNECTERR)?;
+    Ok(new_natpmp_nal_with(s))
+}
+
+/// Create a client with a socket connected to the NAT-PMP server of the gateway.
+pub fn new_natpmp_nal_with<U>(s: U) -> NatpmpNal<U>
+where
+    U: ConnectedUdp,
+{
+    NatpmpNal {
+        s,
+        request: [0u8; NATPMP_MAX_REQUEST_LEN],
+        len: 0,
+    }
+}
+
+impl<U> NatpmpNal<U>
+where
+    U: ConnectedUdp,
+{
+    /// Whether a request is waiting for its response.
+    pub fn has_pending_request(&self) -> bool {
+        self.len > 0
+    }
+
+    /// Abandon the pending request.
+    pub fn cancel_pending_request(&mut self) {
+        self.len = 0;
+    }
+
+    /// Send a request, it replaces the pending one.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_request(&mut self, request: Request) -> Result<()> {
+        self.len = request.encode_into(&mut self.request);
+        self.retransmit().await
+    }
+
+    /// Send public address request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_public_address_request(&mut self) -> Result<()> {
+        self.send_request(Request::PublicAddress).await
+    }
+
+    /// Send port mapping request, a `lifetime` of 0 deletes the mapping.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn send_port_mapping_request(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<()> {
+        self.send_request(Request::Mapping {
+            protocol,
+            private_port,
+            public_port,
+            lifetime,
+        })
+        .await
+    }
+
+    /// Send the pending request again.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub async fn retransmit(&mut self) -> Result<()> {
+        if !self.has_pending_request() {
+            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+        }
+        self.s
+            .send(&self.request[..self.len])
+            .await
+            .map_err(|_| Error::NATPMP_ERR_SENDERR)
+    }
+
+    /// Wait for the response of the pending request.
+    ///
+    /// Datagrams answering another request, e.g. announcements while waiting for a mapping
+    /// response, are discarded.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    /// * Any parse or result code error of the response, see [`Response::parse`](enum.Response.html#method.parse)
+    pub async fn read_response(&mut self) -> Result<Response> {
+        if !self.has_pending_request() {
+            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+        }
+        let mut buf = [0u8; NATPMP_MAX_RESPONSE_LEN];
+        loop {
+            let n = self
+                .s
+                .receive_into(&mut buf)
+                .await
+                .map_err(|_| Error::NATPMP_ERR_RECVFROM)?;
+            if !is_answered_by(&self.request, &buf[..n]) {
+                continue;
+            }
+            let result = Response::parse(&buf[..n]);
+            if result != Err(Error::NATPMP_ERR_TRUNCATEDPACKET) {
+                // the gateway answered, even with an error
+                self.len = 0;
+            }
+            return result;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct MockUdp {
+        sent: Vec<Vec<u8>>,
+        received: VecDeque<Vec<u8>>,
+    }
+
+    impl ConnectedUdp for MockUdp {
+        type Error = Infallible;
+
+        async fn send(&mut self, data: &[u8]) -> core::result::Result<(), Infallible> {
+            self.sent.push(data.to_vec());
+            Ok(())
+        }
+
+        async fn receive_into(
+            &mut self,
+            buf: &mut [u8],
+        ) -> core::result::Result<usize, Infallible> {
+            let datagram = self.received.pop_front().expect("no datagram");
+            buf[..datagram.len()].copy_from_slice(&datagram);
+            Ok(datagram.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nal() -> Result<()> {
+        let mut n = new_natpmp_nal_with(MockUdp::default());
+        assert_eq!(n.read_response().await, Err(Error::NATPMP_ERR_NOPENDINGREQ));
+        n.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30)
+            .await?;
+        n.retransmit().await?;
+        assert_eq!(n.s.sent.len(), 2);
+        assert_eq!(
+            n.s.sent[1],
+            [0, 1, 0, 0, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30]
+        );
+
+        // an announcement, then the response
+        n.s.received
+            .push_back(vec![0, 128, 0, 0, 0, 0, 0, 1, 10, 0, 0, 1]);
+        n.s.received.push_back(vec![
+            0, 129, 0, 0, 0, 0, 0, 1, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+        ]);
+        match n.read_response().await? {
+            Response::UDP(ur) => assert_eq!(ur.public_port(), 4020),
+            _ => panic!("Not a udp mapping response"),
+        }
+        assert!(!n.has_pending_request());
+        Ok(())
+    }
+}
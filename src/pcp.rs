@@ -0,0 +1,1817 @@
+//! PCP (rfc6887) client.
+//!
+//! PCP is the successor of NAT-PMP and uses the same server port. The codec
+//! ([`PcpRequest`](enum.PcpRequest.html), [`PcpResponse::parse`](enum.PcpResponse.html#method.parse))
+//! and the [`PcpProto`](struct.PcpProto.html) state machine are free of sockets,
+//! [`Pcp`](struct.Pcp.html) drives them over a udp socket with the same retry schedule as
+//! [`Natpmp`](struct.Natpmp.html).
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+#[cfg(all(feature = "mio", unix))]
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+#[cfg(all(feature = "mio", unix))]
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::protocol::Retransmission;
+use crate::{
+    connect_socket, get_default_gateway, get_default_gateway_v6, recv_datagram, send_request,
+    set_read_timeout, EpochTracker, Error, MappingLifetime, PcpResultCode, Protocol, PublicPort,
+    Result, RetryPolicy,
+};
+
+/// PCP version as defined by rfc6887.
+pub const PCP_VERSION: u8 = 2;
+
+/// PCP server port as defined by rfc6887, the same as [`NATPMP_PORT`](constant.NATPMP_PORT.html).
+pub const PCP_PORT: u16 = 5351;
+
+/// Port the unsolicited multicast ANNOUNCE responses of a restarting server are sent to.
+pub const PCP_ANNOUNCE_PORT: u16 = 5350;
+
+/// PCP common header size in bytes.
+pub(crate) const PCP_HEADER_LEN: usize = 24;
+
+/// PCP MAP opcode payload size in bytes.
+pub(crate) const PCP_MAP_LEN: usize = 36;
+
+/// PCP PEER opcode payload size in bytes.
+pub(crate) const PCP_PEER_LEN: usize = 56;
+
+/// PCP THIRD_PARTY option code.
+pub(crate) const PCP_OPTION_THIRD_PARTY: u8 = 1;
+
+/// PCP max message size in bytes.
+pub(crate) const PCP_MAX_PACKET_LEN: usize = 1100;
+
+/// PCP opcode as defined by rfc6887.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum PcpOpcode {
+    Announce,
+    Map,
+    Peer,
+}
+
+impl From<PcpOpcode> for u8 {
+    fn from(opcode: PcpOpcode) -> Self {
+        match opcode {
+            PcpOpcode::Announce => 0,
+            PcpOpcode::Map => 1,
+            PcpOpcode::Peer => 2,
+        }
+    }
+}
+
+/// PCP option (rfc6887 section 7.3), e.g. one of the PCP authentication options (rfc7652).
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let option = PcpOption::new(130, vec![1, 2, 3, 4, 5]);
+/// let mut packet = Vec::new();
+/// option.encode_to(&mut packet);
+/// assert_eq!(packet, [130, 0, 0, 5, 1, 2, 3, 4, 5, 0, 0, 0]);
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PcpOption {
+    pub code: u8,
+    pub data: Vec<u8>,
+}
+
+impl PcpOption {
+    /// Create an option.
+    pub fn new(code: u8, data: impl Into<Vec<u8>>) -> PcpOption {
+        PcpOption {
+            code,
+            data: data.into(),
+        }
+    }
+
+    /// Encoded size of the option in bytes, the data is padded to a multiple of 4 bytes.
+    pub fn encoded_len(&self) -> usize {
+        4 + self.data.len().next_multiple_of(4)
+    }
+
+    /// Append the encoded option to `packet`.
+    ///
+    /// # Panics
+    /// Panics if the data is longer than `u16::MAX` bytes.
+    pub fn encode_to(&self, packet: &mut Vec<u8>) {
+        let len = u16::try_from(self.data.len()).expect("option data too long");
+        packet.extend_from_slice(&[self.code, 0]);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(&self.data);
+        packet.resize(packet.len() + self.encoded_len() - 4 - self.data.len(), 0);
+    }
+
+    /// Parse the options following the opcode payload of the PCP packet `buf`.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_TRUNCATEDPACKET`](enum.Error.html#variant.NATPMP_ERR_TRUNCATEDPACKET)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let mut packet = vec![2, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0xe8];
+    /// packet.resize(24, 0);
+    /// PcpOption::new(130, *b"vendor").encode_to(&mut packet);
+    /// assert_eq!(PcpOption::parse_all(&packet), Ok(vec![PcpOption::new(130, *b"vendor")]));
+    /// ```
+    pub fn parse_all(buf: &[u8]) -> Result<Vec<PcpOption>> {
+        let mut offset = match buf.get(1).map(|opcode| opcode & 0x7f) {
+            Some(0) => PCP_HEADER_LEN,
+            Some(1) => PCP_HEADER_LEN + PCP_MAP_LEN,
+            Some(2) => PCP_HEADER_LEN + PCP_PEER_LEN,
+            // unknown payload
+            _ => return Ok(Vec::new()),
+        };
+        let mut options = Vec::new();
+        while offset < buf.len() {
+            if buf.len() < offset + 4 {
+                return Err(Error::NATPMP_ERR_TRUNCATEDPACKET);
+            }
+            let len = usize::from(u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]));
+            let data = buf
+                .get(offset + 4..offset + 4 + len)
+                .ok_or(Error::NATPMP_ERR_TRUNCATEDPACKET)?;
+            options.push(PcpOption::new(buf[offset], data));
+            offset += 4 + len.next_multiple_of(4);
+        }
+        Ok(options)
+    }
+}
+
+/// Hook authenticating PCP requests and responses, e.g. with the PCP authentication options
+/// of rfc7652.
+///
+/// The crate does not run the authentication session itself, an implementation adds its
+/// options to the encoded requests and checks the authentication tag of the responses.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// #[derive(Debug)]
+/// struct Tagger;
+///
+/// impl PcpAuthenticator for Tagger {
+///     fn authenticate_request(&mut self, packet: &mut Vec<u8>) {
+///         PcpOption::new(130, *b"tag!").encode_to(packet);
+///     }
+///
+///     fn verify_response(&mut self, packet: &[u8]) -> bool {
+///         packet.ends_with(b"tag!")
+///     }
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let mut pcp = Pcp::new()?;
+/// pcp.set_authenticator(Box::new(Tagger));
+/// pcp.send_announce_request()?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait PcpAuthenticator: fmt::Debug + Send {
+    /// Add the authentication options to the encoded request `packet`, e.g. an authentication
+    /// tag computed over the whole packet. Retransmissions reuse the packet.
+    fn authenticate_request(&mut self, packet: &mut Vec<u8>);
+
+    /// Whether the response `packet` is authentic, other responses are discarded.
+    fn verify_response(&mut self, packet: &[u8]) -> bool;
+}
+
+/// IANA protocol number of `protocol`.
+fn protocol_number(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::UDP => 17,
+        Protocol::TCP => 6,
+    }
+}
+
+/// Addresses are always 128 bits, IPv4 addresses are IPv4-mapped IPv6 addresses.
+fn encode_address(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+/// Socket address of the PCP server `gateway`, with `scope_id` if IPv6.
+pub(crate) fn pcp_server(gateway: IpAddr, scope_id: u32) -> SocketAddr {
+    match gateway {
+        IpAddr::V4(gateway) => SocketAddrV4::new(gateway, PCP_PORT).into(),
+        IpAddr::V6(gateway) => SocketAddrV6::new(gateway, PCP_PORT, 0, scope_id).into(),
+    }
+}
+
+/// Unspecified address of the family of `address`, i.e. `::ffff:0.0.0.0` or `::` on the wire.
+pub(crate) fn unspecified(address: &IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+fn decode_address(buf: &[u8]) -> IpAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&buf[..16]);
+    let v6 = Ipv6Addr::from(octets);
+    match v6.to_ipv4_mapped() {
+        Some(v4) => IpAddr::V4(v4),
+        None => IpAddr::V6(v6),
+    }
+}
+
+/// Random 96 bits mapping nonce.
+///
+/// The bits come from the randomly keyed hasher of std, seeded by the OS once per process.
+pub(crate) fn random_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    for chunk in nonce.chunks_mut(4) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(now.as_nanos());
+        chunk.copy_from_slice(&hasher.finish().to_be_bytes()[..4]);
+    }
+    nonce
+}
+
+/// Mapping nonces of a client (rfc6887 section 11.2).
+///
+/// A mapping keeps its nonce for its renewals and deletion, responses must echo the nonce of
+/// their mapping, others are spoofed or stale.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Nonces(HashMap<MappingKey, [u8; 12]>);
+
+/// Protocol number, internal port, remote peer of a PEER mapping and third party host of a
+/// mapping.
+type MappingKey = (u8, u16, Option<SocketAddr>, Option<IpAddr>);
+
+impl Nonces {
+    /// Nonce of a mapping, a new random one for a new mapping. The mappings requested on behalf
+    /// of a `third_party` host are distinct from the client's own ones.
+    pub(crate) fn nonce(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        remote: Option<SocketAddr>,
+        third_party: Option<IpAddr>,
+    ) -> [u8; 12] {
+        let key = (
+            protocol_number(protocol),
+            internal_port,
+            remote,
+            third_party,
+        );
+        *self.0.entry(key).or_insert_with(random_nonce)
+    }
+
+    /// Whether the MAP or PEER response in `buf` echoes the nonce of its mapping, the nonce of
+    /// a deleted mapping is forgotten.
+    pub(crate) fn validate(&mut self, buf: &[u8]) -> bool {
+        let remote = match buf.get(1).map(|opcode| opcode & 0x7f) {
+            Some(1) if buf.len() >= PCP_HEADER_LEN + PCP_MAP_LEN => None,
+            Some(2) if buf.len() >= PCP_HEADER_LEN + PCP_PEER_LEN => Some(SocketAddr::new(
+                decode_address(&buf[64..80]),
+                u16::from_be_bytes([buf[60], buf[61]]),
+            )),
+            // nothing to validate
+            _ => return true,
+        };
+        // echoed THIRD_PARTY option
+        let third_party = PcpOption::parse_all(buf)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|option| option.code == PCP_OPTION_THIRD_PARTY && option.data.len() == 16)
+            .map(|option| decode_address(&option.data));
+        let key = (
+            buf[36],
+            u16::from_be_bytes([buf[40], buf[41]]),
+            remote,
+            third_party,
+        );
+        if self.0.get(&key).map(|nonce| &nonce[..]) != Some(&buf[24..36]) {
+            return false;
+        }
+        if buf[3] == 0 && buf[4..8] == [0; 4] {
+            self.0.remove(&key);
+        }
+        true
+    }
+}
+
+/// Whether a PCP server epoch is valid given a previous one, as described in rfc6887 section 8.5.
+///
+/// `elapsed` is the time passed on the client clock between both responses. The epoch must not
+/// go back more than a second and both clocks must advance at the same pace, within 1/16 plus
+/// 2 seconds, otherwise the server lost its state and the mappings must be recreated.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// assert!(pcp_epoch_is_valid(1000, 1060, Duration::from_secs(60)));
+/// assert!(!pcp_epoch_is_valid(1000, 1030, Duration::from_secs(60)));
+/// assert!(!pcp_epoch_is_valid(1000, 1090, Duration::from_secs(60)));
+/// assert!(!pcp_epoch_is_valid(1000, 5, Duration::from_secs(1)));
+/// ```
+pub fn pcp_epoch_is_valid(previous: u32, epoch: u32, elapsed: Duration) -> bool {
+    if u64::from(epoch) + 1 < u64::from(previous) {
+        return false;
+    }
+    let server = u128::from(epoch.saturating_sub(previous)) * 1000; // ms
+    let client = elapsed.as_millis();
+    client + 2000 >= server - server / 16 && server + 2000 >= client - client / 16
+}
+
+/// PCP MAP request (rfc6887 section 11.1).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PcpMapRequest {
+    /// Mapping nonce, echoed by the server.
+    pub nonce: [u8; 12],
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    /// Suggested external port, 0 to let the server choose it.
+    pub external_port: u16,
+    /// Suggested external address, unspecified to let the server choose it.
+    pub external_address: IpAddr,
+    /// Requested lifetime in seconds, 0 deletes the mapping.
+    pub lifetime: u32,
+    /// Internal host to request the mapping for with the THIRD_PARTY option (rfc6887
+    /// section 13.1), `None` for the client itself.
+    pub third_party: Option<IpAddr>,
+}
+
+/// PCP PEER request (rfc6887 section 12.1), creates or refreshes the mapping of an outbound
+/// flow toward a remote peer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PcpPeerRequest {
+    /// Mapping nonce, echoed by the server.
+    pub nonce: [u8; 12],
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    /// Suggested external port, 0 to let the server choose it.
+    pub external_port: u16,
+    /// Suggested external address, unspecified to let the server choose it.
+    pub external_address: IpAddr,
+    pub remote_port: u16,
+    pub remote_address: IpAddr,
+    /// Requested lifetime in seconds.
+    pub lifetime: u32,
+}
+
+/// PCP request.
+///
+/// # Examples
+/// ```
+/// use std::net::{IpAddr, Ipv4Addr};
+/// use natpmp::*;
+///
+/// let request = PcpRequest::Map(PcpMapRequest {
+///     nonce: [0; 12],
+///     protocol: Protocol::UDP,
+///     internal_port: 4020,
+///     external_port: 4020,
+///     external_address: Ipv4Addr::UNSPECIFIED.into(),
+///     lifetime: 30,
+///     third_party: None,
+/// });
+/// let mut buf = [0u8; 60];
+/// let n = request.encode_into(Ipv4Addr::new(192, 168, 0, 2).into(), &mut buf);
+/// assert_eq!(n, 60);
+/// assert_eq!(&buf[..4], &[2, 1, 0, 0]);
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum PcpRequest {
+    /// Learn the server epoch, e.g. to check whether the server restarted.
+    Announce,
+    /// Create, renew or delete a mapping.
+    Map(PcpMapRequest),
+    /// Create or refresh the mapping of a flow toward a remote peer.
+    Peer(PcpPeerRequest),
+}
+
+impl PcpRequest {
+    /// Opcode of the request.
+    pub fn opcode(&self) -> PcpOpcode {
+        match self {
+            PcpRequest::Announce => PcpOpcode::Announce,
+            PcpRequest::Map(_) => PcpOpcode::Map,
+            PcpRequest::Peer(_) => PcpOpcode::Peer,
+        }
+    }
+
+    /// Encoded size of the request in bytes.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            PcpRequest::Announce => PCP_HEADER_LEN,
+            PcpRequest::Map(map) if map.third_party.is_some() => PCP_HEADER_LEN + PCP_MAP_LEN + 20,
+            PcpRequest::Map(_) => PCP_HEADER_LEN + PCP_MAP_LEN,
+            PcpRequest::Peer(_) => PCP_HEADER_LEN + PCP_PEER_LEN,
+        }
+    }
+
+    /// Encode the request sent from `client_address` followed by `options`, e.g. vendor
+    /// specific ones.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// let option = PcpOption::new(130, *b"vendor");
+    /// let packet = PcpRequest::Announce.encode_with_options(Ipv4Addr::new(192, 168, 0, 2).into(), &[option]);
+    /// assert_eq!(packet.len(), 24 + 12);
+    /// assert_eq!(packet[24], 130);
+    /// ```
+    pub fn encode_with_options(&self, client_address: IpAddr, options: &[PcpOption]) -> Vec<u8> {
+        let mut packet = vec![0u8; self.encoded_len()];
+        self.encode_into(client_address, &mut packet);
+        for option in options {
+            option.encode_to(&mut packet);
+        }
+        packet
+    }
+
+    /// Encode the request sent from `client_address` into `buf`, returns the number of bytes
+    /// written.
+    ///
+    /// # Panics
+    /// Panics if `buf` is shorter than [`encoded_len`](#method.encoded_len).
+    pub fn encode_into(&self, client_address: IpAddr, buf: &mut [u8]) -> usize {
+        let len = self.encoded_len();
+        let buf = &mut buf[..len];
+        buf.fill(0);
+        buf[0] = PCP_VERSION;
+        buf[1] = self.opcode().into();
+        buf[8..24].copy_from_slice(&encode_address(client_address));
+        // the PEER payload starts with the MAP payload
+        let (lifetime, nonce, protocol, internal_port, external_port, external_address) =
+            match *self {
+                PcpRequest::Announce => return len,
+                PcpRequest::Map(map) => (
+                    map.lifetime,
+                    map.nonce,
+                    map.protocol,
+                    map.internal_port,
+                    map.external_port,
+                    map.external_address,
+                ),
+                PcpRequest::Peer(peer) => (
+                    peer.lifetime,
+                    peer.nonce,
+                    peer.protocol,
+                    peer.internal_port,
+                    peer.external_port,
+                    peer.external_address,
+                ),
+            };
+        buf[4..8].copy_from_slice(&lifetime.to_be_bytes());
+        buf[24..36].copy_from_slice(&nonce);
+        buf[36] = protocol_number(protocol);
+        buf[40..42].copy_from_slice(&internal_port.to_be_bytes());
+        buf[42..44].copy_from_slice(&external_port.to_be_bytes());
+        buf[44..60].copy_from_slice(&encode_address(external_address));
+        match self {
+            PcpRequest::Map(PcpMapRequest {
+                third_party: Some(address),
+                ..
+            }) => {
+                buf[60] = PCP_OPTION_THIRD_PARTY;
+                buf[62..64].copy_from_slice(&16u16.to_be_bytes());
+                buf[64..80].copy_from_slice(&encode_address(*address));
+            }
+            PcpRequest::Peer(peer) => {
+                buf[60..62].copy_from_slice(&peer.remote_port.to_be_bytes());
+                buf[64..80].copy_from_slice(&encode_address(peer.remote_address));
+            }
+            _ => {}
+        }
+        len
+    }
+}
+
+/// PCP MAP response.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PcpMapResponse {
+    epoch: u32,
+    lifetime: Duration,
+    nonce: [u8; 12],
+    protocol: Protocol,
+    internal_port: u16,
+    external_port: u16,
+    external_address: IpAddr,
+    received_at: Instant,
+}
+
+impl PcpMapResponse {
+    /// Server epoch in seconds.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Lifetime of the mapping, zero if it was deleted.
+    pub fn lifetime(&self) -> &Duration {
+        &self.lifetime
+    }
+
+    /// Mapping nonce of the request.
+    pub fn nonce(&self) -> &[u8; 12] {
+        &self.nonce
+    }
+
+    /// Mapping protocol.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Internal port of the mapping.
+    pub fn internal_port(&self) -> u16 {
+        self.internal_port
+    }
+
+    /// Assigned external port.
+    pub fn external_port(&self) -> u16 {
+        self.external_port
+    }
+
+    /// Assigned external address.
+    pub fn external_address(&self) -> &IpAddr {
+        &self.external_address
+    }
+
+    /// `Instant` the response was received at.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+}
+
+/// PCP ANNOUNCE response, either answering an ANNOUNCE request or sent unsolicited by a
+/// restarting server.
+///
+/// The multicast announcements sent to port [`PCP_ANNOUNCE_PORT`](constant.PCP_ANNOUNCE_PORT.html)
+/// have the same format and parse with [`PcpResponse::parse`](enum.PcpResponse.html#method.parse).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PcpAnnounceResponse {
+    epoch: u32,
+    pub(crate) received_at: Instant,
+}
+
+impl PcpAnnounceResponse {
+    /// Server epoch in seconds.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// `Instant` the response was received at.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+}
+
+/// PCP PEER response, the mapping of a flow toward a remote peer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PcpPeerResponse {
+    mapping: PcpMapResponse,
+    remote_port: u16,
+    remote_address: IpAddr,
+}
+
+impl PcpPeerResponse {
+    /// Mapping of the flow.
+    pub fn mapping(&self) -> &PcpMapResponse {
+        &self.mapping
+    }
+
+    /// Remote peer port.
+    pub fn remote_port(&self) -> u16 {
+        self.remote_port
+    }
+
+    /// Remote peer address.
+    pub fn remote_address(&self) -> &IpAddr {
+        &self.remote_address
+    }
+}
+
+/// PCP response.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PcpResponse {
+    Announce(PcpAnnounceResponse),
+    Map(PcpMapResponse),
+    Peer(PcpPeerResponse),
+}
+
+impl PcpResponse {
+    /// Parse a PCP response.
+    ///
+    /// A NAT-PMP only server answers a PCP request with a NAT-PMP unsupported version error,
+    /// which is reported as such.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_TRUNCATEDPACKET`](enum.Error.html#variant.NATPMP_ERR_TRUNCATEDPACKET)
+    /// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
+    /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDOPCODE)
+    /// * [`Error::PCP_ERR_RESULTCODE`](enum.Error.html#variant.PCP_ERR_RESULTCODE)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// // NAT-PMP only server
+    /// let reply = [0, 129, 0, 1, 0, 0, 0, 9];
+    /// assert_eq!(PcpResponse::parse(&reply), Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION));
+    /// ```
+    pub fn parse(buf: &[u8]) -> Result<PcpResponse> {
+        if buf.len() < 4 {
+            return Err(Error::NATPMP_ERR_TRUNCATEDPACKET);
+        }
+        if buf[0] != PCP_VERSION {
+            return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
+        }
+        if buf[1] & 0x80 == 0 {
+            return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE);
+        }
+        if buf.len() < PCP_HEADER_LEN {
+            return Err(Error::NATPMP_ERR_TRUNCATEDPACKET);
+        }
+        let lifetime = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if let Some(e) = PcpResultCode::from(buf[3]).error(lifetime) {
+            return Err(e);
+        }
+        let epoch = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let len = match buf[1] & 0x7f {
+            0 => {
+                return Ok(PcpResponse::Announce(PcpAnnounceResponse {
+                    epoch,
+                    received_at: Instant::now(),
+                }))
+            }
+            1 => PCP_HEADER_LEN + PCP_MAP_LEN,
+            2 => PCP_HEADER_LEN + PCP_PEER_LEN,
+            _ => return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        };
+        if buf.len() < len {
+            return Err(Error::NATPMP_ERR_TRUNCATEDPACKET);
+        }
+        // only udp and tcp mappings are ever requested
+        let protocol = match buf[36] {
+            17 => Protocol::UDP,
+            6 => Protocol::TCP,
+            _ => return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        };
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&buf[24..36]);
+        let mapping = PcpMapResponse {
+            epoch,
+            lifetime: Duration::from_secs(lifetime.into()),
+            nonce,
+            protocol,
+            internal_port: u16::from_be_bytes([buf[40], buf[41]]),
+            external_port: u16::from_be_bytes([buf[42], buf[43]]),
+            external_address: decode_address(&buf[44..60]),
+            received_at: Instant::now(),
+        };
+        if buf[1] & 0x7f == 1 {
+            return Ok(PcpResponse::Map(mapping));
+        }
+        Ok(PcpResponse::Peer(PcpPeerResponse {
+            mapping,
+            remote_port: u16::from_be_bytes([buf[60], buf[61]]),
+            remote_address: decode_address(&buf[64..80]),
+        }))
+    }
+
+    /// Server epoch in seconds.
+    pub fn epoch(&self) -> u32 {
+        match self {
+            PcpResponse::Announce(announce) => announce.epoch,
+            PcpResponse::Map(map) => map.epoch,
+            PcpResponse::Peer(peer) => peer.mapping.epoch,
+        }
+    }
+
+    /// Mapping of the response, for PEER responses the mapping of the flow.
+    pub fn mapping(&self) -> Option<&PcpMapResponse> {
+        match self {
+            PcpResponse::Announce(_) => None,
+            PcpResponse::Map(map) => Some(map),
+            PcpResponse::Peer(peer) => Some(&peer.mapping),
+        }
+    }
+}
+
+/// PCP protocol state machine, the PCP counterpart of [`NatpmpProto`](struct.NatpmpProto.html).
+///
+/// # Examples
+/// ```
+/// use std::net::{IpAddr, Ipv4Addr};
+/// use std::time::Instant;
+/// use natpmp::*;
+///
+/// let now = Instant::now();
+/// let mut proto = PcpProto::new(Ipv4Addr::new(192, 168, 0, 2).into());
+/// let request = PcpRequest::Map(PcpMapRequest {
+///     nonce: [1; 12],
+///     protocol: Protocol::TCP,
+///     internal_port: 4020,
+///     external_port: 0,
+///     external_address: Ipv4Addr::UNSPECIFIED.into(),
+///     lifetime: 7200,
+///     third_party: None,
+/// });
+/// let bytes = proto.send_request(&request, now);
+/// assert_eq!(bytes.len(), 60);
+/// assert!(proto.has_pending_request());
+/// ```
+#[derive(Debug, Clone)]
+pub struct PcpProto {
+    client_address: IpAddr,
+    pending_request: Option<Vec<u8>>,
+    policy: RetryPolicy,
+    retry: Option<Retransmission>,
+    epoch: EpochTracker,
+}
+
+impl PcpProto {
+    /// Create a protocol state machine for requests sent from `client_address`.
+    pub fn new(client_address: IpAddr) -> PcpProto {
+        PcpProto {
+            client_address,
+            pending_request: None,
+            policy: RetryPolicy::default(),
+            retry: None,
+            epoch: EpochTracker::new_pcp(),
+        }
+    }
+
+    /// Address the requests are sent from.
+    pub fn client_address(&self) -> &IpAddr {
+        &self.client_address
+    }
+
+    /// Retry policy of the requests.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Set the retry policy of the following requests.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Whether a request is pending.
+    pub fn has_pending_request(&self) -> bool {
+        self.pending_request.is_some()
+    }
+
+    /// Bytes of the pending request, if any.
+    pub fn pending_request(&self) -> Option<&[u8]> {
+        self.pending_request.as_deref()
+    }
+
+    /// Abandon the pending request, its response is discarded with
+    /// [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ).
+    pub fn cancel_pending_request(&mut self) {
+        self.pending_request = None;
+    }
+
+    /// Make a request pending, returns the bytes to send.
+    pub fn send_request(&mut self, request: &PcpRequest, now: Instant) -> &[u8] {
+        let mut buf = vec![0u8; request.encoded_len()];
+        request.encode_into(self.client_address, &mut buf);
+        self.send_packet(buf, now)
+    }
+
+    /// Make an encoded request pending, e.g. one with options appended by a
+    /// [`PcpAuthenticator`](trait.PcpAuthenticator.html), returns the bytes to send.
+    ///
+    /// # Panics
+    /// Panics if `packet` is shorter than a PCP header.
+    pub fn send_packet(&mut self, packet: Vec<u8>, now: Instant) -> &[u8] {
+        assert!(packet.len() >= PCP_HEADER_LEN, "truncated pcp request");
+        self.retry = Some(Retransmission::new(self.policy, now));
+        self.pending_request.insert(packet)
+    }
+
+    /// Duration until the pending request must be retransmitted.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    pub fn timeout(&self, now: Instant) -> Result<Duration> {
+        match (&self.pending_request, &self.retry) {
+            (Some(_), Some(retry)) => Ok(retry.timeout(now)),
+            _ => Err(Error::NATPMP_ERR_NOPENDINGREQ),
+        }
+    }
+
+    /// Advance the retry schedule, returns the bytes to retransmit when it is time to.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<Option<&[u8]>> {
+        let (request, retry) = match (&self.pending_request, &mut self.retry) {
+            (Some(request), Some(retry)) => (request, retry),
+            _ => return Err(Error::NATPMP_ERR_NOPENDINGREQ),
+        };
+        if retry.handle_timeout(now)? {
+            Ok(Some(request))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Handle a datagram received from the server at `now`.
+    ///
+    /// Responses whose opcode does not answer the pending request, whose nonce, protocol or
+    /// internal port or, for PEER requests, whose remote peer is not the requested one are
+    /// discarded with `NATPMP_TRYAGAIN`, rejecting spoofed and stale responses. The answered
+    /// request, even with an error result code, stops being retransmitted.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    /// * See [`PcpResponse::parse`](enum.PcpResponse.html#method.parse)
+    pub fn handle_datagram(&mut self, buf: &[u8], now: Instant) -> Result<PcpResponse> {
+        let request = match &self.pending_request {
+            Some(request) => request,
+            None => return Err(Error::NATPMP_ERR_NOPENDINGREQ),
+        };
+        // response opcode is the request opcode with the R bit set
+        if buf.len() >= 2 && buf[0] == PCP_VERSION && buf[1] != request[1] | 0x80 {
+            return Err(Error::NATPMP_TRYAGAIN);
+        }
+        // nonce, protocol and internal port of a MAP or PEER response, the remote peer of a
+        // PEER one
+        let payload_end = match request[1] {
+            1 => PCP_HEADER_LEN + PCP_MAP_LEN,
+            2 => PCP_HEADER_LEN + PCP_PEER_LEN,
+            _ => PCP_HEADER_LEN,
+        };
+        if payload_end > PCP_HEADER_LEN
+            && buf.len() >= payload_end
+            && (buf[24..36] != request[24..36]
+                || buf[36] != request[36]
+                || buf[40..42] != request[40..42]
+                || buf[60..payload_end] != request[60..payload_end])
+        {
+            return Err(Error::NATPMP_TRYAGAIN);
+        }
+        let mut response = match PcpResponse::parse(buf) {
+            Err(Error::NATPMP_ERR_TRUNCATEDPACKET) => {
+                return Err(Error::NATPMP_ERR_TRUNCATEDPACKET)
+            }
+            result => {
+                // the server answered, even with an error
+                self.pending_request = None;
+                result?
+            }
+        };
+        match &mut response {
+            PcpResponse::Announce(announce) => announce.received_at = now,
+            PcpResponse::Map(map) => map.received_at = now,
+            PcpResponse::Peer(peer) => peer.mapping.received_at = now,
+        }
+        self.epoch.update(response.epoch(), now);
+        Ok(response)
+    }
+
+    /// Epoch tracker of the received responses.
+    pub fn epoch_tracker(&self) -> &EpochTracker {
+        &self.epoch
+    }
+
+    /// Whether the epoch of `response` indicates that the server lost its state, see
+    /// [`pcp_epoch_is_valid`](fn.pcp_epoch_is_valid.html).
+    pub fn epoch_indicates_reboot(&self, response: &PcpResponse) -> bool {
+        self.epoch.indicates_reboot(response.epoch())
+    }
+}
+
+/// PCP client.
+///
+/// # Examples
+/// ```
+/// use std::thread;
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut pcp = Pcp::new()?;
+/// pcp.send_map_request(Protocol::UDP, 4020, 4020, 30)?;
+/// thread::sleep(Duration::from_millis(100));
+/// match pcp.read_response_or_retry()? {
+///     PcpResponse::Map(map) => println!("{}:{}", map.external_address(), map.external_port()),
+///     _ => panic!("Not a map response"),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Pcp {
+    s: UdpSocket,
+    gateway: IpAddr,
+    nonces: Nonces,
+    proto: PcpProto,
+    announcements: VecDeque<PcpAnnounceResponse>,
+    authenticator: Option<Box<dyn PcpAuthenticator>>,
+    blocking: bool,
+}
+
+impl Pcp {
+    /// Create a PCP client with default gateway.
+    ///
+    /// # Errors
+    /// See [`get_default_gateway`](fn.get_default_gateway.html) and [`Pcp::new_with`](struct.Pcp.html#method.new_with).
+    pub fn new() -> Result<Pcp> {
+        let gateway = get_default_gateway()?;
+        Pcp::new_with(gateway)
+    }
+
+    /// Create a PCP client with a specified gateway.
+    ///
+    /// The gateway may be an IPv6 address, e.g. to open IPv6 firewall pinholes, the mappings
+    /// then have IPv6 internal and external addresses.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let pcp = Pcp::new_with("192.168.0.1".parse::<std::net::Ipv4Addr>().unwrap()).unwrap();
+    /// let pcp = Pcp::new_with("::1".parse::<std::net::Ipv6Addr>().unwrap()).unwrap();
+    /// ```
+    pub fn new_with(gateway: impl Into<IpAddr>) -> Result<Pcp> {
+        Pcp::new_at(pcp_server(gateway.into(), 0))
+    }
+
+    /// Create a PCP client with a specified gateway listening on a non-default `port`, e.g. a
+    /// test server or a port-forwarded gateway.
+    ///
+    /// # Errors
+    /// See [`Pcp::new_with`](struct.Pcp.html#method.new_with).
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// let pcp = Pcp::new_with_port(Ipv4Addr::LOCALHOST, 15351).unwrap();
+    /// ```
+    pub fn new_with_port(gateway: impl Into<IpAddr>, port: u16) -> Result<Pcp> {
+        Pcp::new_at(SocketAddr::new(gateway.into(), port))
+    }
+
+    /// Create a PCP client with the IPv6 default router, see
+    /// [`get_default_gateway_v6`](fn.get_default_gateway_v6.html).
+    ///
+    /// The router is usually reached at its link-local address, through the interface it was
+    /// learned on.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_CANNOTGETGATEWAY`](enum.Error.html#variant.NATPMP_ERR_CANNOTGETGATEWAY)
+    /// * See [`Pcp::new_with`](struct.Pcp.html#method.new_with)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let pcp = Pcp::new_v6()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_v6() -> Result<Pcp> {
+        let (gateway, scope_id) = get_default_gateway_v6()?;
+        Pcp::new_at(pcp_server(gateway.into(), scope_id))
+    }
+
+    fn new_at(server: SocketAddr) -> Result<Pcp> {
+        let s = connect_socket(server)?;
+        let client_address = s
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?
+            .ip();
+        Ok(Pcp {
+            s,
+            gateway: server.ip(),
+            nonces: Nonces::default(),
+            proto: PcpProto::new(client_address),
+            announcements: VecDeque::new(),
+            authenticator: None,
+            blocking: false,
+        })
+    }
+
+    /// PCP server address.
+    pub fn gateway(&self) -> &IpAddr {
+        &self.gateway
+    }
+
+    /// Local address of the socket, e.g. to log the source port of the requests.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.s
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)
+    }
+
+    /// Address the requests are sent from.
+    pub fn client_address(&self) -> &IpAddr {
+        self.proto.client_address()
+    }
+
+    /// Set the retry policy of the following requests.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), no attempt
+    ///   or no delay
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> Result<()> {
+        if !policy.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.proto.set_retry_policy(policy);
+        Ok(())
+    }
+
+    /// Switch the socket to blocking mode, so that
+    /// [`read_response_or_retry`](#method.read_response_or_retry) waits for the response and
+    /// retransmits the request itself, see [`Natpmp::set_blocking`](struct.Natpmp.html#method.set_blocking).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    pub fn set_blocking(&mut self, blocking: bool) -> Result<()> {
+        self.s
+            .set_nonblocking(!blocking)
+            .map_err(|_| Error::NATPMP_ERR_FCNTLERROR)?;
+        self.blocking = blocking;
+        Ok(())
+    }
+
+    /// Whether the socket is in blocking mode, see [`set_blocking`](#method.set_blocking).
+    pub fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    /// Abandon the pending request, e.g. to give up early, its response is discarded.
+    pub fn cancel_pending_request(&mut self) {
+        self.proto.cancel_pending_request();
+    }
+
+    /// Get timeout duration of the currently pending PCP request.
+    ///
+    /// # Errors:
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    pub fn get_pcp_request_timeout(&self) -> Result<Duration> {
+        self.proto.timeout(Instant::now())
+    }
+
+    /// Authenticate the following requests and responses with `authenticator`.
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn PcpAuthenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// Stop authenticating requests and responses, returns the previous authenticator.
+    pub fn take_authenticator(&mut self) -> Option<Box<dyn PcpAuthenticator>> {
+        self.authenticator.take()
+    }
+
+    /// Send a PCP request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn send_request(&mut self, request: &PcpRequest) -> Result<()> {
+        self.send_request_with_options(request, &[])
+    }
+
+    /// Send a PCP request followed by `options`, e.g. vendor specific ones.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut pcp = Pcp::new()?;
+    /// let request = PcpRequest::Map(PcpMapRequest {
+    ///     nonce: [1; 12],
+    ///     protocol: Protocol::UDP,
+    ///     internal_port: 4020,
+    ///     external_port: 4020,
+    ///     external_address: Ipv4Addr::UNSPECIFIED.into(),
+    ///     lifetime: 30,
+    ///     third_party: None,
+    /// });
+    /// pcp.send_request_with_options(&request, &[PcpOption::new(130, *b"vendor")])?;
+    /// // later
+    /// let (response, options) = pcp.read_response_with_options()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_request_with_options(
+        &mut self,
+        request: &PcpRequest,
+        options: &[PcpOption],
+    ) -> Result<()> {
+        let mut packet = request.encode_with_options(*self.client_address(), options);
+        if let Some(authenticator) = &mut self.authenticator {
+            authenticator.authenticate_request(&mut packet);
+        }
+        let request = self.proto.send_packet(packet, Instant::now());
+        send_request(&self.s, request)
+    }
+
+    /// Send an ANNOUNCE request, the response carries the server epoch.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn send_announce_request(&mut self) -> Result<()> {
+        self.send_request(&PcpRequest::Announce)
+    }
+
+    /// Send a MAP request.
+    ///
+    /// Each mapping gets a random nonce, kept for its renewals and deletion.
+    /// `external_port` is a suggestion, pass [`PublicPort::Any`](enum.PublicPort.html#variant.Any)
+    /// to let the server choose it.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut pcp = Pcp::new()?;
+    /// pcp.send_map_request(Protocol::TCP, 4020, PublicPort::Any, 7200)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_map_request(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let request = PcpRequest::Map(PcpMapRequest {
+            nonce: self.nonces.nonce(protocol, internal_port, None, None),
+            protocol,
+            internal_port,
+            external_port: external_port.into().into(),
+            external_address: unspecified(self.client_address()),
+            lifetime: lifetime.into().as_secs(),
+            third_party: None,
+        });
+        self.send_request(&request)
+    }
+
+    /// Send a MAP request deleting the mapping of `internal_port`.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn send_map_delete(&mut self, protocol: Protocol, internal_port: u16) -> Result<()> {
+        self.send_map_request(protocol, internal_port, PublicPort::Any, 0)
+    }
+
+    /// Send a MAP request on behalf of the internal host `third_party` with the THIRD_PARTY
+    /// option, a lifetime of 0 deletes its mapping.
+    ///
+    /// The server must authorize the client to do so, otherwise it fails with
+    /// [`Error::PCP_ERR_RESULTCODE`](enum.Error.html#variant.PCP_ERR_RESULTCODE) and
+    /// [`PcpResultCode::NotAuthorized`](enum.PcpResultCode.html#variant.NotAuthorized).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::Ipv4Addr;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut pcp = Pcp::new()?;
+    /// let camera = Ipv4Addr::new(192, 168, 0, 42);
+    /// pcp.send_third_party_map_request(camera.into(), Protocol::TCP, 554, 8554, 3600)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_third_party_map_request(
+        &mut self,
+        third_party: IpAddr,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let request = PcpRequest::Map(PcpMapRequest {
+            nonce: self
+                .nonces
+                .nonce(protocol, internal_port, None, Some(third_party)),
+            protocol,
+            internal_port,
+            external_port: external_port.into().into(),
+            external_address: unspecified(self.client_address()),
+            lifetime: lifetime.into().as_secs(),
+            third_party: Some(third_party),
+        });
+        self.send_request(&request)
+    }
+
+    /// Send a PEER request creating or refreshing the mapping of the flow from `internal_port`
+    /// to `remote`.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::net::SocketAddr;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut pcp = Pcp::new()?;
+    /// let remote: SocketAddr = "203.0.113.7:443".parse().unwrap();
+    /// pcp.send_peer_request(Protocol::TCP, 4020, PublicPort::Any, remote, 120)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_peer_request(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: impl Into<PublicPort>,
+        remote: SocketAddr,
+        lifetime: impl Into<MappingLifetime>,
+    ) -> Result<()> {
+        let request = PcpRequest::Peer(PcpPeerRequest {
+            nonce: self
+                .nonces
+                .nonce(protocol, internal_port, Some(remote), None),
+            protocol,
+            internal_port,
+            external_port: external_port.into().into(),
+            external_address: unspecified(self.client_address()),
+            remote_port: remote.port(),
+            remote_address: remote.ip(),
+            lifetime: lifetime.into().as_secs(),
+        });
+        self.send_request(&request)
+    }
+
+    fn read_response(&mut self) -> Result<(PcpResponse, Vec<PcpOption>)> {
+        let mut buf = [0u8; PCP_MAX_PACKET_LEN];
+        loop {
+            let n = recv_datagram(&self.s, self.gateway, &mut buf)?;
+            let now = Instant::now();
+            if let Some(authenticator) = &mut self.authenticator {
+                if !authenticator.verify_response(&buf[..n]) {
+                    continue;
+                }
+            }
+            match self.proto.handle_datagram(&buf[..n], now) {
+                // not the reply, keep the announcements of a restarting server
+                Err(Error::NATPMP_TRYAGAIN) => {
+                    if let Ok(PcpResponse::Announce(mut announce)) = PcpResponse::parse(&buf[..n]) {
+                        announce.received_at = now;
+                        self.announcements.push_back(announce);
+                    }
+                }
+                result => {
+                    self.nonces.validate(&buf[..n]);
+                    let response = result?;
+                    return Ok((response, PcpOption::parse_all(&buf[..n])?));
+                }
+            }
+        }
+    }
+
+    /// Whether the epoch of `response` indicates that the server lost its state since the
+    /// previous response (rfc6887 section 8.5), in which case all mappings must be recreated.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut pcp = Pcp::new()?;
+    /// pcp.send_announce_request()?;
+    /// // later
+    /// if let Ok(response) = pcp.read_response_or_retry() {
+    ///     if pcp.epoch_indicates_reboot(&response) {
+    ///         // recreate mappings
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn epoch_indicates_reboot(&self, response: &PcpResponse) -> bool {
+        self.proto.epoch_indicates_reboot(response)
+    }
+
+    /// Take the oldest unsolicited ANNOUNCE received while reading a response.
+    ///
+    /// A restarting server announces itself (rfc6887 section 14.1.3), its mappings are lost
+    /// and must be recreated.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut pcp = Pcp::new()?;
+    /// pcp.send_map_request(Protocol::UDP, 4020, 4020, 30)?;
+    /// // later
+    /// let response = pcp.read_response_or_retry();
+    /// if pcp.pop_announcement().is_some() {
+    ///     // recreate mappings
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pop_announcement(&mut self) -> Option<PcpAnnounceResponse> {
+        self.announcements.pop_front()
+    }
+
+    /// Read PCP response if possible
+    ///
+    /// In blocking mode (see [`set_blocking`](#method.set_blocking)) it waits for the response,
+    /// retransmitting the request, until the retry policy is exhausted.
+    ///
+    /// Unsolicited ANNOUNCE responses received meanwhile are queued, see
+    /// [`pop_announcement`](#method.pop_announcement).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    /// * [`Error::NATPMP_ERR_WRONGPACKETSOURCE`](enum.Error.html#variant.NATPMP_ERR_WRONGPACKETSOURCE)
+    /// * See [`PcpResponse::parse`](enum.PcpResponse.html#method.parse)
+    pub fn read_response_or_retry(&mut self) -> Result<PcpResponse> {
+        self.read_response_with_options()
+            .map(|(response, _)| response)
+    }
+
+    /// Read PCP response and its options if possible, see
+    /// [`read_response_or_retry`](#method.read_response_or_retry).
+    ///
+    /// # Errors
+    /// See [`read_response_or_retry`](#method.read_response_or_retry) and
+    /// [`PcpOption::parse_all`](struct.PcpOption.html#method.parse_all).
+    pub fn read_response_with_options(&mut self) -> Result<(PcpResponse, Vec<PcpOption>)> {
+        if !self.proto.has_pending_request() {
+            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+        }
+        loop {
+            if self.blocking {
+                set_read_timeout(&self.s, self.proto.timeout(Instant::now())?)?;
+            }
+            let result = self.read_response();
+            if let Err(Error::NATPMP_TRYAGAIN) = result {
+                if let Some(request) = self.proto.handle_timeout(Instant::now())? {
+                    send_request(&self.s, request)?;
+                }
+                if self.blocking {
+                    continue;
+                }
+            }
+            return result;
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Pcp {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.s.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsFd for Pcp {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.s.as_fd()
+    }
+}
+
+/// Readiness of the socket in a mio `Poll` loop, unix only, see
+/// [`Natpmp`](struct.Natpmp.html#impl-Source-for-Natpmp).
+#[cfg(all(feature = "mio", unix))]
+impl mio::event::Source for Pcp {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.s.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.s.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.s.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for Pcp {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.s.as_raw_socket()
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsSocket for Pcp {
+    fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+        self.s.as_socket()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::mock::{MockPcpServer, MOCK_EXTERNAL_ADDRESS};
+
+    fn map_request(internal_port: u16) -> PcpRequest {
+        PcpRequest::Map(PcpMapRequest {
+            nonce: [7; 12],
+            protocol: Protocol::TCP,
+            internal_port,
+            external_port: 4021,
+            external_address: Ipv4Addr::UNSPECIFIED.into(),
+            lifetime: 7200,
+            third_party: None,
+        })
+    }
+
+    /// Server reply to `request`, mapped to 10.0.0.1.
+    fn map_reply(request: &[u8]) -> Vec<u8> {
+        let mut reply = request.to_vec();
+        reply[1] |= 0x80;
+        reply[8..12].copy_from_slice(&1000u32.to_be_bytes());
+        reply[12..24].fill(0);
+        reply[44..60].copy_from_slice(&Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped().octets());
+        reply
+    }
+
+    #[test]
+    fn test_encode_map_request() {
+        let mut buf = [0xffu8; PCP_MAX_PACKET_LEN];
+        let n = map_request(4020).encode_into(Ipv4Addr::new(192, 168, 0, 2).into(), &mut buf);
+        assert_eq!(n, 60);
+        assert_eq!(&buf[..8], &[2, 1, 0, 0, 0, 0, 0x1c, 0x20]);
+        assert_eq!(
+            &buf[8..24],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 168, 0, 2]
+        );
+        assert_eq!(&buf[24..36], &[7; 12]);
+        assert_eq!(&buf[36..44], &[6, 0, 0, 0, 0x0f, 0xb4, 0x0f, 0xb5]);
+        assert_eq!(
+            &buf[44..60],
+            &Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets()
+        );
+    }
+
+    #[test]
+    fn test_handle_map_response() {
+        let now = Instant::now();
+        let mut proto = PcpProto::new(Ipv4Addr::new(192, 168, 0, 2).into());
+        let request = proto.send_request(&map_request(4020), now).to_vec();
+
+        // another mapping
+        let mut other = map_reply(&request);
+        other[40..42].copy_from_slice(&4030u16.to_be_bytes());
+        assert_eq!(
+            proto.handle_datagram(&other, now),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+
+        match proto.handle_datagram(&map_reply(&request), now) {
+            Ok(PcpResponse::Map(map)) => {
+                assert_eq!(map.epoch(), 1000);
+                assert_eq!(map.internal_port(), 4020);
+                assert_eq!(map.external_port(), 4021);
+                assert_eq!(map.external_address(), &IpAddr::from([10, 0, 0, 1]));
+                assert_eq!(map.lifetime(), &Duration::from_secs(7200));
+                assert_eq!(map.received_at(), now);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        // answered
+        assert!(!proto.has_pending_request());
+        assert_eq!(
+            proto.handle_datagram(&map_reply(&request), now),
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+    }
+
+    #[test]
+    fn test_peer() {
+        let now = Instant::now();
+        let mut proto = PcpProto::new(Ipv4Addr::new(192, 168, 0, 2).into());
+        let peer = PcpRequest::Peer(PcpPeerRequest {
+            nonce: [7; 12],
+            protocol: Protocol::UDP,
+            internal_port: 4020,
+            external_port: 0,
+            external_address: Ipv4Addr::UNSPECIFIED.into(),
+            remote_port: 443,
+            remote_address: Ipv4Addr::new(203, 0, 113, 7).into(),
+            lifetime: 120,
+        });
+        let request = proto.send_request(&peer, now).to_vec();
+        assert_eq!(request.len(), 80);
+        assert_eq!(&request[..2], &[2, 2]);
+        assert_eq!(&request[60..64], &[1, 0xbb, 0, 0]);
+
+        // another remote peer
+        let mut other = map_reply(&request);
+        other[79] = 8;
+        assert_eq!(
+            proto.handle_datagram(&other, now),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+
+        match proto.handle_datagram(&map_reply(&request), now) {
+            Ok(PcpResponse::Peer(peer)) => {
+                assert_eq!(peer.mapping().internal_port(), 4020);
+                assert_eq!(
+                    peer.mapping().external_address(),
+                    &IpAddr::from([10, 0, 0, 1])
+                );
+                assert_eq!(peer.remote_port(), 443);
+                assert_eq!(peer.remote_address(), &IpAddr::from([203, 0, 113, 7]));
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_announce() {
+        let now = Instant::now();
+        let mut proto = PcpProto::new(Ipv4Addr::new(192, 168, 0, 2).into());
+        let request = proto.send_request(&PcpRequest::Announce, now).to_vec();
+        assert_eq!(request.len(), 24);
+        assert_eq!(&request[..8], &[2, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut reply = [0u8; 24];
+        reply[..2].copy_from_slice(&[2, 128]);
+        reply[8..12].copy_from_slice(&1000u32.to_be_bytes());
+        match proto.handle_datagram(&reply, now) {
+            Ok(PcpResponse::Announce(announce)) => assert_eq!(announce.epoch(), 1000),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // unsolicited announce while waiting for a mapping
+        proto.send_request(&map_request(4020), now);
+        assert_eq!(
+            proto.handle_datagram(&reply, now),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let now = Instant::now();
+        let client: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut proto = PcpProto::new(client.into());
+        let request = PcpRequest::Map(PcpMapRequest {
+            nonce: [7; 12],
+            protocol: Protocol::TCP,
+            internal_port: 4020,
+            external_port: 0,
+            external_address: unspecified(&client.into()),
+            lifetime: 7200,
+            third_party: None,
+        });
+        let request = proto.send_request(&request, now).to_vec();
+        assert_eq!(&request[8..24], &client.octets());
+        assert_eq!(&request[44..60], &[0; 16]);
+
+        let mut reply = map_reply(&request);
+        reply[44..60].copy_from_slice(&client.octets());
+        match proto.handle_datagram(&reply, now) {
+            Ok(PcpResponse::Map(map)) => assert_eq!(map.external_address(), &IpAddr::V6(client)),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_nonce() {
+        let now = Instant::now();
+        let mut proto = PcpProto::new(Ipv4Addr::new(192, 168, 0, 2).into());
+        let request = proto.send_request(&map_request(4020), now).to_vec();
+        let mut spoofed = map_reply(&request);
+        spoofed[24] = 8;
+        assert_eq!(
+            proto.handle_datagram(&spoofed, now),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+
+        let mut nonces = Nonces::default();
+        let nonce = nonces.nonce(Protocol::TCP, 4020, None, None);
+        assert_eq!(nonces.nonce(Protocol::TCP, 4020, None, None), nonce);
+        assert_ne!(nonces.nonce(Protocol::UDP, 4020, None, None), nonce);
+        let mut reply = map_reply(&request);
+        reply[24..36].copy_from_slice(&nonce);
+        assert!(nonces.validate(&reply));
+        assert!(!nonces.validate(&spoofed));
+
+        // same mapping on behalf of another host
+        let camera = IpAddr::from([192, 168, 0, 42]);
+        let third_party = nonces.nonce(Protocol::TCP, 4020, None, Some(camera));
+        assert_ne!(third_party, nonce);
+        let mut third_party_reply = map_reply(&request);
+        third_party_reply[24..36].copy_from_slice(&third_party);
+        assert!(!nonces.validate(&third_party_reply));
+        let option = PcpOption::new(
+            PCP_OPTION_THIRD_PARTY,
+            Ipv4Addr::new(192, 168, 0, 42).to_ipv6_mapped().octets(),
+        );
+        option.encode_to(&mut third_party_reply);
+        assert!(nonces.validate(&third_party_reply));
+
+        // deleted
+        reply[4..8].fill(0);
+        assert!(nonces.validate(&reply));
+        assert!(!nonces.validate(&reply));
+    }
+
+    #[test]
+    fn test_third_party() {
+        let now = Instant::now();
+        let mut proto = PcpProto::new(Ipv4Addr::new(192, 168, 0, 2).into());
+        let mut request = map_request(4020);
+        if let PcpRequest::Map(map) = &mut request {
+            map.third_party = Some(Ipv4Addr::new(192, 168, 0, 42).into());
+        }
+        let request = proto.send_request(&request, now).to_vec();
+        assert_eq!(request.len(), 80);
+        assert_eq!(&request[60..64], &[1, 0, 0, 16]);
+        assert_eq!(&request[76..80], &[192, 168, 0, 42]);
+
+        // the option is echoed
+        match proto.handle_datagram(&map_reply(&request), now) {
+            Ok(PcpResponse::Map(map)) => assert_eq!(map.internal_port(), 4020),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_epoch() {
+        let now = Instant::now();
+        let mut tracker = EpochTracker::new_pcp();
+        assert!(!tracker.update(100, now));
+        assert!(!tracker.update(160, now + Duration::from_secs(60)));
+        // server clock 10% faster than the client's
+        assert!(tracker.update(270, now + Duration::from_secs(160)));
+        // server restarted
+        assert!(tracker.update(3, now + Duration::from_secs(170)));
+        assert!(!tracker.update(13, now + Duration::from_secs(180)));
+    }
+
+    #[test]
+    fn test_result_code() {
+        let now = Instant::now();
+        let mut proto = PcpProto::new(Ipv4Addr::new(192, 168, 0, 2).into());
+        let request = proto.send_request(&map_request(4020), now).to_vec();
+        let mut reply = map_reply(&request);
+        reply[3] = 8; // NO_RESOURCES
+        reply[4..8].copy_from_slice(&30u32.to_be_bytes());
+        assert_eq!(
+            proto.handle_datagram(&reply[..20], now),
+            Err(Error::NATPMP_ERR_TRUNCATEDPACKET)
+        );
+        assert!(proto.has_pending_request());
+        assert_eq!(
+            proto.handle_datagram(&reply, now),
+            Err(Error::PCP_ERR_RESULTCODE(PcpResultCode::NoResources, 30))
+        );
+        assert!(!proto.has_pending_request());
+        assert_eq!(
+            proto.handle_datagram(&reply, now),
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+    }
+
+    fn wait_response(pcp: &mut Pcp) -> Result<PcpResponse> {
+        loop {
+            match pcp.read_response_or_retry() {
+                Err(Error::NATPMP_TRYAGAIN) => thread::sleep(Duration::from_millis(5)),
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn test_mock_server() {
+        let server = MockPcpServer::start();
+        let mut pcp = Pcp::new_with_port(*server.address().ip(), server.address().port()).unwrap();
+
+        for _ in 0..2 {
+            pcp.send_map_request(Protocol::UDP, 4020, PublicPort::Any, 60)
+                .unwrap();
+            match wait_response(&mut pcp).unwrap() {
+                PcpResponse::Map(map) => {
+                    assert_eq!(map.external_port(), 4020);
+                    assert_eq!(*map.external_address(), IpAddr::V4(MOCK_EXTERNAL_ADDRESS));
+                    assert_eq!(map.epoch(), 1000);
+                }
+                response => panic!("unexpected {:?}", response),
+            }
+        }
+        // the renewal keeps the nonce
+        {
+            let behavior = server.behavior();
+            assert_eq!(behavior.requests.len(), 2);
+            assert_eq!(behavior.requests[0][24..36], behavior.requests[1][24..36]);
+        }
+
+        // server restart
+        server.behavior().epoch = 0;
+        pcp.send_announce_request().unwrap();
+        let response = wait_response(&mut pcp).unwrap();
+        assert!(matches!(response, PcpResponse::Announce(_)));
+        assert!(pcp.epoch_indicates_reboot(&response));
+
+        // spoofed response
+        server.behavior().corrupt_nonce = true;
+        pcp.send_map_request(Protocol::UDP, 4020, PublicPort::Any, 60)
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pcp.read_response_or_retry(), Err(Error::NATPMP_TRYAGAIN));
+
+        // error result code
+        {
+            let mut behavior = server.behavior();
+            behavior.corrupt_nonce = false;
+            behavior.result_code = 8;
+        }
+        pcp.send_map_request(Protocol::TCP, 4021, PublicPort::Any, 60)
+            .unwrap();
+        assert_eq!(
+            wait_response(&mut pcp),
+            Err(Error::PCP_ERR_RESULTCODE(PcpResultCode::NoResources, 30))
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let server = MockPcpServer::start();
+        let mut pcp = Pcp::new_with_port(*server.address().ip(), server.address().port()).unwrap();
+        let options = [PcpOption::new(130, *b"vendor"), PcpOption::new(131, [])];
+        pcp.send_request_with_options(&map_request(4020), &options)
+            .unwrap();
+        let (response, echoed) = loop {
+            match pcp.read_response_with_options() {
+                Err(Error::NATPMP_TRYAGAIN) => thread::sleep(Duration::from_millis(5)),
+                result => break result.unwrap(),
+            }
+        };
+        assert!(matches!(response, PcpResponse::Map(_)));
+        assert_eq!(echoed, options);
+        assert_eq!(server.behavior().requests[0].len(), 60 + 12 + 4);
+
+        let mut reply = map_reply(
+            &map_request(4020)
+                .encode_with_options(Ipv4Addr::new(192, 168, 0, 2).into(), &options[..1]),
+        );
+        reply.truncate(reply.len() - 4);
+        assert_eq!(
+            PcpOption::parse_all(&reply),
+            Err(Error::NATPMP_ERR_TRUNCATEDPACKET)
+        );
+    }
+}
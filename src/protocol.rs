@@ -0,0 +1,1670 @@
+//! Sans-IO NAT-PMP protocol core.
+//!
+//! Everything in this module is free of sockets and clocks: requests are encoded into byte
+//! buffers, received datagrams are parsed from byte slices and the retry timing is driven by
+//! the `Instant` the caller passes in. Both [`Natpmp`](struct.Natpmp.html) and
+//! [`NatpmpAsync`](struct.NatpmpAsync.html) are built on top of it.
+//!
+//! The codec ([`Request`](enum.Request.html), [`Response::parse`](enum.Response.html#method.parse))
+//! is `no_std` and allocation free, the [`NatpmpProto`](struct.NatpmpProto.html) state machine
+//! requires the `std` feature for `Instant`.
+
+use core::net::Ipv4Addr;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::hash::{BuildHasher, Hasher};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use crate::{pcp_epoch_is_valid, MappingLifetime, PublicPort};
+use crate::{Error, GatewayResponse, MappingResponse, Protocol, Response, ResponseType, Result};
+
+/// NAT-PMP mini wait milli-seconds
+#[cfg(feature = "std")]
+pub(crate) const NATPMP_MIN_WAIT: u64 = 250;
+
+/// NAT-PMP max retry
+#[cfg(feature = "std")]
+pub(crate) const NATPMP_MAX_ATTEMPS: u32 = 9;
+
+/// NAT-PMP max request size in bytes.
+pub(crate) const NATPMP_MAX_REQUEST_LEN: usize = 12;
+
+/// NAT-PMP max response size in bytes.
+pub(crate) const NATPMP_MAX_RESPONSE_LEN: usize = 16;
+
+/// Number of answered requests kept to recognize their late responses.
+#[cfg(feature = "std")]
+const NATPMP_COMPLETED_REQUESTS: usize = 8;
+
+/// NAT-PMP request.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let request = Request::Mapping {
+///     protocol: Protocol::UDP,
+///     private_port: 4020,
+///     public_port: 4020,
+///     lifetime: 30,
+/// };
+/// let mut buf = [0u8; 12];
+/// let n = request.encode_into(&mut buf);
+/// assert_eq!(&buf[..n], &[0, 1, 0, 0, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30]);
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Request {
+    /// Public address request.
+    PublicAddress,
+    /// Port mapping request, lifetime in seconds.
+    Mapping {
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    },
+}
+
+impl Request {
+    /// Request to delete the mapping of `private_port`, i.e. a mapping request with public port
+    /// and lifetime 0.
+    pub fn delete_mapping(protocol: Protocol, private_port: u16) -> Request {
+        Request::Mapping {
+            protocol,
+            private_port,
+            public_port: 0,
+            lifetime: 0,
+        }
+    }
+
+    /// Request to delete all mappings of the client for `protocol`, i.e. a deletion request
+    /// with private port 0.
+    pub fn delete_all_mappings(protocol: Protocol) -> Request {
+        Request::delete_mapping(protocol, 0)
+    }
+
+    /// Encoded size of the request in bytes.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Request::PublicAddress => 2,
+            Request::Mapping { .. } => NATPMP_MAX_REQUEST_LEN,
+        }
+    }
+
+    /// Encode the request into `buf`, returns the number of bytes written.
+    ///
+    /// # Panics
+    /// Panics if `buf` is shorter than [`encoded_len`](#method.encoded_len).
+    pub fn encode_into(&self, buf: &mut [u8]) -> usize {
+        let len = self.encoded_len();
+        let buf = &mut buf[..len];
+        buf[0] = 0; // version
+        match *self {
+            Request::PublicAddress => buf[1] = 0,
+            Request::Mapping {
+                protocol,
+                private_port,
+                public_port,
+                lifetime,
+            } => {
+                buf[1] = match protocol {
+                    Protocol::UDP => 1,
+                    _ => 2,
+                };
+                buf[2] = 0; // reserved
+                buf[3] = 0; // reserved
+                buf[4..6].copy_from_slice(&private_port.to_be_bytes());
+                buf[6..8].copy_from_slice(&public_port.to_be_bytes());
+                buf[8..12].copy_from_slice(&lifetime.to_be_bytes());
+            }
+        }
+        len
+    }
+}
+
+/// NAT-PMP result code as defined by rfc6886.
+///
+/// Non-success result codes are reported as [`Error`](enum.Error.html)s, use
+/// [`Error::result_code`](enum.Error.html#method.result_code) to get them back.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// assert_eq!(ResultCode::from(2), ResultCode::NotAuthorized);
+/// assert_eq!(ResultCode::try_from(300), Ok(ResultCode::Other(300)));
+/// assert_eq!(u16::from(ResultCode::OutOfResources), 4);
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ResultCode {
+    Success,
+    UnsupportedVersion,
+    NotAuthorized,
+    NetworkFailure,
+    OutOfResources,
+    UnsupportedOpcode,
+    /// Result code not defined by rfc6886, e.g. a vendor specific one.
+    Other(u16),
+}
+
+impl From<u16> for ResultCode {
+    fn from(code: u16) -> Self {
+        match code {
+            0 => ResultCode::Success,
+            1 => ResultCode::UnsupportedVersion,
+            2 => ResultCode::NotAuthorized,
+            3 => ResultCode::NetworkFailure,
+            4 => ResultCode::OutOfResources,
+            5 => ResultCode::UnsupportedOpcode,
+            _ => ResultCode::Other(code),
+        }
+    }
+}
+
+impl From<ResultCode> for u16 {
+    fn from(code: ResultCode) -> Self {
+        match code {
+            ResultCode::Success => 0,
+            ResultCode::UnsupportedVersion => 1,
+            ResultCode::NotAuthorized => 2,
+            ResultCode::NetworkFailure => 3,
+            ResultCode::OutOfResources => 4,
+            ResultCode::UnsupportedOpcode => 5,
+            ResultCode::Other(code) => code,
+        }
+    }
+}
+
+impl ResultCode {
+    /// Error of a non-success result code.
+    pub fn error(&self) -> Option<Error> {
+        match *self {
+            ResultCode::Success => None,
+            ResultCode::UnsupportedVersion => Some(Error::NATPMP_ERR_UNSUPPORTEDVERSION),
+            ResultCode::NotAuthorized => Some(Error::NATPMP_ERR_NOTAUTHORIZED),
+            ResultCode::NetworkFailure => Some(Error::NATPMP_ERR_NETWORKFAILURE),
+            ResultCode::OutOfResources => Some(Error::NATPMP_ERR_OUTOFRESOURCES),
+            ResultCode::UnsupportedOpcode => Some(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+            ResultCode::Other(code) => Some(Error::NATPMP_ERR_UNKNOWNRESULTCODE(code)),
+        }
+    }
+}
+
+/// PCP result code (rfc6887 section 7.4).
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// assert_eq!(PcpResultCode::from(8), PcpResultCode::NoResources);
+/// assert_eq!(PcpResultCode::from(200), PcpResultCode::Other(200));
+/// assert_eq!(u8::from(PcpResultCode::ExcessiveRemotePeers), 13);
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PcpResultCode {
+    Success,
+    UnsupportedVersion,
+    NotAuthorized,
+    MalformedRequest,
+    UnsupportedOpcode,
+    UnsupportedOption,
+    MalformedOption,
+    NetworkFailure,
+    NoResources,
+    UnsupportedProtocol,
+    UserExceededQuota,
+    CannotProvideExternal,
+    AddressMismatch,
+    ExcessiveRemotePeers,
+    /// Result code not defined by rfc6887.
+    Other(u8),
+}
+
+impl From<u8> for PcpResultCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => PcpResultCode::Success,
+            1 => PcpResultCode::UnsupportedVersion,
+            2 => PcpResultCode::NotAuthorized,
+            3 => PcpResultCode::MalformedRequest,
+            4 => PcpResultCode::UnsupportedOpcode,
+            5 => PcpResultCode::UnsupportedOption,
+            6 => PcpResultCode::MalformedOption,
+            7 => PcpResultCode::NetworkFailure,
+            8 => PcpResultCode::NoResources,
+            9 => PcpResultCode::UnsupportedProtocol,
+            10 => PcpResultCode::UserExceededQuota,
+            11 => PcpResultCode::CannotProvideExternal,
+            12 => PcpResultCode::AddressMismatch,
+            13 => PcpResultCode::ExcessiveRemotePeers,
+            _ => PcpResultCode::Other(code),
+        }
+    }
+}
+
+impl From<PcpResultCode> for u8 {
+    fn from(code: PcpResultCode) -> Self {
+        match code {
+            PcpResultCode::Success => 0,
+            PcpResultCode::UnsupportedVersion => 1,
+            PcpResultCode::NotAuthorized => 2,
+            PcpResultCode::MalformedRequest => 3,
+            PcpResultCode::UnsupportedOpcode => 4,
+            PcpResultCode::UnsupportedOption => 5,
+            PcpResultCode::MalformedOption => 6,
+            PcpResultCode::NetworkFailure => 7,
+            PcpResultCode::NoResources => 8,
+            PcpResultCode::UnsupportedProtocol => 9,
+            PcpResultCode::UserExceededQuota => 10,
+            PcpResultCode::CannotProvideExternal => 11,
+            PcpResultCode::AddressMismatch => 12,
+            PcpResultCode::ExcessiveRemotePeers => 13,
+            PcpResultCode::Other(code) => code,
+        }
+    }
+}
+
+impl PcpResultCode {
+    /// Error of a non-success result code, `lifetime` is the lifetime of the error in seconds.
+    pub fn error(&self, lifetime: u32) -> Option<Error> {
+        match *self {
+            PcpResultCode::Success => None,
+            code => Some(Error::PCP_ERR_RESULTCODE(code, lifetime)),
+        }
+    }
+}
+
+/// NAT-PMP response header size in bytes (version, opcode, result code and epoch).
+const NATPMP_RESPONSE_HEADER_LEN: usize = 8;
+
+/// Parse a NAT-PMP response received at `now`.
+pub(crate) fn parse_response(buf: &[u8], #[cfg(feature = "std")] now: Instant) -> Result<Response> {
+    ResponseRef::parse(buf).map(|r| {
+        r.to_owned(
+            #[cfg(feature = "std")]
+            now,
+        )
+    })
+}
+
+/// Borrowed view of a NAT-PMP response.
+///
+/// The datagram is validated like [`Response::parse`](enum.Response.html#method.parse), but fields
+/// are only decoded from the buffer when accessed.
+///
+/// # Examples
+/// ```
+/// use std::time::Instant;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let buf = [0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0, 30];
+/// let r = ResponseRef::parse(&buf)?;
+/// assert_eq!(r.response_type(), ResponseType::UDP);
+/// assert_eq!(r.public_port(), Some(4021));
+/// assert_eq!(r.public_address(), None);
+/// let response: Response = r.to_owned(Instant::now());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ResponseRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ResponseRef<'a> {
+    /// Validate a NAT-PMP response datagram.
+    ///
+    /// # Errors
+    /// See [`Response::parse`](enum.Response.html#method.parse).
+    pub fn parse(buf: &'a [u8]) -> Result<ResponseRef<'a>> {
+        // an error response may only carry the header
+        if buf.len() < NATPMP_RESPONSE_HEADER_LEN {
+            return Err(Error::NATPMP_ERR_TRUNCATEDPACKET);
+        }
+        // version
+        if buf[0] != 0 {
+            return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
+        }
+        // opcode
+        if buf[1] < 128 || buf[1] > 130 {
+            return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE);
+        }
+        // result code
+        let resultcode = ResultCode::from(u16::from_be_bytes([buf[2], buf[3]]));
+        if let Some(e) = resultcode.error() {
+            return Err(e);
+        }
+        let expected_len = if buf[1] == 128 {
+            12
+        } else {
+            NATPMP_MAX_RESPONSE_LEN
+        };
+        if buf.len() < expected_len {
+            return Err(Error::NATPMP_ERR_TRUNCATEDPACKET);
+        }
+        Ok(ResponseRef {
+            buf: &buf[..expected_len],
+        })
+    }
+
+    /// Bytes of the response.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// Response type.
+    pub fn response_type(&self) -> ResponseType {
+        match self.buf[1] & 0x7f {
+            0 => ResponseType::Gateway,
+            1 => ResponseType::UDP,
+            _ => ResponseType::TCP,
+        }
+    }
+
+    /// Seconds since epoch.
+    pub fn epoch(&self) -> u32 {
+        u32::from_be_bytes([self.buf[4], self.buf[5], self.buf[6], self.buf[7]])
+    }
+
+    /// Public address of a gateway response.
+    pub fn public_address(&self) -> Option<Ipv4Addr> {
+        match self.response_type() {
+            ResponseType::Gateway => Some(Ipv4Addr::new(
+                self.buf[8],
+                self.buf[9],
+                self.buf[10],
+                self.buf[11],
+            )),
+            _ => None,
+        }
+    }
+
+    /// Mapping protocol of a mapping response.
+    pub fn protocol(&self) -> Option<Protocol> {
+        match self.response_type() {
+            ResponseType::Gateway => None,
+            ResponseType::UDP => Some(Protocol::UDP),
+            ResponseType::TCP => Some(Protocol::TCP),
+        }
+    }
+
+    /// Private port of a mapping response.
+    pub fn private_port(&self) -> Option<u16> {
+        self.protocol()
+            .map(|_| u16::from_be_bytes([self.buf[8], self.buf[9]]))
+    }
+
+    /// Public port of a mapping response.
+    pub fn public_port(&self) -> Option<u16> {
+        self.protocol()
+            .map(|_| u16::from_be_bytes([self.buf[10], self.buf[11]]))
+    }
+
+    /// Lifetime of a mapping response.
+    pub fn lifetime(&self) -> Option<Duration> {
+        self.protocol().map(|_| {
+            let lifetime =
+                u32::from_be_bytes([self.buf[12], self.buf[13], self.buf[14], self.buf[15]]);
+            Duration::from_secs(lifetime.into())
+        })
+    }
+
+    /// Decode into an owned response, received at `now` with the `std` feature.
+    pub fn to_owned(&self, #[cfg(feature = "std")] now: Instant) -> Response {
+        let buf = self.buf;
+        let epoch = self.epoch();
+        let protocol = match self.response_type() {
+            ResponseType::Gateway => {
+                return Response::Gateway(GatewayResponse {
+                    epoch,
+                    public_address: Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11]),
+                })
+            }
+            ResponseType::UDP => Protocol::UDP,
+            ResponseType::TCP => Protocol::TCP,
+        };
+        let lifetime = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+        let m = MappingResponse {
+            epoch,
+            protocol,
+            private_port: u16::from_be_bytes([buf[8], buf[9]]),
+            public_port: u16::from_be_bytes([buf[10], buf[11]]),
+            lifetime: Duration::from_secs(lifetime.into()),
+            #[cfg(feature = "std")]
+            received_at: now,
+        };
+        match protocol {
+            Protocol::UDP => Response::UDP(m),
+            Protocol::TCP => Response::TCP(m),
+        }
+    }
+}
+
+/// Whether a gateway epoch is consistent with a previous one, as described in rfc6886 section 3.6.
+///
+/// `elapsed` is the time passed on the client clock between both responses. The client
+/// conservatively expects the gateway clock to advance at least 7/8 of it, an epoch more than 2
+/// seconds behind that estimate means the gateway rebooted or otherwise lost its mapping table.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// assert!(epoch_is_consistent(1000, 1060, Duration::from_secs(60)));
+/// assert!(epoch_is_consistent(1000, 1053, Duration::from_secs(60)));
+/// assert!(!epoch_is_consistent(1000, 1030, Duration::from_secs(60)));
+/// assert!(!epoch_is_consistent(1000, 5, Duration::from_secs(1)));
+/// ```
+pub fn epoch_is_consistent(previous: u32, epoch: u32, elapsed: Duration) -> bool {
+    let estimate = u128::from(previous) * 1000 + elapsed.as_millis() * 7 / 8; // ms
+    (u128::from(epoch) + 2) * 1000 >= estimate
+}
+
+/// Gateway epoch tracker, detects gateway reboots as described in rfc6886 section 3.6.
+///
+/// Each epoch is stored with the local `Instant` it was received at, a following epoch must
+/// have advanced with the local clock (see [`epoch_is_consistent`](fn.epoch_is_consistent.html)),
+/// otherwise the gateway lost its mappings and they must be recreated.
+///
+/// # Examples
+/// ```
+/// use std::time::{Duration, Instant};
+/// use natpmp::*;
+///
+/// let now = Instant::now();
+/// let mut tracker = EpochTracker::new();
+/// assert_eq!(tracker.update(1000, now), false);
+/// assert_eq!(tracker.update(1030, now + Duration::from_secs(30)), false);
+/// assert_eq!(tracker.update(1035, now + Duration::from_secs(60)), true);
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct EpochTracker {
+    last: Option<(u32, Instant)>,
+    previous: Option<(u32, Instant)>,
+    rule: EpochRule,
+}
+
+/// Epoch validity rule of the protocol.
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+enum EpochRule {
+    #[default]
+    Natpmp,
+    Pcp,
+}
+
+#[cfg(feature = "std")]
+impl EpochTracker {
+    /// Create an epoch tracker without any epoch seen.
+    pub fn new() -> EpochTracker {
+        EpochTracker::default()
+    }
+
+    /// Create an epoch tracker of a PCP server, epochs are checked with
+    /// [`pcp_epoch_is_valid`](fn.pcp_epoch_is_valid.html) instead.
+    pub fn new_pcp() -> EpochTracker {
+        EpochTracker {
+            rule: EpochRule::Pcp,
+            ..EpochTracker::default()
+        }
+    }
+
+    fn is_consistent(&self, previous: u32, epoch: u32, elapsed: Duration) -> bool {
+        match self.rule {
+            EpochRule::Natpmp => epoch_is_consistent(previous, epoch, elapsed),
+            EpochRule::Pcp => pcp_epoch_is_valid(previous, epoch, elapsed),
+        }
+    }
+
+    /// Epoch of the last response and the `Instant` it was received at.
+    pub fn last_epoch(&self) -> Option<(u32, Instant)> {
+        self.last
+    }
+
+    /// Record the epoch of a new response received at `now`, returns whether the gateway
+    /// mapping table should be considered lost.
+    pub fn update(&mut self, epoch: u32, now: Instant) -> bool {
+        self.previous = self.last;
+        self.last = Some((epoch, now));
+        self.indicates_reboot(epoch)
+    }
+
+    /// Whether `epoch` indicates a reboot.
+    ///
+    /// The epoch of the last response is checked against the response before it, any other
+    /// epoch against the last response without elapsed time.
+    pub fn indicates_reboot(&self, epoch: u32) -> bool {
+        match (self.previous, self.last) {
+            (Some((previous, previous_at)), Some((last, last_at))) if last == epoch => {
+                !self.is_consistent(previous, epoch, last_at - previous_at)
+            }
+            (_, Some((last, _))) if last != epoch => {
+                !self.is_consistent(last, epoch, Duration::ZERO)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Filter of retransmitted public address announcements.
+///
+/// Gateways announce a public address change up to 10 times (rfc6886 section 3.2.1), each
+/// retransmission with an advanced epoch. An announcement is a repeat when it carries the
+/// same public address as the previous one, arrives within the window and its epoch is
+/// consistent with it, so only actual changes get through.
+///
+/// # Examples
+/// ```
+/// use std::time::{Duration, Instant};
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let now = Instant::now();
+/// let mut filter = AnnouncementFilter::new();
+/// let first = [0, 128, 0, 0, 0, 0, 0x03, 0xe8, 10, 0, 0, 1];
+/// let repeat = [0, 128, 0, 0, 0, 0, 0x03, 0xe9, 10, 0, 0, 1];
+/// let later = now + Duration::from_secs(1);
+/// if let Response::Gateway(gr) = Response::parse(&first)? {
+///     assert_eq!(filter.is_new(&gr, now), true);
+/// }
+/// if let Response::Gateway(gr) = Response::parse(&repeat)? {
+///     assert_eq!(filter.is_new(&gr, later), false);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AnnouncementFilter {
+    last: Option<(GatewayResponse, Instant)>,
+    window: Duration,
+}
+
+#[cfg(feature = "std")]
+impl Default for AnnouncementFilter {
+    fn default() -> Self {
+        AnnouncementFilter::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl AnnouncementFilter {
+    /// Default window, long enough for the 10 retransmissions of an announcement.
+    pub const DEFAULT_WINDOW: Duration = Duration::from_millis(NATPMP_MIN_WAIT * 1023);
+
+    /// Create a filter with the [`DEFAULT_WINDOW`](#associatedconstant.DEFAULT_WINDOW).
+    pub fn new() -> AnnouncementFilter {
+        AnnouncementFilter::with_window(AnnouncementFilter::DEFAULT_WINDOW)
+    }
+
+    /// Create a filter treating announcements as repeats for at most `window`.
+    pub fn with_window(window: Duration) -> AnnouncementFilter {
+        AnnouncementFilter { last: None, window }
+    }
+
+    /// The last announcement seen and the `Instant` it was received at.
+    pub fn last(&self) -> Option<(GatewayResponse, Instant)> {
+        self.last
+    }
+
+    /// Record `response` received at `now`, returns whether it is an actual change rather
+    /// than a repeat of the previous announcement.
+    pub fn is_new(&mut self, response: &GatewayResponse, now: Instant) -> bool {
+        let repeat = match self.last {
+            Some((last, at)) => {
+                let elapsed = now.saturating_duration_since(at);
+                last.public_address == response.public_address
+                    && elapsed <= self.window
+                    && epoch_is_consistent(last.epoch, response.epoch, elapsed)
+            }
+            None => false,
+        };
+        self.last = Some((*response, now));
+        !repeat
+    }
+}
+
+/// Retry policy of the requests.
+///
+/// A request is sent up to `attempts` times, the first retry after `initial_delay`, then
+/// doubling the delay up to `max_delay`. A random delay up to `jitter` is added to each one so
+/// that many clients do not retry in lockstep.
+///
+/// The default policy is the one of rfc6886: 9 attempts, the first retry after 250
+/// milli-seconds, giving up after about 64 seconds. Interactive applications may prefer to fail
+/// sooner.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// let policy = RetryPolicy {
+///     attempts: 4,
+///     initial_delay: Duration::from_millis(250),
+///     ..RetryPolicy::default()
+/// };
+/// assert_eq!(policy.max_wait(), Duration::from_millis(250 + 500 + 1000 + 2000));
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RetryPolicy {
+    /// Number of times a request is sent, at least 1.
+    pub attempts: u32,
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// Maximum delay between two attempts.
+    pub max_delay: Duration,
+    /// Maximum random delay added to each delay.
+    pub jitter: Duration,
+}
+
+#[cfg(feature = "std")]
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: NATPMP_MAX_ATTEMPS,
+            initial_delay: Duration::from_millis(NATPMP_MIN_WAIT),
+            max_delay: Duration::from_millis(NATPMP_MIN_WAIT << (NATPMP_MAX_ATTEMPS - 1)),
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl RetryPolicy {
+    /// Delay after the attempt `try_number` (from 1) without jitter.
+    pub(crate) fn delay(&self, try_number: u32) -> Duration {
+        let factor = 1u32.checked_shl(try_number - 1).unwrap_or(u32::MAX);
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+
+    /// Longest time until a request is given up, jitter aside.
+    pub fn max_wait(&self) -> Duration {
+        (1..=self.attempts).fold(Duration::ZERO, |total, try_number| {
+            total.saturating_add(self.delay(try_number))
+        })
+    }
+
+    /// Single attempt waiting `timeout` for the response.
+    pub(crate) fn single(timeout: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            initial_delay: timeout,
+            max_delay: timeout,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Whether the policy sends requests at all and waits between attempts.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.attempts > 0 && !self.initial_delay.is_zero() && !self.max_delay.is_zero()
+    }
+
+    /// Delay after the attempt `try_number` (from 1).
+    fn jittered_delay(&self, try_number: u32) -> Duration {
+        let delay = self.delay(try_number);
+        if self.jitter.is_zero() {
+            return delay;
+        }
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(try_number);
+        let max = u64::try_from(self.jitter.as_nanos()).unwrap_or(u64::MAX);
+        delay.saturating_add(Duration::from_nanos(
+            hasher.finish() % max.saturating_add(1),
+        ))
+    }
+}
+
+/// Retransmission schedule of a pending request, see [`RetryPolicy`](struct.RetryPolicy.html).
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Retransmission {
+    policy: RetryPolicy,
+    try_number: u32,
+    retry_time: Instant,
+}
+
+#[cfg(feature = "std")]
+impl Retransmission {
+    /// Schedule following `policy` for a request first sent at `now`.
+    pub(crate) fn new(policy: RetryPolicy, now: Instant) -> Retransmission {
+        Retransmission {
+            policy,
+            try_number: 1,
+            retry_time: now + policy.jittered_delay(1),
+        }
+    }
+
+    /// Number of times the request was sent so far.
+    pub(crate) fn attempts_made(&self) -> u32 {
+        self.try_number
+    }
+
+    /// Time of the next retransmission, or of giving up after the last attempt.
+    pub(crate) fn retry_time(&self) -> Instant {
+        self.retry_time
+    }
+
+    /// Duration until the next retransmission.
+    pub(crate) fn timeout(&self, now: Instant) -> Duration {
+        self.retry_time.saturating_duration_since(now)
+    }
+
+    /// Advance the schedule, returns whether to retransmit now.
+    pub(crate) fn handle_timeout(&mut self, now: Instant) -> Result<bool> {
+        // time to retry or not
+        if now < self.retry_time {
+            return Ok(false);
+        }
+        if self.try_number >= self.policy.attempts {
+            return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT);
+        }
+        self.try_number += 1;
+        let delay = self.policy.jittered_delay(self.try_number);
+        self.retry_time = self
+            .retry_time
+            .checked_add(delay)
+            .unwrap_or(self.retry_time); // next time
+        Ok(true)
+    }
+}
+
+/// Token of a request made pending by [`NatpmpProto::send_tracked_request`](struct.NatpmpProto.html#method.send_tracked_request),
+/// to route its response to the caller waiting for it.
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RequestToken(u64);
+
+/// NAT-PMP protocol state machine.
+///
+/// It keeps the pending requests and their retry schedules, but never touches a socket: the
+/// caller transmits the bytes it hands out, feeds received datagrams into
+/// [`handle_datagram`](#method.handle_datagram) and calls
+/// [`handle_timeout`](#method.handle_timeout) when [`timeout`](#method.timeout) expires.
+///
+/// Several requests may be pending at once, e.g. TCP and UDP mappings of the same port, each
+/// with its own retry schedule. A request is identified by its opcode and, for mappings, its
+/// private port: sending a request with the same opcode and port replaces the pending one.
+///
+/// The last answered requests are remembered, so that the late responses to their
+/// retransmissions are discarded instead of answering the next request.
+///
+/// # Examples
+/// ```
+/// use std::net::Ipv4Addr;
+/// use std::time::Instant;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let now = Instant::now();
+/// let mut proto = NatpmpProto::new();
+/// let request = proto.send_public_address_request(now);
+/// assert_eq!(request, &[0, 0]);
+/// // transmit the request, then feed the reply
+/// let reply = [0, 128, 0, 0, 0, 0, 0, 1, 10, 0, 0, 1, 0, 0, 0, 0];
+/// match proto.handle_datagram(&reply, now)? {
+///     Response::Gateway(gr) => assert_eq!(gr.public_address(), &Ipv4Addr::new(10, 0, 0, 1)),
+///     _ => panic!("Not a gateway response"),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct NatpmpProto {
+    pending: Vec<PendingRequest>,
+    completed: Vec<CompletedRequest>,
+    next_token: u64,
+    policy: RetryPolicy,
+    epoch: EpochTracker,
+}
+
+/// Request sent to the gateway and waiting for its response.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+struct PendingRequest {
+    token: RequestToken,
+    kind: Request,
+    request: [u8; NATPMP_MAX_REQUEST_LEN],
+    len: usize,
+    retry: Retransmission,
+    sent_at: Instant,
+}
+
+#[cfg(feature = "std")]
+impl PendingRequest {
+    fn bytes(&self) -> &[u8] {
+        &self.request[..self.len]
+    }
+}
+
+/// Request answered by the gateway.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+struct CompletedRequest {
+    request: [u8; NATPMP_MAX_REQUEST_LEN],
+    response: [u8; NATPMP_MAX_RESPONSE_LEN],
+    len: usize,
+    epoch: u32,
+    sent_at: Instant,
+    answered_at: Instant,
+}
+
+#[cfg(feature = "std")]
+impl CompletedRequest {
+    /// Whether the response `buf` repeats the answer, apart from its epoch.
+    fn is_repeated_by(&self, buf: &[u8]) -> bool {
+        let response = &self.response[..self.len];
+        buf.len() == response.len()
+            && buf.len() >= 8
+            && buf[..4] == response[..4]
+            && buf[8..] == response[8..]
+    }
+
+    /// Whether a response with `epoch` answering the request too was sent by the gateway
+    /// before a new request first sent at `sent_at`, i.e. it answers a retransmission.
+    fn is_older(&self, epoch: u32, sent_at: Instant) -> bool {
+        let epoch = u64::from(epoch);
+        let answered = u64::from(self.epoch);
+        // seconds of the gateway clock, one more for the rounding
+        let idle = sent_at
+            .saturating_duration_since(self.answered_at)
+            .as_secs();
+        let span = self
+            .answered_at
+            .saturating_duration_since(self.sent_at)
+            .as_secs();
+        // an epoch far behind is a reboot, not a retransmission
+        epoch + 1 < answered + idle && epoch + span + 1 >= answered
+    }
+}
+
+/// Epoch of the response `buf`, even an error one.
+#[cfg(feature = "std")]
+fn response_epoch(buf: &[u8]) -> Option<u32> {
+    let epoch = buf.get(4..8)?;
+    Some(u32::from_be_bytes([epoch[0], epoch[1], epoch[2], epoch[3]]))
+}
+
+/// Whether `other` has the same opcode and private port as `request`.
+#[cfg(feature = "std")]
+fn is_same_request(request: &[u8], other: &[u8]) -> bool {
+    request[1] == other[1] && (request[1] == 0 || request[4..6] == other[4..6])
+}
+
+/// Whether the response `buf` may answer `request`.
+#[cfg(any(feature = "std", feature = "embedded-nal-async"))]
+pub(crate) fn is_answered_by(request: &[u8], buf: &[u8]) -> bool {
+    // response opcode is the request opcode + 128
+    if buf.len() >= 2 && buf[1] != request[1] | 0x80 {
+        return false;
+    }
+    // private port of a mapping response, short error responses may omit it
+    !(request[1] != 0 && buf.len() >= 10 && buf[8..10] != request[4..6])
+}
+
+#[cfg(feature = "std")]
+impl Default for NatpmpProto {
+    fn default() -> Self {
+        NatpmpProto::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl NatpmpProto {
+    /// Create a protocol state machine without pending request.
+    pub fn new() -> NatpmpProto {
+        NatpmpProto {
+            pending: Vec::new(),
+            completed: Vec::new(),
+            next_token: 0,
+            policy: RetryPolicy::default(),
+            epoch: EpochTracker::new(),
+        }
+    }
+
+    /// Retry policy of the requests.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Set the retry policy of the following requests.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Whether a request is waiting for its response.
+    pub fn has_pending_request(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Bytes of the most recent request waiting for its response, if any.
+    pub fn pending_request(&self) -> Option<&[u8]> {
+        self.last_pending().map(PendingRequest::bytes)
+    }
+
+    /// Bytes of the requests waiting for their response, oldest first.
+    pub fn pending_requests(&self) -> impl Iterator<Item = &[u8]> {
+        self.pending.iter().map(PendingRequest::bytes)
+    }
+
+    /// Most recent request waiting for its response, if any.
+    pub fn pending_request_type(&self) -> Option<Request> {
+        self.last_pending().map(|p| p.kind)
+    }
+
+    /// Number of times the most recent request waiting for its response was sent so far,
+    /// counting the first transmission.
+    pub fn attempts_made(&self) -> Option<u32> {
+        self.last_pending().map(|p| p.retry.attempts_made())
+    }
+
+    /// Time of the next retransmission of the pending requests, or of giving up on them once
+    /// their attempts are exhausted.
+    pub fn next_retry_at(&self) -> Option<Instant> {
+        self.pending.iter().map(|p| p.retry.retry_time()).min()
+    }
+
+    fn last_pending(&self) -> Option<&PendingRequest> {
+        self.pending.last()
+    }
+
+    /// Whether the request of `token` is waiting for its response.
+    pub fn is_pending(&self, token: RequestToken) -> bool {
+        self.pending.iter().any(|p| p.token == token)
+    }
+
+    /// Abandon the pending requests, their responses are discarded with
+    /// [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ).
+    pub fn cancel_pending_request(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Abandon the request of `token`, returns whether it was waiting for its response.
+    pub fn cancel_request(&mut self, token: RequestToken) -> bool {
+        let pending = self.is_pending(token);
+        self.pending.retain(|p| p.token != token);
+        pending
+    }
+
+    /// Whether `buf` is a late response to an answered request, e.g. the response to a
+    /// retransmission, that [`handle_datagram`](#method.handle_datagram) discards.
+    ///
+    /// Such a response is matched against a pending request with the same opcode and private
+    /// port only if its epoch shows that the gateway sent it after that request.
+    pub fn is_late_response(&self, buf: &[u8]) -> bool {
+        let epoch = match response_epoch(buf) {
+            Some(epoch) => epoch,
+            None => return false,
+        };
+        self.completed
+            .iter()
+            .filter(|c| is_answered_by(&c.request, buf))
+            // an announcement of a new public address is not late
+            .filter(|c| c.request[1] != 0 || c.is_repeated_by(buf))
+            .any(|c| {
+                match self
+                    .pending
+                    .iter()
+                    .find(|p| is_answered_by(&p.request, buf))
+                {
+                    Some(p) => c.is_older(epoch, p.sent_at),
+                    None => true,
+                }
+            })
+    }
+
+    /// Make a request pending, returns the bytes to send.
+    ///
+    /// It replaces a pending request with the same opcode and private port, other pending
+    /// requests are kept.
+    pub fn send_request(&mut self, request: Request, now: Instant) -> &[u8] {
+        self.send_tracked_request(request, now).1
+    }
+
+    /// Make a request pending, returns its token and the bytes to send, see
+    /// [`demux_datagram`](#method.demux_datagram).
+    pub fn send_tracked_request(
+        &mut self,
+        request: Request,
+        now: Instant,
+    ) -> (RequestToken, &[u8]) {
+        self.send_tracked_request_with_policy(request, self.policy, now)
+    }
+
+    /// Make a request pending like [`send_tracked_request`](#method.send_tracked_request), but
+    /// retransmitted following `policy` instead of the retry policy of the requests.
+    pub fn send_tracked_request_with_policy(
+        &mut self,
+        request: Request,
+        policy: RetryPolicy,
+        now: Instant,
+    ) -> (RequestToken, &[u8]) {
+        let token = RequestToken(self.next_token);
+        self.next_token += 1;
+        let mut pending = PendingRequest {
+            token,
+            kind: request,
+            request: [0u8; NATPMP_MAX_REQUEST_LEN],
+            len: 0,
+            retry: Retransmission::new(policy, now),
+            sent_at: now,
+        };
+        pending.len = request.encode_into(&mut pending.request);
+        self.pending
+            .retain(|p| !is_same_request(&p.request, &pending.request));
+        self.pending.push(pending);
+        (token, self.pending[self.pending.len() - 1].bytes())
+    }
+
+    /// Make a public address request pending, returns the bytes to send.
+    pub fn send_public_address_request(&mut self, now: Instant) -> &[u8] {
+        self.send_request(Request::PublicAddress, now)
+    }
+
+    /// Make a port mapping request pending, returns the bytes to send.
+    pub fn send_port_mapping_request(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+        now: Instant,
+    ) -> &[u8] {
+        let request = Request::Mapping {
+            protocol,
+            private_port,
+            public_port: public_port.into().into(),
+            lifetime: lifetime.into().as_secs(),
+        };
+        self.send_request(request, now)
+    }
+
+    /// Make a port mapping deletion request pending, returns the bytes to send.
+    pub fn send_port_mapping_delete(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        now: Instant,
+    ) -> &[u8] {
+        self.send_request(Request::delete_mapping(protocol, private_port), now)
+    }
+
+    /// Make a request deleting all mappings of the client pending, returns the bytes to send.
+    pub fn send_delete_all_mappings(&mut self, protocol: Protocol, now: Instant) -> &[u8] {
+        self.send_request(Request::delete_all_mappings(protocol), now)
+    }
+
+    /// Duration until the first pending request must be retransmitted.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    pub fn timeout(&self, now: Instant) -> Result<Duration> {
+        self.pending
+            .iter()
+            .map(|p| p.retry.timeout(now))
+            .min()
+            .ok_or(Error::NATPMP_ERR_NOPENDINGREQ)
+    }
+
+    /// Advance the retry schedules, returns the bytes of a request to retransmit when it is
+    /// time to.
+    ///
+    /// With several pending requests, call it again until it returns `None` to get all the
+    /// requests to retransmit. A request whose attempts are exhausted is dropped.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<Option<&[u8]>> {
+        if !self.has_pending_request() {
+            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+        }
+        match self.handle_request_timeout(now) {
+            Some((_, result)) => result.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Advance the retry schedules like [`handle_timeout`](#method.handle_timeout), returns
+    /// the token of a request to retransmit with its bytes, or with
+    /// [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+    /// once its attempts are exhausted.
+    pub fn handle_request_timeout(
+        &mut self,
+        now: Instant,
+    ) -> Option<(RequestToken, Result<&[u8]>)> {
+        for i in 0..self.pending.len() {
+            match self.pending[i].retry.handle_timeout(now) {
+                Ok(false) => {}
+                Ok(true) => return Some((self.pending[i].token, Ok(self.pending[i].bytes()))),
+                Err(e) => return Some((self.pending.remove(i).token, Err(e))),
+            }
+        }
+        None
+    }
+
+    /// Handle a datagram received from the gateway at `now`.
+    ///
+    /// Responses answering none of the pending requests, because of their opcode (e.g. a
+    /// stale TCP mapping response while waiting for a UDP one) or, for mappings, their private
+    /// port, and late responses (see [`is_late_response`](#method.is_late_response)) are
+    /// discarded with `NATPMP_TRYAGAIN`. The answered request stops being retransmitted.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN)
+    /// * See [`Natpmp::read_response_or_retry`](struct.Natpmp.html#method.read_response_or_retry)
+    pub fn handle_datagram(&mut self, buf: &[u8], now: Instant) -> Result<Response> {
+        if self.is_late_response(buf) {
+            return Err(Error::NATPMP_TRYAGAIN);
+        }
+        if self.pending.is_empty() {
+            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+        }
+        let index = self
+            .pending
+            .iter()
+            .position(|p| is_answered_by(&p.request, buf))
+            .ok_or(Error::NATPMP_TRYAGAIN)?;
+        self.answer(index, buf, now)
+    }
+
+    /// Route a datagram received from the gateway at `now` to the request waiting for it,
+    /// returns the token of the request with its response.
+    ///
+    /// `None` is returned if the datagram answers no request waiting for its response, e.g.
+    /// an unsolicited announcement or a late response of an answered request.
+    pub fn demux_datagram(
+        &mut self,
+        buf: &[u8],
+        now: Instant,
+    ) -> Option<(RequestToken, Result<Response>)> {
+        if self.is_late_response(buf) {
+            return None;
+        }
+        let index = self
+            .pending
+            .iter()
+            .position(|p| is_answered_by(&p.request, buf))?;
+        let token = self.pending[index].token;
+        Some((token, self.answer(index, buf, now)))
+    }
+
+    fn answer(&mut self, index: usize, buf: &[u8], now: Instant) -> Result<Response> {
+        let response = match parse_response(buf, now) {
+            Err(Error::NATPMP_ERR_TRUNCATEDPACKET) => {
+                return Err(Error::NATPMP_ERR_TRUNCATEDPACKET)
+            }
+            result => {
+                // the gateway answered, even with an error
+                self.complete(index, buf, now);
+                result?
+            }
+        };
+        self.epoch.update(response.epoch(), now);
+        Ok(response)
+    }
+
+    fn complete(&mut self, index: usize, buf: &[u8], now: Instant) {
+        let pending = self.pending.remove(index);
+        self.completed
+            .retain(|c| !is_same_request(&c.request, &pending.request));
+        if self.completed.len() == NATPMP_COMPLETED_REQUESTS {
+            self.completed.remove(0);
+        }
+        let mut response = [0u8; NATPMP_MAX_RESPONSE_LEN];
+        let len = buf.len().min(NATPMP_MAX_RESPONSE_LEN);
+        response[..len].copy_from_slice(&buf[..len]);
+        self.completed.push(CompletedRequest {
+            request: pending.request,
+            response,
+            len,
+            epoch: response_epoch(buf).unwrap_or(0),
+            sent_at: pending.sent_at,
+            answered_at: now,
+        });
+    }
+
+    /// Epoch tracker of the received responses.
+    pub fn epoch_tracker(&self) -> &EpochTracker {
+        &self.epoch
+    }
+
+    /// Whether the epoch of `response` indicates that the gateway rebooted and lost its
+    /// mappings, see [`EpochTracker::indicates_reboot`](struct.EpochTracker.html#method.indicates_reboot).
+    pub fn epoch_indicates_reboot(&self, response: &Response) -> bool {
+        self.epoch.indicates_reboot(response.epoch())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request() {
+        let mut buf = [0xffu8; NATPMP_MAX_REQUEST_LEN];
+        let n = Request::PublicAddress.encode_into(&mut buf);
+        assert_eq!(&buf[..n], &[0, 0]);
+
+        let request = Request::Mapping {
+            protocol: Protocol::TCP,
+            private_port: 4020,
+            public_port: 4021,
+            lifetime: 7200,
+        };
+        let n = request.encode_into(&mut buf);
+        assert_eq!(
+            &buf[..n],
+            &[0, 2, 0, 0, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0x1c, 0x20]
+        );
+    }
+
+    #[test]
+    fn test_public_port_any() {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        let request =
+            proto.send_port_mapping_request(Protocol::UDP, 4020, PublicPort::Any, 30, now);
+        assert_eq!(request, &[0, 1, 0, 0, 0x0f, 0xb4, 0, 0, 0, 0, 0, 30]);
+        assert_eq!(PublicPort::from(0), PublicPort::Any);
+        assert_eq!(PublicPort::from(4020), PublicPort::Port(4020));
+    }
+
+    #[test]
+    fn test_delete_mapping() -> Result<()> {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        let request = proto.send_port_mapping_delete(Protocol::TCP, 4020, now);
+        assert_eq!(request, &[0, 2, 0, 0, 0x0f, 0xb4, 0, 0, 0, 0, 0, 0]);
+        let reply = [0, 130, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0, 0, 0, 0, 0, 0];
+        match proto.handle_datagram(&reply, now)? {
+            Response::TCP(tr) => assert!(tr.is_deleted() && !tr.is_all_deleted()),
+            _ => panic!("Not a tcp mapping response"),
+        }
+
+        let request = proto.send_delete_all_mappings(Protocol::UDP, now);
+        assert_eq!(request, &[0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let reply = [0, 129, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 0];
+        match proto.handle_datagram(&reply, now)? {
+            Response::UDP(ur) => assert!(ur.is_all_deleted()),
+            _ => panic!("Not a udp mapping response"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapping_response_eq() -> Result<()> {
+        let reply = [
+            0, 130, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0, 30,
+        ];
+        let first = Response::parse(&reply)?;
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(Response::parse(&reply)?, first);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_schedule() {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        assert_eq!(proto.timeout(now), Err(Error::NATPMP_ERR_NOPENDINGREQ));
+        proto.send_public_address_request(now);
+        assert_eq!(proto.timeout(now), Ok(Duration::from_millis(250)));
+        assert_eq!(proto.handle_timeout(now), Ok(None));
+
+        let mut t = now;
+        for i in 1..NATPMP_MAX_ATTEMPS {
+            t += Duration::from_millis(NATPMP_MIN_WAIT * (1 << (i - 1)));
+            assert_eq!(proto.handle_timeout(t), Ok(Some(&[0u8, 0][..])));
+        }
+        t += Duration::from_millis(NATPMP_MIN_WAIT * (1 << (NATPMP_MAX_ATTEMPS - 1)));
+        assert_eq!(
+            proto.handle_timeout(t),
+            Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+        );
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        proto.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(150),
+            jitter: Duration::ZERO,
+        });
+        proto.send_public_address_request(now);
+        let mut t = now + Duration::from_millis(100);
+        assert_eq!(proto.handle_timeout(t), Ok(Some(&[0u8, 0][..])));
+        assert_eq!(proto.timeout(t), Ok(Duration::from_millis(150)));
+        t += Duration::from_millis(150);
+        assert_eq!(proto.handle_timeout(t), Ok(Some(&[0u8, 0][..])));
+        t += Duration::from_millis(150);
+        assert_eq!(
+            proto.handle_timeout(t),
+            Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+        );
+
+        proto.set_retry_policy(RetryPolicy {
+            jitter: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        });
+        proto.send_public_address_request(now);
+        let timeout = proto.timeout(now).unwrap();
+        assert!(timeout >= Duration::from_millis(250) && timeout <= Duration::from_millis(260));
+    }
+
+    #[test]
+    fn test_handle_datagram() -> Result<()> {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        let reply = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0, 30,
+        ];
+        assert_eq!(
+            proto.handle_datagram(&reply, now),
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4021, 30, now);
+        match proto.handle_datagram(&reply, now)? {
+            Response::UDP(ur) => {
+                assert_eq!(ur.epoch(), 9);
+                assert_eq!(ur.protocol(), Protocol::UDP);
+                assert_eq!(ur.private_port(), 4020);
+                assert_eq!(ur.public_port(), 4021);
+                assert_eq!(ur.lifetime(), &Duration::from_secs(30));
+                assert_eq!(ur.expires_at(), now + Duration::from_secs(30));
+            }
+            _ => panic!("Not a udp mapping response"),
+        }
+
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4021, 30, now);
+        let reply = [0, 129, 0, 2, 0, 0, 0, 9, 0x0f, 0xb4, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            proto.handle_datagram(&reply, now),
+            Err(Error::NATPMP_ERR_NOTAUTHORIZED)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_opcode_mismatch() -> Result<()> {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30, now);
+        let tcp_reply = [
+            0, 130, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+        ];
+        assert_eq!(
+            proto.handle_datagram(&tcp_reply, now),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        let tcp_error = [0, 130, 0, 3, 0, 0, 0, 9];
+        assert_eq!(
+            proto.handle_datagram(&tcp_error, now),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        let udp_reply = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+        ];
+        assert!(matches!(
+            proto.handle_datagram(&udp_reply, now)?,
+            Response::UDP(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_private_port_mismatch() -> Result<()> {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30, now);
+        let other_reply = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb5, 0x0f, 0xb5, 0, 0, 0, 30,
+        ];
+        assert_eq!(
+            proto.handle_datagram(&other_reply, now),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        let short_error = [0, 129, 0, 4, 0, 0, 0, 9];
+        assert_eq!(
+            proto.handle_datagram(&short_error, now),
+            Err(Error::NATPMP_ERR_OUTOFRESOURCES)
+        );
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30, now);
+        let reply = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0, 30,
+        ];
+        assert!(matches!(
+            proto.handle_datagram(&reply, now)?,
+            Response::UDP(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_pending() -> Result<()> {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        proto.send_port_mapping_request(Protocol::TCP, 4020, 4020, 30, now);
+        let later = now + Duration::from_millis(100);
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30, later);
+        proto.send_port_mapping_request(Protocol::UDP, 4021, 4021, 30, later);
+        // same opcode and private port, replaced
+        proto.send_port_mapping_request(Protocol::UDP, 4021, 4022, 30, later);
+        assert_eq!(proto.pending_requests().count(), 3);
+        assert_eq!(
+            proto.pending_request(),
+            Some(&[0, 1, 0, 0, 0x0f, 0xb5, 0x0f, 0xb6, 0, 0, 0, 30][..])
+        );
+
+        // independent schedules
+        assert_eq!(proto.timeout(later), Ok(Duration::from_millis(150)));
+        let t = now + Duration::from_millis(250);
+        assert_eq!(proto.handle_timeout(t)?.map(|r| r[1]), Some(2));
+        assert_eq!(proto.handle_timeout(t), Ok(None));
+        let t = later + Duration::from_millis(250);
+        assert_eq!(proto.handle_timeout(t)?.map(|r| r[5]), Some(0xb4));
+        assert_eq!(proto.handle_timeout(t)?.map(|r| r[5]), Some(0xb5));
+        assert_eq!(proto.handle_timeout(t), Ok(None));
+
+        let udp_reply = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+        ];
+        assert!(matches!(
+            proto.handle_datagram(&udp_reply, t)?,
+            Response::UDP(ur) if ur.private_port() == 4020
+        ));
+        let tcp_reply = [
+            0, 130, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+        ];
+        assert!(matches!(
+            proto.handle_datagram(&tcp_reply, t)?,
+            Response::TCP(_)
+        ));
+        assert_eq!(proto.pending_requests().count(), 1);
+        // retransmitted response of an answered request
+        assert_eq!(
+            proto.handle_datagram(&udp_reply, t),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_demux_datagram() {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        let (address, _) = proto.send_tracked_request(Request::PublicAddress, now);
+        let (delete, _) =
+            proto.send_tracked_request(Request::delete_mapping(Protocol::TCP, 4020), now);
+        assert!(proto.is_pending(address) && proto.is_pending(delete));
+
+        let reply = [0, 130, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            proto.demux_datagram(&reply, now),
+            Some((token, Ok(Response::TCP(_)))) if token == delete
+        ));
+        assert!(!proto.is_pending(delete));
+        // retransmitted response
+        assert_eq!(proto.demux_datagram(&reply, now), None);
+
+        let error = [0, 128, 0, 3, 0, 0, 0, 9];
+        assert_eq!(
+            proto.demux_datagram(&error, now),
+            Some((address, Err(Error::NATPMP_ERR_NETWORKFAILURE)))
+        );
+    }
+
+    #[test]
+    fn test_late_response() -> Result<()> {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        proto.send_public_address_request(now);
+        let reply = [0, 128, 0, 0, 0, 0, 0x10, 0, 10, 0, 0, 1];
+        proto.handle_datagram(&reply, now)?;
+        // response to a retransmission, one second later
+        let retransmitted = [0, 128, 0, 0, 0, 0, 0x10, 1, 10, 0, 0, 1];
+        assert!(proto.is_late_response(&retransmitted));
+        let announcement = [0, 128, 0, 0, 0, 0, 0x10, 1, 10, 0, 0, 2];
+        assert!(!proto.is_late_response(&announcement));
+        assert_eq!(proto.demux_datagram(&retransmitted, now), None);
+
+        // left in the socket buffer until the next mapping request
+        let later = now + Duration::from_secs(10);
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30, later);
+        assert_eq!(
+            proto.handle_datagram(&retransmitted, later),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+
+        // or the next public address request
+        proto.send_public_address_request(later);
+        assert_eq!(
+            proto.handle_datagram(&retransmitted, later),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        assert!(proto.has_pending_request());
+        let reply = [0, 128, 0, 0, 0, 0, 0x10, 10, 10, 0, 0, 1];
+        assert!(!proto.is_late_response(&reply));
+        assert!(matches!(
+            proto.handle_datagram(&reply, later)?,
+            Response::Gateway(_)
+        ));
+
+        // a rebooted gateway is not late
+        proto.send_public_address_request(later);
+        let rebooted = [0, 128, 0, 0, 0, 0, 0, 3, 10, 0, 0, 1];
+        assert!(proto.handle_datagram(&rebooted, later).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_pending_request() {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        let (token, _) = proto.send_tracked_request(Request::PublicAddress, now);
+        proto.send_port_mapping_request(Protocol::UDP, 4020, 4020, 30, now);
+        assert!(proto.cancel_request(token));
+        assert!(!proto.cancel_request(token));
+        assert!(proto.has_pending_request());
+
+        proto.cancel_pending_request();
+        assert!(!proto.has_pending_request());
+        assert_eq!(proto.timeout(now), Err(Error::NATPMP_ERR_NOPENDINGREQ));
+        let reply = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0x0f, 0xb4, 0x0f, 0xb4, 0, 0, 0, 30,
+        ];
+        assert_eq!(
+            proto.handle_datagram(&reply, now),
+            Err(Error::NATPMP_ERR_NOPENDINGREQ)
+        );
+    }
+
+    #[test]
+    fn test_epoch_indicates_reboot() -> Result<()> {
+        let now = Instant::now();
+        let mut proto = NatpmpProto::new();
+        proto.send_public_address_request(now);
+        let first = proto.handle_datagram(&[0, 128, 0, 0, 0, 0, 0x10, 0, 10, 0, 0, 1], now)?;
+        assert!(!proto.epoch_indicates_reboot(&first));
+        proto.send_public_address_request(now);
+        let second = proto.handle_datagram(&[0, 128, 0, 0, 0, 0, 0x10, 1, 10, 0, 0, 1], now)?;
+        assert!(!proto.epoch_indicates_reboot(&second));
+        proto.send_public_address_request(now);
+        let rebooted = proto.handle_datagram(&[0, 128, 0, 0, 0, 0, 0, 3, 10, 0, 0, 1], now)?;
+        assert!(proto.epoch_indicates_reboot(&rebooted));
+        assert!(!proto.epoch_indicates_reboot(&second));
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch_clock_drift() {
+        let now = Instant::now();
+        let mut tracker = EpochTracker::new();
+        assert!(!tracker.update(100, now));
+        // gateway clock 10% slower than the client's
+        assert!(!tracker.update(190, now + Duration::from_secs(100)));
+        // gateway clock stopped
+        assert!(tracker.update(191, now + Duration::from_secs(200)));
+        assert!(tracker.indicates_reboot(191));
+        assert!(!tracker.indicates_reboot(192));
+        assert!(tracker.indicates_reboot(100));
+    }
+
+    #[test]
+    fn test_announcement_filter() {
+        let now = Instant::now();
+        let announcement = |epoch, last| GatewayResponse {
+            epoch,
+            public_address: Ipv4Addr::new(10, 0, 0, last),
+        };
+        let mut filter = AnnouncementFilter::with_window(Duration::from_secs(60));
+        assert!(filter.is_new(&announcement(100, 1), now));
+        assert!(!filter.is_new(&announcement(100, 1), now));
+        assert!(!filter.is_new(&announcement(110, 1), now + Duration::from_secs(10)));
+        // address changed
+        assert!(filter.is_new(&announcement(111, 2), now + Duration::from_secs(11)));
+        // gateway rebooted
+        assert!(filter.is_new(&announcement(1, 2), now + Duration::from_secs(12)));
+        // out of window
+        assert!(filter.is_new(&announcement(200, 2), now + Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn test_truncated_response() {
+        let reply = [0, 129, 0, 2, 0, 0, 0, 9];
+        assert_eq!(
+            Response::parse(&reply),
+            Err(Error::NATPMP_ERR_NOTAUTHORIZED)
+        );
+        let reply = [0, 129, 0, 0, 0, 0, 0, 9];
+        assert_eq!(
+            Response::parse(&reply),
+            Err(Error::NATPMP_ERR_TRUNCATEDPACKET)
+        );
+        let reply = [0, 128, 0, 0, 0, 0, 0, 9, 10, 0, 0];
+        assert_eq!(
+            Response::parse(&reply),
+            Err(Error::NATPMP_ERR_TRUNCATEDPACKET)
+        );
+        assert_eq!(
+            Response::parse(&[0, 128, 0]),
+            Err(Error::NATPMP_ERR_TRUNCATEDPACKET)
+        );
+    }
+}
@@ -0,0 +1,552 @@
+//! Renewal of NAT-PMP port mappings on a tokio task.
+
+#[cfg(feature = "stream")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "stream")]
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::{
+    AsyncUdpSocket, Error, MappingLifetime, MappingResponse, NatpmpAsync, Protocol, PublicPort,
+    Request, Response, Result,
+};
+
+/// Renewal schedule of a [`MappingRenewer`](struct.MappingRenewer.html).
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// let policy = RenewalPolicy {
+///     renew_percent: 75,
+///     ..RenewalPolicy::default()
+/// };
+/// assert_eq!(policy.initial_backoff, Duration::from_secs(1));
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RenewalPolicy {
+    /// Percentage of the granted lifetime after which a mapping is renewed, from 1 to 100.
+    pub renew_percent: u32,
+    /// Delay before requesting a mapping again after a failure.
+    pub initial_backoff: Duration,
+    /// Maximum delay between two failed requests, the delay doubles after each failure.
+    pub max_backoff: Duration,
+}
+
+impl Default for RenewalPolicy {
+    fn default() -> Self {
+        RenewalPolicy {
+            // rfc6886 section 3.3
+            renew_percent: 50,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RenewalPolicy {
+    fn is_valid(&self) -> bool {
+        (1..=100).contains(&self.renew_percent)
+            && !self.initial_backoff.is_zero()
+            && !self.max_backoff.is_zero()
+    }
+
+    /// Delay before the next request after `failures` consecutive failures.
+    fn backoff(&self, failures: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Delay before renewing a mapping granted for `lifetime`.
+    fn renewal(&self, lifetime: Duration) -> Duration {
+        (lifetime * self.renew_percent / 100).max(self.initial_backoff)
+    }
+}
+
+/// State of a mapping renewed by a [`MappingRenewer`](struct.MappingRenewer.html).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RenewalStatus {
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Private port of the mapping.
+    pub private_port: u16,
+    /// Requested public port, the assigned one is requested again on renewals.
+    pub public_port: PublicPort,
+    /// Requested lifetime.
+    pub lifetime: MappingLifetime,
+    /// Last response of the gateway, `None` until the mapping is first granted.
+    pub mapping: Option<MappingResponse>,
+    /// Error of the last request, `None` once it succeeded.
+    pub last_error: Option<Error>,
+    /// Number of consecutive failed requests.
+    pub failures: u32,
+    /// Time of the next request.
+    pub next_renewal: Instant,
+}
+
+/// Change of a mapping renewed by a [`MappingRenewer`](struct.MappingRenewer.html).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MappingEvent {
+    /// The mapping was granted for the first time, or again after it expired.
+    Created(MappingResponse),
+    /// The mapping was renewed with the same public port.
+    Renewed(MappingResponse),
+    /// The gateway assigned another public port on renewal.
+    PublicPortChanged {
+        previous: MappingResponse,
+        mapping: MappingResponse,
+    },
+    /// The mapping was not renewed within its lifetime, it is requested until granted again.
+    Expired(MappingResponse),
+    /// The epoch of a response shows that the gateway rebooted, all the mappings are requested
+    /// again right away.
+    GatewayRebooted { epoch: u32 },
+    /// A request of the mapping failed, it is retried after a backoff.
+    Error {
+        protocol: Protocol,
+        private_port: u16,
+        error: Error,
+    },
+}
+
+/// Events of a [`MappingRenewer`](struct.MappingRenewer.html), see
+/// [`MappingRenewer::events`](struct.MappingRenewer.html#method.events).
+///
+/// With the `stream` feature it is also a `futures_core::Stream`.
+#[derive(Debug)]
+pub struct MappingEvents {
+    receiver: UnboundedReceiver<MappingEvent>,
+}
+
+impl MappingEvents {
+    /// Wait for the next event, `None` once the renewer is dropped.
+    pub async fn next(&mut self) -> Option<MappingEvent> {
+        self.receiver.recv().await
+    }
+
+    /// Next event if one is queued, without waiting.
+    pub fn try_next(&mut self) -> Option<MappingEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[cfg(feature = "stream")]
+impl futures_core::Stream for MappingEvents {
+    type Item = MappingEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<MappingEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    mappings: Vec<RenewalStatus>,
+    deletions: Vec<(Protocol, u16)>,
+    subscribers: Vec<UnboundedSender<MappingEvent>>,
+}
+
+impl State {
+    fn emit(&mut self, event: MappingEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event).is_ok());
+    }
+}
+
+struct Shared {
+    policy: RenewalPolicy,
+    state: Mutex<State>,
+    wake: Notify,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Next step of the renewal task.
+enum Job {
+    Delete(Protocol, u16),
+    Request(RenewalStatus),
+    Wait(Option<Instant>),
+}
+
+/// Keeps a set of NAT-PMP port mappings alive from a tokio task.
+///
+/// Each mapping is requested when added, then renewed at
+/// [`RenewalPolicy::renew_percent`](struct.RenewalPolicy.html#structfield.renew_percent) of its
+/// granted lifetime. Failed requests are retried with an exponential backoff. Removed mappings
+/// are deleted on the gateway. The task is aborted when the renewer is dropped, the mappings
+/// then expire at the end of their lifetime.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let n = new_tokio_natpmp().await?;
+/// let renewer = MappingRenewer::new(n, RenewalPolicy::default())?;
+/// renewer.add(Protocol::UDP, 4020, 4020, 3600);
+/// // later
+/// for status in renewer.status() {
+///     println!("{:?}", status.mapping);
+/// }
+/// ```
+pub struct MappingRenewer {
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl MappingRenewer {
+    /// Spawn the renewal task with the client `n`, it must be called from a tokio runtime.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), the
+    ///   percentage is not within 1 and 100 or a backoff is zero
+    pub fn new<S>(n: NatpmpAsync<S>, policy: RenewalPolicy) -> Result<MappingRenewer>
+    where
+        S: AsyncUdpSocket + Send + Sync + 'static,
+    {
+        if !policy.is_valid() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        let shared = Arc::new(Shared {
+            policy,
+            state: Mutex::new(State::default()),
+            wake: Notify::new(),
+        });
+        let task = tokio::spawn(run(n, shared.clone()));
+        Ok(MappingRenewer { shared, task })
+    }
+
+    /// Request and keep renewing a mapping of `private_port`, replacing the previous mapping of
+    /// the port if any.
+    pub fn add(
+        &self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: impl Into<PublicPort>,
+        lifetime: impl Into<MappingLifetime>,
+    ) {
+        let status = RenewalStatus {
+            protocol,
+            private_port,
+            public_port: public_port.into(),
+            lifetime: lifetime.into(),
+            mapping: None,
+            last_error: None,
+            failures: 0,
+            next_renewal: Instant::now(),
+        };
+        let mut state = self.shared.state();
+        state
+            .deletions
+            .retain(|deletion| *deletion != (protocol, private_port));
+        match state
+            .mappings
+            .iter_mut()
+            .find(|m| m.protocol == protocol && m.private_port == private_port)
+        {
+            Some(m) => *m = status,
+            None => state.mappings.push(status),
+        }
+        self.shared.wake.notify_one();
+    }
+
+    /// Keep renewing the mapping `mr` already granted for the requested `public_port` and
+    /// `lifetime`, replacing the previous mapping of the port if any.
+    pub(crate) fn add_granted(
+        &self,
+        mr: MappingResponse,
+        public_port: PublicPort,
+        lifetime: MappingLifetime,
+    ) {
+        let status = RenewalStatus {
+            protocol: mr.protocol(),
+            private_port: mr.private_port(),
+            public_port,
+            lifetime,
+            mapping: Some(mr),
+            last_error: None,
+            failures: 0,
+            next_renewal: mr.received_at() + self.shared.policy.renewal(*mr.lifetime()),
+        };
+        let mut state = self.shared.state();
+        match state
+            .mappings
+            .iter_mut()
+            .find(|m| m.protocol == status.protocol && m.private_port == status.private_port)
+        {
+            Some(m) => *m = status,
+            None => state.mappings.push(status),
+        }
+        self.shared.wake.notify_one();
+    }
+
+    /// Stop renewing the mapping of `private_port` and delete it on the gateway, returns whether
+    /// it was renewed.
+    pub fn remove(&self, protocol: Protocol, private_port: u16) -> bool {
+        let mut state = self.shared.state();
+        let len = state.mappings.len();
+        state
+            .mappings
+            .retain(|m| m.protocol != protocol || m.private_port != private_port);
+        if state.mappings.len() == len {
+            return false;
+        }
+        state.deletions.push((protocol, private_port));
+        self.shared.wake.notify_one();
+        true
+    }
+
+    /// Snapshot of the renewed mappings.
+    pub fn status(&self) -> Vec<RenewalStatus> {
+        self.shared.state().mappings.clone()
+    }
+
+    /// Subscribe to the changes of the mappings from now on.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let renewer = MappingRenewer::new(new_tokio_natpmp().await?, RenewalPolicy::default())?;
+    /// let mut events = renewer.events();
+    /// renewer.add(Protocol::TCP, 8080, 8080, 3600);
+    /// while let Some(event) = events.next().await {
+    ///     if let MappingEvent::PublicPortChanged { mapping, .. } = event {
+    ///         println!("now reachable on port {}", mapping.public_port());
+    ///     }
+    /// }
+    /// ```
+    pub fn events(&self) -> MappingEvents {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.shared.state().subscribers.push(sender);
+        MappingEvents { receiver }
+    }
+
+    /// Renewal schedule.
+    pub fn policy(&self) -> &RenewalPolicy {
+        &self.shared.policy
+    }
+}
+
+impl Drop for MappingRenewer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn run<S>(n: NatpmpAsync<S>, shared: Arc<Shared>)
+where
+    S: AsyncUdpSocket,
+{
+    loop {
+        match next_job(&shared, Instant::now()) {
+            Job::Delete(protocol, private_port) => {
+                // best effort, the mapping expires anyway
+                let request = Request::delete_mapping(protocol, private_port);
+                if let Ok(token) = n.send_tracked_request(request).await {
+                    let _ = n.read_response_for(token).await;
+                }
+            }
+            Job::Request(status) => {
+                let result = request(&n, &status).await;
+                record(&shared, &status, result, Instant::now());
+            }
+            Job::Wait(Some(at)) => {
+                let _ = tokio::time::timeout_at(at.into(), shared.wake.notified()).await;
+            }
+            Job::Wait(None) => shared.wake.notified().await,
+        }
+    }
+}
+
+fn next_job(shared: &Shared, now: Instant) -> Job {
+    let mut state = shared.state();
+    if let Some((protocol, private_port)) = state.deletions.pop() {
+        return Job::Delete(protocol, private_port);
+    }
+    match state.mappings.iter().min_by_key(|m| m.next_renewal) {
+        Some(m) if m.next_renewal <= now => Job::Request(*m),
+        Some(m) => Job::Wait(Some(m.next_renewal)),
+        None => Job::Wait(None),
+    }
+}
+
+/// Request the mapping of `status`, returns the response and whether its epoch indicates a
+/// reboot of the gateway.
+async fn request<S>(n: &NatpmpAsync<S>, status: &RenewalStatus) -> Result<(MappingResponse, bool)>
+where
+    S: AsyncUdpSocket,
+{
+    // keep the assigned port
+    let public_port = match status.mapping {
+        Some(mr) => PublicPort::from(mr.public_port()),
+        None => status.public_port,
+    };
+    let request = Request::Mapping {
+        protocol: status.protocol,
+        private_port: status.private_port,
+        public_port: public_port.into(),
+        lifetime: status.lifetime.as_secs(),
+    };
+    let token = n.send_tracked_request(request).await?;
+    let response = n.read_response_for(token).await?;
+    let rebooted = n.epoch_indicates_reboot(&response);
+    match response {
+        Response::TCP(mr) | Response::UDP(mr) => Ok((mr, rebooted)),
+        Response::Gateway(_) => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+    }
+}
+
+/// Record the result of the request of `status`, unless the mapping was removed or replaced
+/// meanwhile.
+fn record(
+    shared: &Shared,
+    status: &RenewalStatus,
+    result: Result<(MappingResponse, bool)>,
+    now: Instant,
+) {
+    let mut state = shared.state();
+    let Some(i) = state.mappings.iter().position(|m| {
+        m.protocol == status.protocol
+            && m.private_port == status.private_port
+            && m.public_port == status.public_port
+            && m.lifetime == status.lifetime
+    }) else {
+        return;
+    };
+    let policy = &shared.policy;
+    match result {
+        Ok((mr, rebooted)) => {
+            if rebooted {
+                // the gateway lost the other mappings too
+                for m in state.mappings.iter_mut() {
+                    m.next_renewal = now;
+                }
+                state.emit(MappingEvent::GatewayRebooted { epoch: mr.epoch() });
+            }
+            let m = &mut state.mappings[i];
+            let event = match m.mapping {
+                None => MappingEvent::Created(mr),
+                Some(previous) if previous.public_port() != mr.public_port() => {
+                    MappingEvent::PublicPortChanged {
+                        previous,
+                        mapping: mr,
+                    }
+                }
+                Some(_) => MappingEvent::Renewed(mr),
+            };
+            m.mapping = Some(mr);
+            m.last_error = None;
+            m.failures = 0;
+            m.next_renewal = now + policy.renewal(*mr.lifetime());
+            state.emit(event);
+        }
+        Err(error) => {
+            let m = &mut state.mappings[i];
+            m.last_error = Some(error);
+            m.failures = m.failures.saturating_add(1);
+            m.next_renewal = now + policy.backoff(m.failures);
+            let expired = m.mapping.filter(|mr| mr.expires_at() <= now);
+            if expired.is_some() {
+                m.mapping = None;
+            }
+            state.emit(MappingEvent::Error {
+                protocol: status.protocol,
+                private_port: status.private_port,
+                error,
+            });
+            if let Some(mr) = expired {
+                state.emit(MappingEvent::Expired(mr));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::new_natpmp_async_with_port;
+
+    async fn recv_request(server: &UdpSocket) -> ([u8; 12], std::net::SocketAddr) {
+        let mut buf = [0u8; 12];
+        let recv = server.recv_from(&mut buf);
+        let (_, client) = tokio::time::timeout(Duration::from_secs(5), recv)
+            .await
+            .unwrap()
+            .unwrap();
+        (buf, client)
+    }
+
+    #[tokio::test]
+    async fn test_renewal() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let n = new_natpmp_async_with_port(s, Ipv4Addr::LOCALHOST, port);
+        let renewer = MappingRenewer::new(n, RenewalPolicy::default())?;
+        let mut events = renewer.events();
+        renewer.add(Protocol::UDP, 4020, PublicPort::Any, 1);
+
+        // granted for 1 second with public port 5020, renewed after 500ms by a rebooted gateway
+        for epoch in [100, 1] {
+            let (request, client) = recv_request(&server).await;
+            let reply = [
+                0, 129, 0, 0, 0, 0, 0, epoch, request[4], request[5], 19, 156, 0, 0, 0, 1,
+            ];
+            server.send_to(&reply, client).await.unwrap();
+        }
+        let (request, _) = recv_request(&server).await;
+        // the assigned port is requested again
+        assert_eq!(&request[..8], &[0, 1, 0, 0, 15, 180, 19, 156]);
+        let status = renewer.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].mapping.map(|mr| mr.public_port()), Some(5020));
+        assert_eq!(status[0].last_error, None);
+        assert!(matches!(events.try_next(), Some(MappingEvent::Created(_))));
+        assert_eq!(
+            events.try_next(),
+            Some(MappingEvent::GatewayRebooted { epoch: 1 })
+        );
+        assert!(matches!(events.try_next(), Some(MappingEvent::Renewed(_))));
+        assert_eq!(events.try_next(), None);
+
+        assert!(renewer.remove(Protocol::UDP, 4020));
+        assert!(!renewer.remove(Protocol::UDP, 4020));
+        assert!(renewer.status().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RenewalPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(64), Duration::from_secs(300));
+        assert_eq!(
+            policy.renewal(Duration::from_secs(3600)),
+            Duration::from_secs(1800)
+        );
+        assert!(!RenewalPolicy {
+            renew_percent: 0,
+            ..policy
+        }
+        .is_valid());
+    }
+}
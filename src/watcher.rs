@@ -0,0 +1,392 @@
+//! Detection of default gateway changes on a tokio task.
+
+use std::io;
+use std::net::Ipv4Addr;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+#[cfg(feature = "stream")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(feature = "stream")]
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinHandle;
+
+use crate::{Error, GatewaySource, Result};
+
+/// Change of the default gateway seen by a [`GatewayWatcher`](struct.GatewayWatcher.html).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum GatewayEvent {
+    /// A default gateway appeared, e.g. the link came up.
+    Up(Ipv4Addr),
+    /// The default gateway changed, e.g. after roaming or a DHCP renewal.
+    Changed {
+        previous: Ipv4Addr,
+        gateway: Ipv4Addr,
+    },
+    /// The default gateway is gone, e.g. the link went down.
+    Down(Ipv4Addr),
+}
+
+impl GatewayEvent {
+    /// Gateway from now on, `None` after [`Down`](#variant.Down).
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        match self {
+            GatewayEvent::Up(gateway) | GatewayEvent::Changed { gateway, .. } => Some(*gateway),
+            GatewayEvent::Down(_) => None,
+        }
+    }
+}
+
+/// Watches the default gateway from a tokio task, so that a long-running client can create a
+/// new [`NatpmpAsync`](struct.NatpmpAsync.html) and request its mappings again after roaming
+/// or a DHCP change.
+///
+/// The gateway is read again on the change notifications of the routing table, a netlink
+/// socket on Linux and a routing socket on macOS and the BSDs, and at least every `interval`.
+/// Elsewhere, or when the notifications cannot be subscribed to, it is only read every
+/// `interval`, so a change is seen up to `interval` late. The reads, which may block, run on
+/// the blocking threads of the runtime. The task is aborted when the watcher is dropped.
+///
+/// With the `stream` feature it is also a `futures_core::Stream` of the events.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// let mut watcher = GatewayWatcher::new(Duration::from_secs(5))?;
+/// while let Some(event) = watcher.next().await {
+///     if let Some(gateway) = event.gateway() {
+///         let n = new_tokio_natpmp_with(gateway).await?;
+///         // request the mappings again
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct GatewayWatcher {
+    gateway: Arc<Mutex<Option<Ipv4Addr>>>,
+    receiver: UnboundedReceiver<GatewayEvent>,
+    task: JoinHandle<()>,
+}
+
+impl GatewayWatcher {
+    /// Spawn the task watching the default gateway, read at least every `interval`, it must be
+    /// called from a tokio runtime.
+    ///
+    /// The current gateway is read right away, it is not an event, see
+    /// [`gateway`](#method.gateway).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), zero
+    ///   `interval`
+    pub fn new(interval: Duration) -> Result<GatewayWatcher> {
+        GatewayWatcher::with_source(GatewaySource::Auto, interval)
+    }
+
+    /// Spawn the task watching the gateway resolved with `source`, see
+    /// [`new`](#method.new).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS), zero
+    ///   `interval`
+    pub fn with_source(source: GatewaySource, interval: Duration) -> Result<GatewayWatcher> {
+        GatewayWatcher::with_lookup(interval, move || source.resolve())
+    }
+
+    /// Spawn the watching task reading the gateway with `lookup`.
+    fn with_lookup<F>(interval: Duration, lookup: F) -> Result<GatewayWatcher>
+    where
+        F: Fn() -> Result<Ipv4Addr> + Send + Sync + 'static,
+    {
+        if interval.is_zero() {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        // subscribe first, so that no change is missed after the first read
+        let mut routes = RouteSocket::open().ok();
+        let gateway = Arc::new(Mutex::new(lookup().ok()));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let current = gateway.clone();
+        let lookup = Arc::new(lookup);
+        let task = tokio::spawn(async move {
+            loop {
+                match &routes {
+                    Some(socket) => {
+                        if let Ok(Err(_)) = tokio::time::timeout(interval, socket.changed()).await {
+                            // poll from now on
+                            routes = None;
+                        }
+                    }
+                    None => tokio::time::sleep(interval).await,
+                }
+                let lookup = lookup.clone();
+                let gateway = match tokio::task::spawn_blocking(move || lookup()).await {
+                    Ok(gateway) => gateway.ok(),
+                    Err(_) => continue,
+                };
+                let previous = {
+                    let mut current = current.lock().unwrap_or_else(PoisonError::into_inner);
+                    std::mem::replace(&mut *current, gateway)
+                };
+                let event = match (previous, gateway) {
+                    (None, Some(gateway)) => GatewayEvent::Up(gateway),
+                    (Some(previous), Some(gateway)) if previous != gateway => {
+                        GatewayEvent::Changed { previous, gateway }
+                    }
+                    (Some(previous), None) => GatewayEvent::Down(previous),
+                    _ => continue,
+                };
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(GatewayWatcher {
+            gateway,
+            receiver,
+            task,
+        })
+    }
+
+    /// Last default gateway read, `None` while there is none.
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        *self.gateway.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait for the next change of the default gateway.
+    pub async fn next(&mut self) -> Option<GatewayEvent> {
+        self.receiver.recv().await
+    }
+
+    /// Next change if one is queued, without waiting.
+    pub fn try_next(&mut self) -> Option<GatewayEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Drop for GatewayWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(feature = "stream")]
+impl futures_core::Stream for GatewayWatcher {
+    type Item = GatewayEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<GatewayEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Socket receiving the change notifications of the routing table.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+struct RouteSocket(AsyncFd<OwnedFd>);
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+impl RouteSocket {
+    /// Subscribe to the changes of the links, addresses and IPv4 routes.
+    #[cfg(target_os = "linux")]
+    fn open() -> io::Result<RouteSocket> {
+        let flags = libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        // SAFETY: plain socket creation, the descriptor is owned right after
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, flags, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new descriptor nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: an all-zero `sockaddr_nl` is valid, the kernel assigns the port id
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV4_ROUTE) as u32;
+        // SAFETY: `addr` outlives the call and its length is passed along
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RouteSocket(AsyncFd::new(fd)?))
+    }
+
+    /// Open a routing socket, it receives every change of the routing table.
+    #[cfg(not(target_os = "linux"))]
+    fn open() -> io::Result<RouteSocket> {
+        // SAFETY: plain socket creation, the descriptor is owned right after
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_INET) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new descriptor nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: `fd` is open, the flags are read and written back
+        let nonblocking = unsafe {
+            let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+            flags >= 0 && libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) >= 0
+        };
+        if !nonblocking {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RouteSocket(AsyncFd::new(fd)?))
+    }
+
+    /// Wait for a change notification, the queued ones are consumed together.
+    async fn changed(&self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut guard = self.0.readable().await?;
+            let mut changed = false;
+            loop {
+                // SAFETY: `buf` outlives the call and its length is passed along
+                let n = unsafe {
+                    libc::recv(
+                        self.0.as_raw_fd(),
+                        buf.as_mut_ptr().cast(),
+                        buf.len(),
+                        libc::MSG_DONTWAIT,
+                    )
+                };
+                if n >= 0 {
+                    changed = true;
+                    continue;
+                }
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+            guard.clear_ready();
+            if changed {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// No change notifications on this system, the gateway is polled.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+struct RouteSocket;
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+impl RouteSocket {
+    fn open() -> io::Result<RouteSocket> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn changed(&self) -> io::Result<()> {
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_gateway_watcher() -> Result<()> {
+        assert_eq!(
+            GatewayWatcher::new(Duration::ZERO).err(),
+            Some(Error::NATPMP_ERR_INVALIDARGS)
+        );
+
+        let a = Ipv4Addr::new(192, 168, 0, 1);
+        let b = Ipv4Addr::new(10, 0, 0, 1);
+        let readings = Mutex::new(VecDeque::from([
+            Ok(a),
+            Ok(a),
+            Ok(b),
+            Err(Error::NATPMP_ERR_CANNOTGETGATEWAY),
+            Ok(a),
+        ]));
+        let mut watcher = GatewayWatcher::with_lookup(Duration::from_millis(10), move || {
+            readings.lock().unwrap().pop_front().unwrap_or(Ok(a))
+        })?;
+        assert_eq!(watcher.gateway(), Some(a));
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            let event = tokio::time::timeout(Duration::from_secs(5), watcher.next())
+                .await
+                .unwrap();
+            events.push(event.unwrap());
+        }
+        assert_eq!(
+            events,
+            [
+                GatewayEvent::Changed {
+                    previous: a,
+                    gateway: b
+                },
+                GatewayEvent::Down(b),
+                GatewayEvent::Up(a),
+            ]
+        );
+        assert_eq!(watcher.gateway(), Some(a));
+        assert_eq!(watcher.try_next(), None);
+
+        let watcher = GatewayWatcher::with_source(GatewaySource::Fixed(b), Duration::from_secs(5))?;
+        assert_eq!(watcher.gateway(), Some(b));
+        Ok(())
+    }
+}